@@ -4,7 +4,10 @@ use chrono::{offset::TimeZone as _, Local};
 use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
 use smb3::FileAllInformation;
-use smb3_client::Result;
+use smb3_client::{
+    Authenticator, KerberosAuthenticator, KerberosCredentials, KerberosOptions, NtlmAuthenticator,
+    Result,
+};
 use std::path::PathBuf;
 use tokio::net::TcpStream;
 
@@ -39,14 +42,49 @@ struct Options {
     tree_path: String,
     #[clap(long, default_value_t = smb3_client::PORT)]
     port: u16,
-    #[clap(long)]
-    username: String,
-    #[clap(long)]
+    /// For NTLM the user can be qualified with a domain, as `DOMAIN\user` or `user@domain`.
+    #[clap(long, required_unless_present_any = ["anonymous", "guest"])]
+    username: Option<String>,
+    /// Not needed when authenticating with an NT hash, keytab or credential cache.
+    #[clap(long, default_value = "")]
     password: String,
+    /// Authenticate with NTLM using this NT hash of the password, given as 32 hex digits.
+    #[clap(long, value_parser = parse_nt_hash, conflicts_with_all = ["password", "kerberos"])]
+    nt_hash: Option<[u8; 16]>,
+    /// Authenticate with Kerberos rather than NTLM. The username has to be of the form user@REALM.
+    #[clap(long)]
+    kerberos: bool,
+    /// The KDC to get Kerberos tickets from, defaulting to the realm's name.
+    #[clap(long, requires = "kerberos")]
+    kdc: Option<String>,
+    /// Get Kerberos tickets with the keys in this keytab rather than the password.
+    #[clap(long, requires = "kerberos", conflicts_with = "ccache")]
+    keytab: Option<PathBuf>,
+    /// Use the Kerberos tickets in this credential cache.
+    #[clap(long, requires = "kerberos")]
+    ccache: Option<PathBuf>,
+    /// Log on with a null session, which has no credentials.
+    #[clap(long, conflicts_with_all = ["username", "guest"])]
+    anonymous: bool,
+    /// Log on as the server's guest account.
+    #[clap(long, conflicts_with_all = ["username", "anonymous"])]
+    guest: bool,
     #[command(subcommand)]
     command: Command,
 }
 
+fn parse_nt_hash(hex: &str) -> std::result::Result<[u8; 16], String> {
+    let error = || format!("{hex:?} isn't 32 hex digits");
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(error());
+    }
+    let mut nt_hash = [0; 16];
+    for (i, byte) in nt_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| error())?;
+    }
+    Ok(nt_hash)
+}
+
 struct Cli {
     client: smb3_client::Client<TcpStream>,
 }
@@ -104,10 +142,8 @@ impl Cli {
     }
 
     async fn query_info(&mut self, remote: PathBuf) -> Result<()> {
-        let file_id = self.client.look_up(&remote).await?;
-        let info: FileAllInformation = self.client.query_info(file_id).await?;
+        let info: FileAllInformation = self.client.query_info_path(&remote).await?;
         println!("{info:#?}");
-        self.client.close(file_id).await?;
         Ok(())
     }
 
@@ -128,10 +164,30 @@ impl Cli {
 async fn main() -> Result<()> {
     let opts = Options::parse();
 
+    let username = opts.username.unwrap_or_default();
+    let authenticator: Box<dyn Authenticator> = if opts.anonymous {
+        Box::new(NtlmAuthenticator::anonymous())
+    } else if opts.guest {
+        Box::new(NtlmAuthenticator::guest()?)
+    } else if opts.kerberos {
+        let options = KerberosOptions {
+            server: opts.host.clone(),
+            kdc: opts.kdc,
+            credentials: match (opts.keytab, opts.ccache) {
+                (Some(keytab), _) => KerberosCredentials::Keytab(keytab),
+                (_, Some(ccache)) => KerberosCredentials::CredentialCache(ccache),
+                _ => KerberosCredentials::Password,
+            },
+        };
+        Box::new(KerberosAuthenticator::new(&options, &username, &opts.password).await?)
+    } else if let Some(nt_hash) = opts.nt_hash {
+        Box::new(NtlmAuthenticator::with_nt_hash(&username, nt_hash)?)
+    } else {
+        Box::new(NtlmAuthenticator::new(&username, &opts.password)?)
+    };
+
     let transport = TcpStream::connect((opts.host, opts.port)).await?;
-    let client =
-        smb3_client::Client::new(transport, &opts.username, &opts.password, &opts.tree_path)
-            .await?;
+    let client = smb3_client::Client::new(transport, authenticator, &opts.tree_path).await?;
 
     let mut cli = Cli { client };
     match opts.command {
@@ -145,6 +201,7 @@ async fn main() -> Result<()> {
             remote_target,
         } => cli.rename(remote_src, remote_target).await?,
     }
+    cli.client.shutdown().await?;
 
     Ok(())
 }
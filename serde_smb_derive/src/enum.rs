@@ -2,6 +2,7 @@
 
 use super::{generate_where_clause, generics_to_args, type_with_generics};
 use darling::{FromDeriveInput, FromVariant};
+use quote::quote;
 use syn::{
     parse_quote, Arm, ConstParam, DeriveInput, Expr, GenericParam, Ident, ItemImpl, LifetimeParam,
     Pat, Result, Type, TypeParam,
@@ -121,27 +122,37 @@ pub fn deserialize_smb_enum_inner(input: DeriveInput) -> Result<ItemImpl> {
 
     let input = SerInput::from_derive_input(&input)?;
     let variants = input.data.take_enum().unwrap();
+    // Variants that share a tag are told apart by the size of their data
+    let shares_tag = |v: &EnumVariant| variants.iter().filter(|o| o.tag == v.tag).count() > 1;
+    let any_shared_tag = variants.iter().any(shares_tag);
     let match_arms = variants.iter().map(|v| -> Arm {
         let ident = &v.ident;
         let tag = &v.tag;
+        let size = u32::try_from(v.size).unwrap();
+        let guard = shares_tag(v).then(|| quote!(if data_count == #size));
         if let Some(reserved) = &v.reserved_value {
             parse_quote! {
-                #tag => {
+                #tag #guard => {
                     let _: #reserved = seq.next_element()?
                         .ok_or(::serde::de::Error::missing_field("reserved"))?;
                     Ok(#self_ident::#ident)
                 }
             }
         } else if v.size == 0 {
-            parse_quote!(#tag => Ok(#self_ident::#ident))
+            parse_quote!(#tag #guard => Ok(#self_ident::#ident))
         } else {
             parse_quote! {
-                #tag => Ok(#self_ident::#ident(
+                #tag #guard => Ok(#self_ident::#ident(
                     seq.next_element()?.ok_or(::serde::de::Error::missing_field("data"))?
                 ))
             }
         }
     });
+    let data_count: Pat = if any_shared_tag {
+        parse_quote!(data_count)
+    } else {
+        parse_quote!(_)
+    };
 
     Ok(parse_quote! {
         impl #impl_generics ::serde::Deserialize<'de> for #self_ #impl_where_clause {
@@ -174,7 +185,7 @@ pub fn deserialize_smb_enum_inner(input: DeriveInput) -> Result<ItemImpl> {
                             .ok_or(::serde::de::Error::missing_field("reserved"))?;
                         let _ = seq.next_element::<u16>()?
                             .ok_or(::serde::de::Error::missing_field("data_offset"))?;
-                        let _ = seq.next_element::<u32>()?
+                        let #data_count = seq.next_element::<u32>()?
                             .ok_or(::serde::de::Error::missing_field("data_count"))?;
                         let name: Vec<u8> = seq.next_element()?
                             .ok_or(::serde::de::Error::missing_field("name"))?;
@@ -54,18 +54,33 @@ pub struct HeaderFlags {
     pub unused2: B2,
 }
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct MessageId(pub u64);
 
 #[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
 pub struct ProcessId(pub u32);
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TreeId(pub u32);
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SessionId(pub u64);
 
+/// Identifies an operation the server is completing asynchronously. In the async form of the
+/// header it takes the place of the process id and tree id.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AsyncId(pub u64);
+
+impl AsyncId {
+    fn from_parts(process_id: ProcessId, tree_id: TreeId) -> Self {
+        Self((tree_id.0 as u64) << 32 | process_id.0 as u64)
+    }
+
+    fn into_parts(self) -> (ProcessId, TreeId) {
+        (ProcessId(self.0 as u32), TreeId((self.0 >> 32) as u32))
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
 pub struct Signature(pub [u8; 16]);
 
@@ -92,11 +107,55 @@ pub struct RequestHeader {
 
 pub const HEADER_SIZE: usize = 64;
 
-#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
-#[repr(u32)]
-pub enum NtStatus {
+impl RequestHeader {
+    pub fn async_id(&self) -> Option<AsyncId> {
+        self.flags
+            .r#async()
+            .then(|| AsyncId::from_parts(self.process_id, self.tree_id))
+    }
+
+    /// Switches the header to the async form.
+    pub fn set_async_id(&mut self, async_id: AsyncId) {
+        self.flags.set_async(true);
+        (self.process_id, self.tree_id) = async_id.into_parts();
+    }
+}
+
+macro_rules! nt_status {
+    ($($name:ident = $code:literal,)*) => {
+        /// The status a server responds with. Codes we don't have a name for are kept as they are,
+        /// so they survive a round trip.
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        pub enum NtStatus {
+            $($name,)*
+            Unknown(u32),
+        }
+
+        impl From<u32> for NtStatus {
+            fn from(code: u32) -> Self {
+                match code {
+                    $($code => Self::$name,)*
+                    code => Self::Unknown(code),
+                }
+            }
+        }
+
+        impl From<NtStatus> for u32 {
+            fn from(status: NtStatus) -> Self {
+                match status {
+                    $(NtStatus::$name => $code,)*
+                    NtStatus::Unknown(code) => code,
+                }
+            }
+        }
+    };
+}
+
+nt_status! {
     Success = 0x00000000,
     Pending = 0x00000103,
+    NotifyCleanup = 0x0000010b,
+    NotifyEnumDir = 0x0000010c,
     InvalidSmb = 0x00010002,
     BadTid = 0x00050002,
     BadCommand = 0x00160002,
@@ -610,6 +669,157 @@ pub enum NtStatus {
     Toomanyuids = 0xc000205a,
 }
 
+impl Serialize for NtStatus {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        u32::from(*self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NtStatus {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// The top two bits of a status code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Success,
+    Informational,
+    Warning,
+    Error,
+}
+
+impl NtStatus {
+    pub fn severity(self) -> Severity {
+        match u32::from(self) >> 30 {
+            0 => Severity::Success,
+            1 => Severity::Informational,
+            2 => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// The part of the system the status comes from, zero for most of the ones SMB uses.
+    pub fn facility(self) -> u16 {
+        ((u32::from(self) >> 16) & 0xfff) as u16
+    }
+
+    /// Whether the code was defined by someone other than Microsoft.
+    pub fn is_customer_code(self) -> bool {
+        u32::from(self) & 0x2000_0000 != 0
+    }
+
+    pub fn is_error(self) -> bool {
+        self.severity() == Severity::Error
+    }
+
+    /// What went wrong, for the statuses callers are most likely to run into.
+    fn message(self) -> Option<&'static str> {
+        Some(match self {
+            Self::Success => "the operation succeeded",
+            Self::Pending => "the operation is still in progress",
+            Self::BufferOverflow => "the data was too large to fit in the buffer",
+            Self::NoMoreFiles => "no more files were found",
+            Self::StoppedOnSymlink => "the path contains a symbolic link",
+            Self::Unsuccessful => "the operation was unsuccessful",
+            Self::NotImplemented => "the operation is not implemented",
+            Self::InvalidInfoClass => "the information class is not valid",
+            Self::InfoLengthMismatch => "the information length does not match the class",
+            Self::InvalidHandle => "the handle is not valid",
+            Self::InvalidParameter => "a parameter is not valid",
+            Self::NoSuchDevice => "the device does not exist",
+            Self::NoSuchFile => "the file does not exist",
+            Self::InvalidDeviceRequest => "the request is not valid for this device",
+            Self::EndOfFile => "the end of the file was reached",
+            Self::MoreProcessingRequired => "more processing is required",
+            Self::NoMemory => "the server is out of memory",
+            Self::AccessDenied => "access is denied",
+            Self::BufferTooSmall => "the buffer is too small",
+            Self::ObjectNameInvalid => "the name is not valid",
+            Self::ObjectNameNotFound => "the file or directory was not found",
+            Self::ObjectNameCollision => "the file or directory already exists",
+            Self::ObjectPathInvalid => "the path is not valid",
+            Self::ObjectPathNotFound => "the path was not found",
+            Self::ObjectPathSyntaxBad => "the path syntax is bad",
+            Self::SharingViolation => "the file is in use by another open",
+            Self::QuotaExceeded => "the quota was exceeded",
+            Self::FileLockConflict => "a lock conflicts with the operation",
+            Self::LockNotGranted => "the lock was not granted",
+            Self::DeletePending => "the file is being deleted",
+            Self::PrivilegeNotHeld => "a required privilege is not held",
+            Self::LogonFailure => "the user name or password is incorrect",
+            Self::AccountRestriction => "the account is restricted",
+            Self::PasswordExpired => "the password has expired",
+            Self::AccountDisabled => "the account is disabled",
+            Self::DiskFull => "the disk is full",
+            Self::InsufficientResources => "the server has insufficient resources",
+            Self::FileIsADirectory => "the file is a directory",
+            Self::NotSupported => "the request is not supported",
+            Self::NetworkNameDeleted => "the network name was deleted",
+            Self::BadNetworkName => "the share does not exist",
+            Self::Cancelled => "the operation was cancelled",
+            Self::FileClosed => "the file was closed",
+            Self::DirectoryNotEmpty => "the directory is not empty",
+            Self::NotADirectory => "the file is not a directory",
+            Self::UserSessionDeleted => "the session was deleted",
+            Self::Networksessionexpired => "the session has expired",
+            Self::MediaWriteProtected => "the media is write protected",
+            Self::CannotDelete => "the file cannot be deleted",
+            Self::FileDeleted => "the file has been deleted",
+            Self::TooManyOpenedFiles => "too many files are open",
+            Self::NotSameDevice => "the files are on different devices",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = u32::from(*self);
+        match (self, self.message()) {
+            (Self::Unknown(_), _) => write!(f, "unknown status {code:#010x}"),
+            (_, Some(message)) => write!(f, "{message} ({self:?}, {code:#010x})"),
+            (_, None) => write!(f, "{self:?} ({code:#010x})"),
+        }
+    }
+}
+
+#[test]
+fn unknown_nt_status_round_trips() {
+    let bytes = serde_smb::to_vec(&NtStatus::AccessDenied).unwrap();
+    assert_eq!(bytes, 0xc0000022u32.to_le_bytes());
+
+    let status: NtStatus = serde_smb::from_slice(&0xc0ff0001u32.to_le_bytes()).unwrap();
+    assert_eq!(status, NtStatus::Unknown(0xc0ff0001));
+    assert_eq!(
+        serde_smb::to_vec(&status).unwrap(),
+        0xc0ff0001u32.to_le_bytes()
+    );
+    assert_eq!(status.severity(), Severity::Error);
+    assert_eq!(status.facility(), 0x0ff);
+    assert_eq!(status.to_string(), "unknown status 0xc0ff0001");
+}
+
+#[test]
+fn nt_status_severity() {
+    assert_eq!(NtStatus::Success.severity(), Severity::Success);
+    assert_eq!(NtStatus::Pending.severity(), Severity::Success);
+    assert_eq!(NtStatus::BufferOverflow.severity(), Severity::Warning);
+    assert_eq!(
+        NtStatus::Unknown(0x40000000).severity(),
+        Severity::Informational
+    );
+    assert!(NtStatus::ObjectNameNotFound.is_error());
+    assert!(!NtStatus::NoMoreFiles.is_error());
+    assert_eq!(NtStatus::BadTid.facility(), 0x005);
+    assert!(!NtStatus::AccessDenied.is_customer_code());
+    assert_eq!(
+        NtStatus::ObjectNameNotFound.to_string(),
+        "the file or directory was not found (ObjectNameNotFound, 0xc0000034)"
+    );
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ProtocolId([u8; 4]);
 
@@ -623,6 +833,14 @@ impl ProtocolId {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn new_transform() -> Self {
+        Self(*b"\xfdSMB")
+    }
+
+    pub fn is_transform(&self) -> bool {
+        self.0 == *b"\xfdSMB"
+    }
 }
 
 impl fmt::Debug for ProtocolId {
@@ -650,6 +868,86 @@ pub struct ResponseHeader {
     pub signature: Signature,
 }
 
+impl ResponseHeader {
+    pub fn async_id(&self) -> Option<AsyncId> {
+        self.flags
+            .r#async()
+            .then(|| AsyncId::from_parts(self.process_id, self.tree_id))
+    }
+
+    /// Switches the header to the async form.
+    pub fn set_async_id(&mut self, async_id: AsyncId) {
+        self.flags.set_async(true);
+        (self.process_id, self.tree_id) = async_id.into_parts();
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Nonce(pub [u8; 16]);
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct TransformFlags: u16 {
+        const ENCRYPTED = 0x0001;
+    }
+}
+
+impl_serde_for_bitflags!(TransformFlags);
+
+/// Precedes an encrypted message. The signature is the AEAD tag and everything after it (starting
+/// at the nonce) is the associated data.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "TransformHeaderRepr", into = "TransformHeaderRepr")]
+pub struct TransformHeader {
+    pub protocol_id: ProtocolId,
+    pub signature: Signature,
+    pub nonce: Nonce,
+    pub original_message_size: u32,
+    pub flags: TransformFlags,
+    pub session_id: SessionId,
+}
+
+/// The session id in the transform header isn't 8-byte aligned, so it is sent as two halves to
+/// avoid the serializer inserting padding in front of it.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct)]
+struct TransformHeaderRepr {
+    protocol_id: ProtocolId,
+    signature: Signature,
+    nonce: Nonce,
+    original_message_size: u32,
+    #[smb(insert_reserved(name = "reserved", int_type = "u16"))]
+    flags: TransformFlags,
+    session_id: (u32, u32),
+}
+
+impl From<TransformHeaderRepr> for TransformHeader {
+    fn from(r: TransformHeaderRepr) -> Self {
+        Self {
+            protocol_id: r.protocol_id,
+            signature: r.signature,
+            nonce: r.nonce,
+            original_message_size: r.original_message_size,
+            flags: r.flags,
+            session_id: SessionId(r.session_id.0 as u64 | (r.session_id.1 as u64) << 32),
+        }
+    }
+}
+
+impl From<TransformHeader> for TransformHeaderRepr {
+    fn from(h: TransformHeader) -> Self {
+        Self {
+            protocol_id: h.protocol_id,
+            signature: h.signature,
+            nonce: h.nonce,
+            original_message_size: h.original_message_size,
+            flags: h.flags,
+            session_id: (h.session_id.0 as u32, (h.session_id.0 >> 32) as u32),
+        }
+    }
+}
+
+pub const TRANSFORM_HEADER_SIZE: usize = 52;
+
 bitflags! {
     #[derive(PartialEq, Eq, Copy, Clone, Debug)]
     pub struct SecurityMode: u8 {
@@ -675,7 +973,17 @@ bitflags! {
 
 impl_serde_for_bitflags!(Capabilities);
 
-#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
+#[derive(
+    SerializeWithDiscriminant,
+    DeserializeWithDiscriminant,
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
 #[repr(u16)]
 pub enum Dialect {
     Smb2_0_2 = 0x0202,
@@ -726,10 +1034,11 @@ impl fmt::Debug for Uuid {
 
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Clone, Debug, PartialEq)]
 #[repr(u16)]
-#[serde(rename = "NegotiateContext$Pad4")]
+#[serde(rename = "NegotiateContext$Pad8")]
 pub enum NegotiateContext {
     Smb2PreauthIntegrityCapabilities(Smb2PreauthIntegrityCapabilities) = 1,
     Smb2EncryptionCapabilities(Smb2EncryptionCapabilities) = 2,
+    Smb2SigningCapabilities(Smb2SigningCapabilities) = 8,
 }
 
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
@@ -737,6 +1046,16 @@ pub enum NegotiateContext {
 pub enum CipherId {
     Aes128Ccm = 1,
     Aes128Gcm = 2,
+    Aes256Ccm = 3,
+    Aes256Gcm = 4,
+}
+
+#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
+#[repr(u16)]
+pub enum SigningAlgorithmId {
+    HmacSha256 = 0,
+    AesCmac = 1,
+    AesGmac = 2,
 }
 
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
@@ -767,6 +1086,16 @@ pub struct Smb2EncryptionCapabilities {
     pub ciphers: Vec<CipherId>,
 }
 
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct Smb2SigningCapabilities {
+    pub data_length: u16,
+    #[smb(
+        insert_reserved(name = "reserved", int_type = "u32"),
+        collection(count(int_type = "u16", after = "reserved"))
+    )]
+    pub signing_algorithms: Vec<SigningAlgorithmId>,
+}
+
 #[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
 #[smb(size = 36)]
 pub struct NegotiateRequest {
@@ -781,7 +1110,8 @@ pub struct NegotiateRequest {
         offset(
             int_type = "u32",
             after = "client_guid",
-            value = "HEADER_SIZE + 38 + self.dialects.len() * 2"
+            value = "HEADER_SIZE + 38 + self.dialects.len() * 2",
+            empty_zero = true
         )
     ))]
     pub negotiate_contexts: Vec<NegotiateContext>,
@@ -937,6 +1267,21 @@ pub struct SessionSetupResponse {
     pub security_blob: Vec<u8>,
 }
 
+/// Ends the session given in the header.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct LogoffRequest;
+
+impl HasCommand for LogoffRequest {
+    fn command() -> Command {
+        Command::Logoff
+    }
+}
+
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct LogoffResponse;
+
 bitflags! {
     #[derive(PartialEq, Eq, Copy, Clone, Debug)]
     pub struct TreeConnectFlags: u16 {
@@ -998,6 +1343,38 @@ bitflags! {
 
 impl_serde_for_bitflags!(ShareFlags);
 
+/// How clients may cache files from a share for offline use, kept in bits 4 and 5 of the share
+/// flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CachingMode {
+    Manual,
+    Auto,
+    Vdo,
+    None,
+}
+
+impl ShareFlags {
+    pub fn caching_mode(self) -> CachingMode {
+        match self.bits() & Self::NO_CACHING.bits() {
+            0x00 => CachingMode::Manual,
+            0x10 => CachingMode::Auto,
+            0x20 => CachingMode::Vdo,
+            _ => CachingMode::None,
+        }
+    }
+}
+
+#[test]
+fn share_flags_caching_mode() {
+    assert_eq!(ShareFlags::DFS.caching_mode(), CachingMode::Manual);
+    assert_eq!(
+        (ShareFlags::AUTO_CACHING | ShareFlags::ENCRYPT_DATA).caching_mode(),
+        CachingMode::Auto
+    );
+    assert_eq!(ShareFlags::VDO_CACHING.caching_mode(), CachingMode::Vdo);
+    assert_eq!(ShareFlags::NO_CACHING.caching_mode(), CachingMode::None);
+}
+
 bitflags! {
     #[derive(PartialEq, Eq, Copy, Clone, Debug)]
     pub struct ShareCapabilities: u32 {
@@ -1056,6 +1433,21 @@ pub struct TreeConnectResponse {
     pub access_mask: AccessMask,
 }
 
+/// Disconnects the tree given in the header.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct TreeDisconnectRequest;
+
+impl HasCommand for TreeDisconnectRequest {
+    fn command() -> Command {
+        Command::TreeDisconnect
+    }
+}
+
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct TreeDisconnectResponse;
+
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OplockLevel {
@@ -1153,7 +1545,7 @@ bitflags! {
 
 impl_serde_for_bitflags!(FileCreateOptions);
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct LeaseKey(pub [u8; 16]);
 
 bitflags! {
@@ -1189,6 +1581,52 @@ pub struct RequestLease {
     pub epoch: u16,
 }
 
+/// A lease as SMB 2.1 asks for it, without a parent lease key or epoch.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct RequestLeaseV1 {
+    #[smb(insert_reserved(name = "unknown", int_type = "u32"))]
+    pub lease_key: LeaseKey,
+    pub lease_state: LeaseState,
+    #[smb(insert_reserved(name = "lease_duration", int_type = "u64", after = true))]
+    pub flags: LeaseFlags,
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct DurableHandleFlags: u32 {
+        const PERSISTENT = 0x00000002;
+    }
+}
+
+impl_serde_for_bitflags!(DurableHandleFlags);
+
+/// Asks for a handle that survives losing the connection. The create guid identifies the open
+/// when the create is replayed or the handle reconnected.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct DurableHandleRequestV2 {
+    #[smb(insert_reserved(name = "unknown", int_type = "u32"))]
+    pub timeout: u32,
+    #[smb(insert_reserved(name = "reserved", int_type = "u64", after = true))]
+    pub flags: DurableHandleFlags,
+    pub create_guid: Uuid,
+}
+
+/// Reopens a durable handle on a new connection.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct DurableHandleReconnectV2 {
+    #[smb(insert_reserved(name = "unknown", int_type = "u32"))]
+    pub file_id: FileId,
+    pub create_guid: Uuid,
+    pub flags: DurableHandleFlags,
+}
+
+/// The durability the server granted, in the `DH2Q` context of a create response.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct DurableHandleResponseV2 {
+    pub timeout: u32,
+    pub flags: DurableHandleFlags,
+}
+
 #[derive(SerializeSmbEnum, DeserializeSmbEnum, Clone, Debug, PartialEq)]
 #[smb(offset = 4)]
 pub enum CreateContext {
@@ -1196,6 +1634,12 @@ pub enum CreateContext {
     DurableHandleRequest,
     #[smb(tag = "RqLs", size = "52", offset = 4)]
     RequestLease(RequestLease),
+    #[smb(tag = "RqLs", size = "32", offset = 4)]
+    RequestLeaseV1(RequestLeaseV1),
+    #[smb(tag = "DH2Q", size = "32", offset = 4)]
+    DurableHandleRequestV2(DurableHandleRequestV2),
+    #[smb(tag = "DH2C", size = "36", offset = 4)]
+    DurableHandleReconnectV2(DurableHandleReconnectV2),
     #[smb(tag = "QFid", size = "0", reserved_value = "u32")]
     QueryOnDiskId,
 }
@@ -1212,6 +1656,63 @@ impl From<CreateContext> for CreateContextEntry {
     }
 }
 
+#[test]
+fn durable_handle_context_layouts() {
+    let create_guid = Uuid {
+        data1: 0x11111111,
+        data2: 0x2222,
+        data3: 0x3333,
+        data4: [0x44; 8],
+    };
+    let mut guid_bytes = vec![0x11; 4];
+    guid_bytes.extend([0x22; 2]);
+    guid_bytes.extend([0x33; 2]);
+    guid_bytes.extend([0x44; 8]);
+
+    let request = CreateContextEntry::from(CreateContext::DurableHandleRequestV2(
+        DurableHandleRequestV2 {
+            timeout: 0,
+            flags: DurableHandleFlags::PERSISTENT,
+            create_guid: create_guid.clone(),
+        },
+    ));
+    // On its own, an entry points past itself
+    let bytes = serde_smb::to_vec(&request).unwrap();
+    let mut expected = 56u32.to_le_bytes().to_vec();
+    expected.extend([16, 0, 4, 0, 0, 0, 24, 0, 32, 0, 0, 0]);
+    expected.extend(b"DH2Q\0\0\0\0");
+    expected.extend([0, 0, 0, 0, 2, 0, 0, 0]);
+    expected.extend([0; 8]);
+    expected.extend(&guid_bytes);
+    assert_eq!(bytes, expected);
+    assert_eq!(
+        serde_smb::from_slice::<CreateContextEntry>(&bytes).unwrap(),
+        request
+    );
+
+    let reconnect = CreateContextEntry::from(CreateContext::DurableHandleReconnectV2(
+        DurableHandleReconnectV2 {
+            file_id: FileId {
+                persistent: 1,
+                volatile: 2,
+            },
+            create_guid,
+            flags: DurableHandleFlags::PERSISTENT,
+        },
+    ));
+    let bytes = serde_smb::to_vec(&reconnect).unwrap();
+    let mut expected = 64u32.to_le_bytes().to_vec();
+    expected.extend([16, 0, 4, 0, 0, 0, 24, 0, 36, 0, 0, 0]);
+    expected.extend(b"DH2C\0\0\0\0");
+    expected.extend(1u64.to_le_bytes());
+    expected.extend(2u64.to_le_bytes());
+    expected.extend(&guid_bytes);
+    expected.extend([2, 0, 0, 0]);
+    // Padded to the alignment of the next entry
+    expected.extend([0; 4]);
+    assert_eq!(bytes, expected);
+}
+
 #[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
 #[smb(size = 57)]
 pub struct CreateRequest {
@@ -1251,12 +1752,20 @@ impl HasCommand for CreateRequest {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FileId {
     pub persistent: u64,
     pub volatile: u64,
 }
 
+impl FileId {
+    /// Used by related requests in a compound to refer to the file the previous request opened.
+    pub const RELATED: Self = Self {
+        persistent: u64::MAX,
+        volatile: u64::MAX,
+    };
+}
+
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
 #[repr(u32)]
 pub enum FileCreateAction {
@@ -1293,6 +1802,305 @@ pub struct CreateResponse {
     pub create_contexts: Vec<u8>,
 }
 
+impl CreateResponse {
+    /// The data of the create context with the given tag, if the server sent one.
+    pub fn create_context(&self, tag: &[u8]) -> Option<&[u8]> {
+        let mut contexts = &self.create_contexts[..];
+        loop {
+            let field = |offset: usize, len: usize| -> Option<usize> {
+                let bytes = contexts.get(offset..offset + len)?;
+                Some(bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as usize))
+            };
+            let (next, name_offset, name_len) = (field(0, 4)?, field(4, 2)?, field(6, 2)?);
+            let (data_offset, data_len) = (field(10, 2)?, field(12, 4)?);
+            if contexts.get(name_offset..name_offset + name_len)? == tag {
+                return contexts.get(data_offset..data_offset + data_len);
+            }
+            if next == 0 {
+                return None;
+            }
+            contexts = contexts.get(next..)?;
+        }
+    }
+
+    /// The lease the server granted, if it sent one. SMB 2.1 servers send the shorter version 1
+    /// lease, which is returned without a parent lease key and with an epoch of 0.
+    pub fn lease(&self) -> serde_smb::Result<Option<ResponseLease>> {
+        let Some(data) = self.create_context(b"RqLs") else {
+            return Ok(None);
+        };
+        match data.len() {
+            32 => {
+                let lease: ResponseLeaseV1 = serde_smb::from_slice(data)?;
+                Ok(Some(ResponseLease {
+                    lease_key: lease.lease_key,
+                    lease_state: lease.lease_state,
+                    flags: lease.flags,
+                    parent_lease_key: LeaseKey::default(),
+                    epoch: 0,
+                }))
+            }
+            52 => Ok(Some(serde_smb::from_slice(data)?)),
+            len => Err(serde::de::Error::invalid_length(
+                len,
+                &"a lease of 32 or 52 bytes",
+            )),
+        }
+    }
+}
+
+/// The lease the server granted, in the `RqLs` context of a create response.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct ResponseLease {
+    pub lease_key: LeaseKey,
+    pub lease_state: LeaseState,
+    #[smb(insert_reserved(name = "lease_duration", int_type = "u64", after = true))]
+    pub flags: LeaseFlags,
+    pub parent_lease_key: LeaseKey,
+    #[smb(insert_reserved(name = "reserved", int_type = "u16", after = true))]
+    pub epoch: u16,
+}
+
+/// The lease an SMB 2.1 server granted.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct ResponseLeaseV1 {
+    pub lease_key: LeaseKey,
+    pub lease_state: LeaseState,
+    #[smb(insert_reserved(name = "lease_duration", int_type = "u64", after = true))]
+    pub flags: LeaseFlags,
+}
+
+/// The server breaking an oplock, and the client acknowledging it. The server responds with the
+/// same too.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 24)]
+pub struct OplockBreak {
+    #[smb(insert_reserved(name = "reserved", int_type = "(u8, u32)", after = true))]
+    pub oplock_level: OplockLevel,
+    pub file_id: FileId,
+}
+
+impl HasCommand for OplockBreak {
+    fn command() -> Command {
+        Command::OplockBreak
+    }
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct LeaseBreakFlags: u32 {
+        const ACK_REQUIRED = 0x00000001;
+    }
+}
+
+impl_serde_for_bitflags!(LeaseBreakFlags);
+
+/// The server breaking a lease, which arrives with the same command as an oplock break.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 44)]
+pub struct LeaseBreakNotification {
+    pub new_epoch: u16,
+    pub flags: LeaseBreakFlags,
+    pub lease_key: LeaseKey,
+    pub current_lease_state: LeaseState,
+    #[smb(insert_reserved(
+        name = "break_reason_and_hints",
+        int_type = "(u32, u32, u32)",
+        after = true
+    ))]
+    pub new_lease_state: LeaseState,
+}
+
+/// The client acknowledging a lease break. The server responds with the same.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 36)]
+pub struct LeaseBreakAcknowledgment {
+    #[smb(insert_reserved(name = "reserved_and_flags", int_type = "(u16, u32)"))]
+    pub lease_key: LeaseKey,
+    #[smb(insert_reserved(name = "lease_duration", int_type = "(u32, u32)", after = true))]
+    pub lease_state: LeaseState,
+}
+
+impl HasCommand for LeaseBreakAcknowledgment {
+    fn command() -> Command {
+        Command::OplockBreak
+    }
+}
+
+#[test]
+fn break_layouts() {
+    let file_id = FileId {
+        persistent: 1,
+        volatile: 2,
+    };
+    let bytes = serde_smb::to_vec(&OplockBreak {
+        oplock_level: OplockLevel::II,
+        file_id,
+    })
+    .unwrap();
+    let mut expected = vec![24, 0, 1, 0, 0, 0, 0, 0];
+    expected.extend(1u64.to_le_bytes());
+    expected.extend(2u64.to_le_bytes());
+    assert_eq!(bytes, expected);
+
+    let notification = LeaseBreakNotification {
+        new_epoch: 3,
+        flags: LeaseBreakFlags::ACK_REQUIRED,
+        lease_key: LeaseKey([0x11; 16]),
+        current_lease_state: LeaseState::READ_CACHING | LeaseState::WRITE_CACHING,
+        new_lease_state: LeaseState::READ_CACHING,
+    };
+    let bytes = serde_smb::to_vec(&notification).unwrap();
+    let mut expected = vec![44, 0, 3, 0, 1, 0, 0, 0];
+    expected.extend([0x11; 16]);
+    expected.extend([5, 0, 0, 0, 1, 0, 0, 0]);
+    expected.extend([0; 12]);
+    assert_eq!(bytes, expected);
+    assert_eq!(
+        serde_smb::from_slice::<LeaseBreakNotification>(&bytes).unwrap(),
+        notification
+    );
+
+    let bytes = serde_smb::to_vec(&LeaseBreakAcknowledgment {
+        lease_key: LeaseKey([0x11; 16]),
+        lease_state: LeaseState::READ_CACHING,
+    })
+    .unwrap();
+    let mut expected = vec![36, 0, 0, 0, 0, 0, 0, 0];
+    expected.extend([0x11; 16]);
+    expected.extend([1, 0, 0, 0]);
+    expected.extend([0; 8]);
+    assert_eq!(bytes, expected);
+}
+
+#[test]
+fn create_response_lease_context() {
+    let lease = ResponseLease {
+        lease_key: LeaseKey([0x22; 16]),
+        lease_state: LeaseState::READ_CACHING | LeaseState::HANDLE_CACHING,
+        flags: LeaseFlags::empty(),
+        parent_lease_key: LeaseKey::default(),
+        epoch: 1,
+    };
+    let data = serde_smb::to_vec(&lease).unwrap();
+    assert_eq!(data.len(), 52);
+
+    // A maximal access context, then the lease
+    let mut contexts = vec![];
+    contexts.extend(32u32.to_le_bytes());
+    contexts.extend([16, 0, 4, 0, 0, 0, 24, 0, 8, 0, 0, 0]);
+    contexts.extend(b"MxAc\0\0\0\0");
+    contexts.extend([0; 8]);
+    contexts.extend(0u32.to_le_bytes());
+    contexts.extend([16, 0, 4, 0, 0, 0, 24, 0, 52, 0, 0, 0]);
+    contexts.extend(b"RqLs\0\0\0\0");
+    contexts.extend(&data);
+
+    let response = CreateResponse {
+        oplock_level: OplockLevel::Lease,
+        reparse_point: false,
+        create_action: FileCreateAction::Opened,
+        create_time: Time { intervals: 0 },
+        last_access_time: Time { intervals: 0 },
+        last_write_time: Time { intervals: 0 },
+        change_time: Time { intervals: 0 },
+        allocation_size: 0,
+        end_of_file: 0,
+        file_attributes: FileAttributes::empty(),
+        file_id: FileId {
+            persistent: 0,
+            volatile: 0,
+        },
+        create_contexts: contexts,
+    };
+    let found = response.create_context(b"RqLs").unwrap();
+    assert_eq!(
+        serde_smb::from_slice::<ResponseLease>(found).unwrap(),
+        lease
+    );
+    assert!(response.create_context(b"DH2Q").is_none());
+}
+
+#[test]
+fn lease_context_versions() {
+    let lease_key = LeaseKey([0x22; 16]);
+    let lease_state = LeaseState::READ_CACHING | LeaseState::WRITE_CACHING;
+
+    // The version 1 request is told apart from version 2 by its size
+    let v1 = CreateContextEntry::from(CreateContext::RequestLeaseV1(RequestLeaseV1 {
+        lease_key,
+        lease_state,
+        flags: LeaseFlags::empty(),
+    }));
+    let bytes = serde_smb::to_vec(&v1).unwrap();
+    let mut expected = 56u32.to_le_bytes().to_vec();
+    expected.extend([16, 0, 4, 0, 0, 0, 24, 0, 32, 0, 0, 0]);
+    expected.extend(b"RqLs\0\0\0\0");
+    expected.extend([0x22; 16]);
+    expected.extend([5, 0, 0, 0]);
+    expected.extend([0; 12]);
+    assert_eq!(bytes, expected);
+    assert_eq!(
+        serde_smb::from_slice::<CreateContextEntry>(&bytes).unwrap(),
+        v1
+    );
+    let v2 = CreateContextEntry::from(CreateContext::RequestLease(RequestLease {
+        lease_key,
+        lease_state,
+        flags: LeaseFlags::empty(),
+        parent_lease_key: LeaseKey::default(),
+        epoch: 1,
+    }));
+    let bytes = serde_smb::to_vec(&v2).unwrap();
+    assert_eq!(
+        serde_smb::from_slice::<CreateContextEntry>(&bytes).unwrap(),
+        v2
+    );
+
+    let response_with_lease = |data: &[u8]| {
+        let mut contexts = 0u32.to_le_bytes().to_vec();
+        contexts.extend([16, 0, 4, 0, 0, 0, 24, 0]);
+        contexts.extend((data.len() as u32).to_le_bytes());
+        contexts.extend(b"RqLs\0\0\0\0");
+        contexts.extend(data);
+        CreateResponse {
+            oplock_level: OplockLevel::Lease,
+            reparse_point: false,
+            create_action: FileCreateAction::Opened,
+            create_time: Time { intervals: 0 },
+            last_access_time: Time { intervals: 0 },
+            last_write_time: Time { intervals: 0 },
+            change_time: Time { intervals: 0 },
+            allocation_size: 0,
+            end_of_file: 0,
+            file_attributes: FileAttributes::empty(),
+            file_id: FileId {
+                persistent: 0,
+                volatile: 0,
+            },
+            create_contexts: contexts,
+        }
+    };
+    let data = serde_smb::to_vec(&ResponseLeaseV1 {
+        lease_key,
+        lease_state,
+        flags: LeaseFlags::empty(),
+    })
+    .unwrap();
+    assert_eq!(data.len(), 32);
+    assert_eq!(
+        response_with_lease(&data).lease().unwrap(),
+        Some(ResponseLease {
+            lease_key,
+            lease_state,
+            flags: LeaseFlags::empty(),
+            parent_lease_key: LeaseKey::default(),
+            epoch: 0,
+        })
+    );
+    assert!(response_with_lease(&data[..20]).lease().is_err());
+}
+
 bitflags! {
     #[derive(PartialEq, Eq, Copy, Clone, Debug)]
     pub struct QueryDirectoryFlags: u8 {
@@ -1825,6 +2633,201 @@ impl HasCommand for FlushRequest {
 #[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
 pub struct FlushResponse;
 
+/// Asks the server to stop working on the request with the message id or async id given in the
+/// header. There is no response.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct CancelRequest;
+
+impl HasCommand for CancelRequest {
+    fn command() -> Command {
+        Command::Cancel
+    }
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct LockFlags: u32 {
+        const SHARED_LOCK      = 0x00000001;
+        const EXCLUSIVE_LOCK   = 0x00000002;
+        const UNLOCK           = 0x00000004;
+        const FAIL_IMMEDIATELY = 0x00000010;
+    }
+}
+
+impl_serde_for_bitflags!(LockFlags);
+
+/// A range of bytes to lock or unlock.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+pub struct LockElement {
+    pub offset: u64,
+    pub length: u64,
+    #[smb(insert_reserved(name = "reserved", int_type = "u32", after = true))]
+    pub flags: LockFlags,
+}
+
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 48)]
+pub struct LockRequest {
+    /// A sequence number in the low four bits and an index in the rest, for resilient and
+    /// persistent handles to replay locks with. Zero otherwise.
+    pub lock_sequence: u32,
+    pub file_id: FileId,
+    #[smb(collection(count(int_type = "u16", after = "size")))]
+    pub locks: Vec<LockElement>,
+}
+
+impl HasCommand for LockRequest {
+    fn command() -> Command {
+        Command::Lock
+    }
+}
+
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 4, insert_reserved(name = "reserved", int_type = "u16"))]
+pub struct LockResponse;
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct ChangeNotifyFlags: u16 {
+        const WATCH_TREE = 0x0001;
+    }
+}
+
+impl_serde_for_bitflags!(ChangeNotifyFlags);
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct CompletionFilter: u32 {
+        const FILE_NAME    = 0x00000001;
+        const DIR_NAME     = 0x00000002;
+        const ATTRIBUTES   = 0x00000004;
+        const SIZE         = 0x00000008;
+        const LAST_WRITE   = 0x00000010;
+        const LAST_ACCESS  = 0x00000020;
+        const CREATION     = 0x00000040;
+        const EA           = 0x00000080;
+        const SECURITY     = 0x00000100;
+        const STREAM_NAME  = 0x00000200;
+        const STREAM_SIZE  = 0x00000400;
+        const STREAM_WRITE = 0x00000800;
+    }
+}
+
+impl_serde_for_bitflags!(CompletionFilter);
+
+/// Asks to be told about changes to a directory. The server holds on to the request until there
+/// are some.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 32)]
+pub struct ChangeNotifyRequest {
+    pub flags: ChangeNotifyFlags,
+    pub output_buffer_length: u32,
+    pub file_id: FileId,
+    #[smb(insert_reserved(name = "reserved", int_type = "u32", after = true))]
+    pub completion_filter: CompletionFilter,
+}
+
+impl HasCommand for ChangeNotifyRequest {
+    fn command() -> Command {
+        Command::ChangeNotify
+    }
+}
+
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(size = 9)]
+pub struct ChangeNotifyResponse {
+    #[smb(collection(
+        count(
+            int_type = "u32",
+            after = "size",
+            value = "smb_size(&self.entries)",
+            as_bytes = true
+        ),
+        offset(int_type = "u16", after = "size", value = "HEADER_SIZE + 8")
+    ))]
+    pub entries: Vec<FileNotifyInformation>,
+}
+
+#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, Copy, Clone, Debug, PartialEq)]
+#[repr(u32)]
+pub enum FileAction {
+    Added = 0x00000001,
+    Removed = 0x00000002,
+    Modified = 0x00000003,
+    RenamedOldName = 0x00000004,
+    RenamedNewName = 0x00000005,
+    AddedStream = 0x00000006,
+    RemovedStream = 0x00000007,
+    ModifiedStream = 0x00000008,
+    RemovedByDelete = 0x00000009,
+    IdNotTunnelled = 0x0000000a,
+    TunnelledIdCollision = 0x0000000b,
+}
+
+/// A change to a file in a watched directory. The name is relative to the directory.
+#[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
+#[smb(next_entry_offset = "align_to(smb_size(&self.file_name) + 12, 4)")]
+pub struct FileNotifyInformation {
+    pub action: FileAction,
+    #[smb(collection(count(int_type = "u32", after = "action", element_size = 2)))]
+    pub file_name: String,
+}
+
+#[test]
+fn change_notify_response_entries() {
+    let response = ChangeNotifyResponse {
+        entries: vec![
+            FileNotifyInformation {
+                action: FileAction::RenamedOldName,
+                file_name: "a".into(),
+            },
+            FileNotifyInformation {
+                action: FileAction::RenamedNewName,
+                file_name: "dir\\bcd".into(),
+            },
+        ],
+    };
+    let bytes = serde_smb::to_vec(&response).unwrap();
+    // The entries start at the offset, which counts the header. The first is padded to 4 bytes
+    // and the last has a zero offset.
+    let entries = &bytes[HEADER_SIZE + 8..];
+    assert_eq!(entries.len(), 16 + 26);
+    assert_eq!(entries[0..4], 16u32.to_le_bytes());
+    assert_eq!(entries[16..20], 0u32.to_le_bytes());
+
+    let parsed: ChangeNotifyResponse = serde_smb::from_slice(&bytes).unwrap();
+    assert_eq!(parsed, response);
+}
+
+#[test]
+fn lock_request_layout() {
+    let request = LockRequest {
+        lock_sequence: 0,
+        file_id: FileId {
+            persistent: 1,
+            volatile: 2,
+        },
+        locks: vec![LockElement {
+            offset: 0x10,
+            length: 0x20,
+            flags: LockFlags::EXCLUSIVE_LOCK | LockFlags::FAIL_IMMEDIATELY,
+        }],
+    };
+    let bytes = serde_smb::to_vec(&request).unwrap();
+    let mut expected = vec![48, 0, 1, 0, 0, 0, 0, 0];
+    expected.extend(1u64.to_le_bytes());
+    expected.extend(2u64.to_le_bytes());
+    expected.extend(0x10u64.to_le_bytes());
+    expected.extend(0x20u64.to_le_bytes());
+    expected.extend([0x12, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(bytes, expected);
+    assert_eq!(
+        serde_smb::from_slice::<LockRequest>(&bytes).unwrap(),
+        request
+    );
+}
+
 #[derive(SerializeSmbStruct, DeserializeSmbStruct, Clone, Debug, PartialEq)]
 pub struct FileRenameInformation {
     #[smb(insert_reserved(name = "root_directory", int_type = "u64", after = true))]
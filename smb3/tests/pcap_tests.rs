@@ -548,3 +548,87 @@ fn create_response() {
 
     assert_eq!(deserialized, (header, res), "actual != expected");
 }
+
+#[test]
+fn transform_header() {
+    let header = TransformHeader {
+        protocol_id: ProtocolId::new_transform(),
+        signature: Signature([
+            0x81, 0xa2, 0x86, 0x53, 0x54, 0x15, 0x44, 0x5d, 0xae, 0x39, 0x39, 0x21, 0xe4, 0x4f,
+            0xa4, 0x2e,
+        ]),
+        nonce: Nonce([
+            0xc7, 0xd3, 0x6f, 0x7f, 0x42, 0x2d, 0x8c, 0x62, 0x87, 0x0e, 0x47, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ]),
+        original_message_size: 0x68,
+        flags: TransformFlags::ENCRYPTED,
+        session_id: SessionId(0x0000100000000011),
+    };
+
+    let actual = serde_smb::to_vec(&header).unwrap();
+
+    let expected = [
+        0xfd, 0x53, 0x4d, 0x42, 0x81, 0xa2, 0x86, 0x53, 0x54, 0x15, 0x44, 0x5d, 0xae, 0x39, 0x39,
+        0x21, 0xe4, 0x4f, 0xa4, 0x2e, 0xc7, 0xd3, 0x6f, 0x7f, 0x42, 0x2d, 0x8c, 0x62, 0x87, 0x0e,
+        0x47, 0x00, 0x00, 0x00, 0x00, 0x00, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x11,
+        0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+    ];
+    assert_eq!(expected.len(), TRANSFORM_HEADER_SIZE);
+    assert_bytes_equal(&expected, &actual);
+
+    let deserialized: TransformHeader = serde_smb::from_slice(&expected[..]).unwrap();
+    assert!(deserialized.protocol_id.is_transform());
+    assert_eq!(deserialized, header);
+}
+
+#[test]
+fn signing_capabilities() {
+    let context = NegotiateContext::Smb2SigningCapabilities(Smb2SigningCapabilities {
+        data_length: 6,
+        signing_algorithms: vec![SigningAlgorithmId::AesGmac, SigningAlgorithmId::AesCmac],
+    });
+
+    let actual = serde_smb::to_vec(&context).unwrap();
+
+    let expected = [
+        0x08, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x00, 0x01, 0x00,
+    ];
+    assert_bytes_equal(&expected, &actual);
+
+    let deserialized: NegotiateContext = serde_smb::from_slice(&expected[..]).unwrap();
+    assert_eq!(deserialized, context);
+}
+
+#[test]
+fn async_interim_response() {
+    let mut header = ResponseHeader {
+        protocol_id: ProtocolId::new(),
+        header_length: 64,
+        credit_charge: Credits(1),
+        nt_status: NtStatus::Pending,
+        command: Command::ChangeNotify,
+        credits_granted: Credits(1),
+        flags: HeaderFlags::new().with_response(true),
+        chain_offset: 0,
+        message_id: MessageId(7),
+        process_id: ProcessId(0xfeff),
+        tree_id: TreeId(1),
+        session_id: SessionId(0x2d),
+        signature: Signature([0; 16]),
+    };
+    assert_eq!(header.async_id(), None);
+    header.set_async_id(AsyncId(0x15));
+
+    let expected = [
+        0xfe, 0x53, 0x4d, 0x42, 0x40, 0x00, 0x01, 0x00, 0x03, 0x01, 0x00, 0x00, 0x0f, 0x00, 0x01,
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2d, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_bytes_equal(&expected[..], &serde_smb::to_vec(&header).unwrap()[..]);
+
+    let parsed: ResponseHeader = serde_smb::from_slice(&expected[..]).unwrap();
+    assert_eq!(parsed.async_id(), Some(AsyncId(0x15)));
+}
@@ -0,0 +1,104 @@
+use crate::kerberos::{KerberosContext, KerberosOptions};
+use crate::ntlm::{AnonymousContext, GuestContext, NtlmContext};
+use crate::spnego::Spnego;
+use crate::Result;
+
+/// Authenticates a session, producing the security blobs sent in session setup requests and the
+/// session key that comes out of it.
+pub trait Authenticator: Send {
+    /// Produces the security blob for the next session setup request. The first time it is given
+    /// the blob from the negotiate response, which hints at the mechanisms the server supports,
+    /// and after that the blob from the response asking for more processing.
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>>;
+
+    /// Checks the security blob that came with the final response.
+    fn finish(&mut self, _security_blob: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// The key to sign and encrypt the session with, once authentication is done. Anonymous and
+    /// guest sessions don't have one.
+    fn session_key(&self) -> Result<Option<Vec<u8>>>;
+}
+
+impl<T: Authenticator + ?Sized> Authenticator for Box<T> {
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>> {
+        (**self).step(security_blob)
+    }
+
+    fn finish(&mut self, security_blob: &[u8]) -> Result<()> {
+        (**self).finish(security_blob)
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        (**self).session_key()
+    }
+}
+
+/// NTLM, negotiated through SPNEGO.
+pub struct NtlmAuthenticator(Spnego);
+
+impl NtlmAuthenticator {
+    /// The user name can be qualified with a domain, as either `DOMAIN\user` or `user@domain`.
+    pub fn new(username: &str, password: &str) -> Result<Self> {
+        Ok(Self(Spnego::new(NtlmContext::new(username, password)?)))
+    }
+
+    /// Authenticates with the NT hash of the user's password, the MD4 digest of its UTF-16
+    /// encoding, instead of the password itself.
+    pub fn with_nt_hash(username: &str, nt_hash: [u8; 16]) -> Result<Self> {
+        Ok(Self(Spnego::new(NtlmContext::with_nt_hash(
+            username, nt_hash,
+        )?)))
+    }
+
+    /// A null session, which has no credentials at all.
+    pub fn anonymous() -> Self {
+        Self(Spnego::new(AnonymousContext::default()))
+    }
+
+    /// A session as the server's guest account.
+    pub fn guest() -> Result<Self> {
+        Ok(Self(Spnego::new(GuestContext::new()?)))
+    }
+}
+
+impl Authenticator for NtlmAuthenticator {
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>> {
+        self.0.step(security_blob)
+    }
+
+    fn finish(&mut self, security_blob: &[u8]) -> Result<()> {
+        self.0.finish(security_blob)
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        self.0.session_key()
+    }
+}
+
+/// Kerberos, negotiated through SPNEGO.
+pub struct KerberosAuthenticator(Spnego);
+
+impl KerberosAuthenticator {
+    /// Gets a ticket for the server. See [`KerberosCredentials`](crate::KerberosCredentials) for
+    /// which of the user name and password are needed.
+    pub async fn new(options: &KerberosOptions, username: &str, password: &str) -> Result<Self> {
+        let context = KerberosContext::new(options, username, password).await?;
+        Ok(Self(Spnego::new(context)))
+    }
+}
+
+impl Authenticator for KerberosAuthenticator {
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>> {
+        self.0.step(security_blob)
+    }
+
+    fn finish(&mut self, security_blob: &[u8]) -> Result<()> {
+        self.0.finish(security_blob)
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        self.0.session_key()
+    }
+}
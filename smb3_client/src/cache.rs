@@ -0,0 +1,270 @@
+//! Caching the contents of a file for as long as a lease lets us.
+
+use crate::oplock::BreakHandler;
+use crate::{
+    spawn_cleanup, CachingRequest, Client, Error, LeasedFile, Result, Transport,
+    CREDIT_PAYLOAD_SIZE,
+};
+use futures::FutureExt as _;
+use smb3::{CloseResponse, FileId, LeaseState, NtStatus};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{self, AsyncWriteExt as _};
+use tokio::sync::Mutex;
+
+/// Files are cached in pages of this size, which each take a single read to fill.
+const PAGE_SIZE: u64 = CREDIT_PAYLOAD_SIZE as u64;
+
+#[derive(Default)]
+struct Cache {
+    /// Pages of the file as the server has it. The one with the end of the file is shorter, and
+    /// the ones after it are empty.
+    pages: HashMap<u64, Vec<u8>>,
+    /// Writes that haven't been sent yet, in the order they were made.
+    writes: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Cache {
+    /// Gives up what the caching state doesn't let us cache any more.
+    async fn release<TransportT: Transport>(
+        &mut self,
+        client: &Client<TransportT>,
+        file_id: FileId,
+        state: LeaseState,
+    ) -> Result<()> {
+        if !state.contains(LeaseState::READ_CACHING) {
+            self.pages.clear();
+        }
+        if !state.contains(LeaseState::WRITE_CACHING) {
+            self.write_back(client, file_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the writes that were held back. If one fails, it and the ones after it are kept.
+    async fn write_back<TransportT: Transport>(
+        &mut self,
+        client: &Client<TransportT>,
+        file_id: FileId,
+    ) -> Result<()> {
+        while let Some((offset, data)) = self.writes.front().cloned() {
+            self.invalidate(offset, data.len());
+            client.write_chunk(file_id, offset, data).await?;
+            self.writes.pop_front();
+        }
+        Ok(())
+    }
+
+    fn invalidate(&mut self, offset: u64, len: usize) {
+        let end = offset + len as u64;
+        self.pages
+            .retain(|&page, _| page * PAGE_SIZE >= end || (page + 1) * PAGE_SIZE <= offset);
+    }
+
+    fn buffered(&self) -> usize {
+        self.writes.iter().map(|(_, data)| data.len()).sum()
+    }
+
+    /// Puts the writes that haven't been sent yet over data read from `offset`, which they may
+    /// extend up to `count` bytes.
+    fn overlay(&self, offset: u64, count: u32, data: &mut Vec<u8>) {
+        let end = offset + count as u64;
+        for (write_offset, write) in &self.writes {
+            let from = offset.max(*write_offset);
+            let to = end.min(write_offset + write.len() as u64);
+            if from >= to {
+                continue;
+            }
+            if data.len() < (to - offset) as usize {
+                data.resize((to - offset) as usize, 0);
+            }
+            data[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &write[(from - write_offset) as usize..(to - write_offset) as usize],
+            );
+        }
+    }
+}
+
+/// A file that is read from a cache while the lease on it has read caching, and whose writes are
+/// held back while it has write caching. When the server breaks the lease, writes are sent and
+/// the cache emptied before the break is acknowledged.
+///
+/// Dropping it sends the writes held back and closes the file, without waiting for either. Use
+/// `close` to find out whether they succeeded.
+pub struct CachedFile<TransportT: Transport> {
+    client: Client<TransportT>,
+    file: Option<LeasedFile<TransportT>>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl<TransportT: Transport> CachedFile<TransportT> {
+    fn file(&self) -> &LeasedFile<TransportT> {
+        self.file.as_ref().unwrap()
+    }
+
+    pub fn file_id(&self) -> FileId {
+        self.file().file_id()
+    }
+
+    pub fn caching_state(&self) -> LeaseState {
+        self.file().caching_state()
+    }
+
+    /// Reads up to `count` bytes, fewer at the end of the file.
+    pub async fn read(&self, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let file_id = self.file_id();
+        // A break arriving from here on waits for the cache, and gives up what it holds after us
+        let mut cache = self.cache.lock().await;
+        let state = self.caching_state();
+        cache.release(&self.client, file_id, state).await?;
+
+        let mut data = if state.contains(LeaseState::READ_CACHING) {
+            let end = offset + count as u64;
+            let mut data = vec![];
+            let mut page = offset / PAGE_SIZE;
+            while page * PAGE_SIZE < end {
+                let bytes = match cache.pages.entry(page) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(self.read_page(file_id, page).await?),
+                };
+                let from = offset.saturating_sub(page * PAGE_SIZE) as usize;
+                let to = ((end - page * PAGE_SIZE).min(PAGE_SIZE) as usize).min(bytes.len());
+                data.extend(bytes.get(from..to).unwrap_or_default());
+                if bytes.len() < PAGE_SIZE as usize {
+                    break;
+                }
+                page += 1;
+            }
+            data
+        } else {
+            self.client.read_chunk(file_id, offset, count).await?
+        };
+        cache.overlay(offset, count, &mut data);
+        Ok(data)
+    }
+
+    /// Reads the whole file into the sink, from the cache as much as possible.
+    pub async fn read_all(&self, mut sink: impl io::AsyncWrite + Unpin) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let data = self.read(offset, PAGE_SIZE as u32).await?;
+            sink.write_all(&data).await?;
+            if data.len() < PAGE_SIZE as usize {
+                return Ok(());
+            }
+            offset += PAGE_SIZE;
+        }
+    }
+
+    /// Writes all of the data, which is held back while the lease has write caching. Small
+    /// writes that follow on from each other are sent together.
+    pub async fn write(&self, offset: u64, data: Vec<u8>) -> Result<()> {
+        let file_id = self.file_id();
+        let mut cache = self.cache.lock().await;
+        let state = self.caching_state();
+        cache.release(&self.client, file_id, state).await?;
+
+        if state.contains(LeaseState::WRITE_CACHING) {
+            match cache.writes.back_mut() {
+                Some((last_offset, last)) if *last_offset + last.len() as u64 == offset => {
+                    last.extend(data);
+                }
+                _ => cache.writes.push_back((offset, data)),
+            }
+            // There is no point holding back more than can be sent at once
+            if cache.buffered() >= self.client.max_write_size() as usize {
+                cache.write_back(&self.client, file_id).await?;
+            }
+            return Ok(());
+        }
+        cache.invalidate(offset, data.len());
+        self.client.write_chunk(file_id, offset, data).await
+    }
+
+    /// Sends the writes held back, and has the server flush the file.
+    pub async fn flush(&self) -> Result<()> {
+        let file_id = self.file_id();
+        self.cache
+            .lock()
+            .await
+            .write_back(&self.client, file_id)
+            .await?;
+        self.client.flush(file_id).await
+    }
+
+    /// Sends the writes held back and closes the file. It is closed even if they fail.
+    pub async fn close(mut self) -> Result<CloseResponse> {
+        let file = self.file.take().unwrap();
+        let written = self
+            .cache
+            .lock()
+            .await
+            .write_back(&self.client, file.file_id())
+            .await;
+        let closed = file.close().await;
+        written.and(closed)
+    }
+
+    async fn read_page(&self, file_id: FileId, page: u64) -> Result<Vec<u8>> {
+        match self
+            .client
+            .read(file_id, page * PAGE_SIZE, PAGE_SIZE as u32)
+            .await
+        {
+            Err(Error::NtStatus(NtStatus::EndOfFile)) => Ok(vec![]),
+            result => result,
+        }
+    }
+}
+
+impl<TransportT: Transport> Drop for CachedFile<TransportT> {
+    fn drop(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let (client, cache) = (self.client.clone(), self.cache.clone());
+        spawn_cleanup(async move {
+            let written = cache.lock().await.write_back(&client, file.file_id()).await;
+            written.and(file.close().await.map(drop))
+        });
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Opens a file for reading and writing like `look_up`, with a lease that lets it be cached.
+    /// If the server doesn't grant one, reads and writes go straight to it.
+    pub async fn open_cached(&self, path: impl AsRef<Path>) -> Result<CachedFile<TransportT>> {
+        let cache = Arc::new(Mutex::new(Cache::default()));
+        let on_break: BreakHandler = {
+            let (client, cache) = (self.clone(), cache.clone());
+            Arc::new(move |file_id, state| {
+                let (client, cache) = (client.clone(), cache.clone());
+                // If writing back fails it is tried again by the next read or write, but the
+                // server won't wait for that
+                async move {
+                    let _ = cache.lock().await.release(&client, file_id, state).await;
+                }
+                .boxed()
+            })
+        };
+        let file = self
+            .open_with_break_handler(
+                path,
+                CachingRequest::Lease(
+                    LeaseState::READ_CACHING
+                        | LeaseState::WRITE_CACHING
+                        | LeaseState::HANDLE_CACHING,
+                ),
+                false,
+                Some(on_break),
+            )
+            .await?;
+        Ok(CachedFile {
+            client: self.clone(),
+            file: Some(file),
+            cache,
+        })
+    }
+}
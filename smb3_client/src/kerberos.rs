@@ -0,0 +1,940 @@
+//! Kerberos (RFC 4120) as a GSS-API mechanism (RFC 4121). We get a ticket for the server from the
+//! KDC and present it in an AP-REQ, and the server proves it could read the ticket with an AP-REP.
+
+use crate::spnego::{split_der, Mechanism};
+use crate::{Error, Result};
+use byteorder::{BigEndian, ReadBytesExt as _};
+use picky_asn1::bit_string::BitString;
+use picky_asn1::date::GeneralizedTime;
+use picky_asn1::restricted_string::Ia5String;
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2,
+    ExplicitContextTag3, ExplicitContextTag4, ExplicitContextTag5, ExplicitContextTag6,
+    ExplicitContextTag7, ExplicitContextTag8, IntegerAsn1, OctetStringAsn1, Optional,
+};
+use picky_asn1_der::application_tag::ApplicationTag;
+use picky_asn1_x509::oids;
+use picky_krb::constants::error_codes::KDC_ERR_PREAUTH_REQUIRED;
+use picky_krb::constants::gss_api::{
+    AP_REP_TOKEN_ID, AP_REQ_TOKEN_ID, AUTHENTICATOR_CHECKSUM_TYPE,
+};
+use picky_krb::constants::key_usages::{
+    AP_REP_ENC, AP_REQ_AUTHENTICATOR, AS_REP_ENC, AS_REQ_TIMESTAMP, TGS_REP_ENC_SESSION_KEY,
+    TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR, TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR_CKSUM,
+};
+use picky_krb::constants::types::{
+    AP_REQ_MSG_TYPE, AS_REQ_MSG_TYPE, NT_PRINCIPAL, NT_SRV_INST, PA_ENC_TIMESTAMP,
+    PA_ETYPE_INFO2_TYPE, PA_PAC_REQUEST_TYPE, PA_TGS_REQ_TYPE, TGS_REQ_MSG_TYPE,
+};
+use picky_krb::crypto::{ChecksumSuite, CipherSuite};
+use picky_krb::data_types::{
+    ApOptions, Authenticator, AuthenticatorInner, Checksum, EncApRepPart, EncryptedData,
+    EncryptionKey, EtypeInfo2, KerbPaPacRequest, KerberosFlags, KerberosStringAsn1, KerberosTime,
+    Microseconds, PaData, PaEncTsEnc, PrincipalName, Realm, Ticket,
+};
+use picky_krb::gss_api::{KrbMessage, MechType};
+use picky_krb::messages::{
+    ApRep, ApReq, ApReqInner, AsRep, AsReq, EncAsRepPart, EncTgsRepPart, KdcRep, KdcReq,
+    KdcReqBody, KrbError, KrbErrorInner, TgsRep, TgsReq,
+};
+use rand::rngs::OsRng;
+use rand::Rng as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+use tokio::net::TcpStream;
+
+pub const KDC_PORT: u16 = 88;
+
+const KERBEROS_VERSION: u32 = 5;
+
+/// How long we ask for tickets to last. The KDC may well cut it short.
+const TICKET_LIFETIME: Duration = Duration::days(1);
+
+/// Forwardable, renewable, canonicalize and renewable-ok.
+const AS_REQ_OPTIONS: [u8; 4] = [0x40, 0x81, 0x00, 0x10];
+
+/// Forwardable and renewable.
+const TGS_REQ_OPTIONS: [u8; 4] = [0x40, 0x80, 0x00, 0x00];
+
+/// Mutual-required, so the server answers with an AP-REP.
+const AP_OPTIONS_MUTUAL_REQUIRED: [u8; 4] = [0x20, 0x00, 0x00, 0x00];
+
+/// The checksum GSS-API puts in the authenticator (RFC 4121 4.1.1). There are no channel bindings,
+/// and the flags ask for mutual authentication, replay and sequence detection, confidentiality and
+/// integrity.
+const GSS_CHECKSUM: [u8; 24] = [
+    0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x3e, 0x00, 0x00, 0x00,
+];
+
+const KRB_ERROR_TOKEN_ID: [u8; 2] = [0x03, 0x00];
+
+/// The encryption types we support, best first.
+const ETYPES: [CipherSuite; 2] = [
+    CipherSuite::Aes256CtsHmacSha196,
+    CipherSuite::Aes128CtsHmacSha196,
+];
+
+/// How to authenticate with Kerberos rather than NTLM.
+#[derive(Clone, Debug)]
+pub struct KerberosOptions {
+    /// The server's host name. We ask for a ticket for `cifs/<server>`.
+    pub server: String,
+    /// The KDC to get tickets from, as `host` or `host:port`. Defaults to the realm's name, which
+    /// in an Active Directory domain resolves to its domain controllers.
+    pub kdc: Option<String>,
+    pub credentials: KerberosCredentials,
+}
+
+impl KerberosOptions {
+    fn kdc(&self, realm: &str) -> String {
+        let kdc = self.kdc.clone().unwrap_or_else(|| realm.to_lowercase());
+        if kdc.contains(':') {
+            kdc
+        } else {
+            format!("{kdc}:{KDC_PORT}")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum KerberosCredentials {
+    /// Gets a ticket with the password given to the client. The user name has to be of the form
+    /// `user@REALM`.
+    Password,
+    /// Gets a ticket with the user's key from a keytab file. The user name has to be of the form
+    /// `user@REALM`.
+    Keytab(PathBuf),
+    /// Uses the tickets in a credential cache file, like the one `kinit` leaves behind. The user
+    /// name is ignored in favor of the cache's principal.
+    CredentialCache(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Principal {
+    name_type: u8,
+    components: Vec<String>,
+    realm: String,
+}
+
+impl Principal {
+    /// Realms are conventionally upper case, Active Directory's are its domain name in upper case.
+    fn user(username: &str) -> Result<Self> {
+        let (user, realm) = username.rsplit_once('@').ok_or_else(|| {
+            Error::Kerberos(format!(
+                "user name {username:?} isn't of the form user@REALM"
+            ))
+        })?;
+        Ok(Self {
+            name_type: NT_PRINCIPAL,
+            components: vec![user.into()],
+            realm: realm.to_uppercase(),
+        })
+    }
+
+    fn service(service: &str, host: &str, realm: &str) -> Self {
+        Self {
+            name_type: NT_SRV_INST,
+            components: vec![service.into(), host.into()],
+            realm: realm.into(),
+        }
+    }
+
+    /// The ticket granting service, which hands out tickets for the realm's other services.
+    fn tgs(realm: &str) -> Self {
+        Self::service("krbtgt", realm, realm)
+    }
+
+    fn from_asn1(name: &PrincipalName, realm: &Realm) -> Self {
+        Self {
+            name_type: int(&name.name_type.0) as u8,
+            components: name
+                .name_string
+                .0
+                 .0
+                .iter()
+                .map(|s| s.0.to_string())
+                .collect(),
+            realm: realm.0.to_string(),
+        }
+    }
+
+    fn name(&self) -> Result<PrincipalName> {
+        Ok(PrincipalName {
+            name_type: ExplicitContextTag0::from(integer(self.name_type as u32)),
+            name_string: ExplicitContextTag1::from(Asn1SequenceOf::from(
+                self.components
+                    .iter()
+                    .map(|component| kerberos_string(component))
+                    .collect::<Result<Vec<_>>>()?,
+            )),
+        })
+    }
+
+    fn realm(&self) -> Result<Realm> {
+        kerberos_string(&self.realm)
+    }
+
+    /// The salt for deriving keys from passwords, unless the KDC says otherwise.
+    fn default_salt(&self) -> String {
+        self.realm.clone() + &self.components.concat()
+    }
+
+    /// Host names and the like aren't case sensitive, and the name types are used inconsistently.
+    fn matches(&self, other: &Self) -> bool {
+        self.realm == other.realm
+            && self.components.len() == other.components.len()
+            && self
+                .components
+                .iter()
+                .zip(&other.components)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl std::fmt::Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.components.join("/"), self.realm)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Key {
+    etype: CipherSuite,
+    value: Vec<u8>,
+}
+
+impl Key {
+    fn random(etype: &CipherSuite) -> Self {
+        let cipher = etype.cipher();
+        let mut seed = vec![0; cipher.key_size()];
+        OsRng.fill(&mut seed[..]);
+        Self {
+            etype: etype.clone(),
+            value: cipher.random_to_key(seed),
+        }
+    }
+
+    fn from_password(etype: &CipherSuite, password: &str, salt: &str) -> Result<Self> {
+        let value = etype
+            .cipher()
+            .generate_key_from_password(password.as_bytes(), salt.as_bytes())?;
+        Ok(Self {
+            etype: etype.clone(),
+            value,
+        })
+    }
+
+    fn from_asn1(key: &EncryptionKey) -> Result<Self> {
+        Ok(Self {
+            etype: etype(&key.key_type.0)?,
+            value: key.key_value.0 .0.clone(),
+        })
+    }
+
+    fn to_asn1(&self) -> EncryptionKey {
+        EncryptionKey {
+            key_type: ExplicitContextTag0::from(integer(u32::from(&self.etype))),
+            key_value: ExplicitContextTag1::from(OctetStringAsn1::from(self.value.clone())),
+        }
+    }
+
+    fn encrypt(&self, key_usage: i32, plaintext: &[u8]) -> Result<EncryptedData> {
+        let cipher = self
+            .etype
+            .cipher()
+            .encrypt(&self.value, key_usage, plaintext)?;
+        Ok(EncryptedData {
+            etype: ExplicitContextTag0::from(integer(u32::from(&self.etype))),
+            kvno: Optional::from(None),
+            cipher: ExplicitContextTag2::from(OctetStringAsn1::from(cipher)),
+        })
+    }
+
+    fn decrypt(&self, key_usage: i32, data: &EncryptedData) -> Result<Vec<u8>> {
+        if etype(&data.etype.0)? != self.etype {
+            return Err(Error::Kerberos(
+                "data is encrypted with a different type of key".into(),
+            ));
+        }
+        Ok(self
+            .etype
+            .cipher()
+            .decrypt(&self.value, key_usage, &data.cipher.0 .0)?)
+    }
+
+    fn checksum(&self, key_usage: i32, data: &[u8]) -> Result<Checksum> {
+        let suite = match self.etype {
+            CipherSuite::Aes256CtsHmacSha196 => ChecksumSuite::HmacSha196Aes256,
+            CipherSuite::Aes128CtsHmacSha196 => ChecksumSuite::HmacSha196Aes128,
+            CipherSuite::Des3CbcSha1Kd => ChecksumSuite::HmacSha1Des3Kd,
+        };
+        let checksum = suite.hasher().checksum(&self.value, key_usage, data)?;
+        Ok(Checksum {
+            cksumtype: ExplicitContextTag0::from(integer(u32::from(&suite))),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(checksum)),
+        })
+    }
+}
+
+/// A ticket and the session key that goes with it.
+#[derive(Clone, Debug)]
+struct Credential {
+    client: Principal,
+    ticket: Ticket,
+    key: Key,
+}
+
+/// Where the client's long-term key comes from.
+enum Secret {
+    Password(String),
+    Keytab(Vec<KeytabEntry>),
+}
+
+impl Secret {
+    fn etypes(&self, client: &Principal) -> Vec<CipherSuite> {
+        match self {
+            Self::Password(_) => ETYPES.to_vec(),
+            Self::Keytab(entries) => ETYPES
+                .into_iter()
+                .filter(|etype| entries.iter().any(|entry| entry.is_for(client, etype)))
+                .collect(),
+        }
+    }
+
+    fn key(&self, client: &Principal, etype: &CipherSuite, salt: Option<&str>) -> Result<Key> {
+        match self {
+            Self::Password(password) => {
+                let salt = salt.map_or_else(|| client.default_salt(), String::from);
+                Key::from_password(etype, password, &salt)
+            }
+            // The last entry has the latest version of the key
+            Self::Keytab(entries) => entries
+                .iter()
+                .rev()
+                .find(|entry| entry.is_for(client, etype))
+                .map(|entry| Key {
+                    etype: etype.clone(),
+                    value: entry.key.clone(),
+                })
+                .ok_or_else(|| Error::Kerberos(format!("no {etype:?} key for {client} in keytab"))),
+        }
+    }
+}
+
+struct KeytabEntry {
+    principal: Principal,
+    etype: u16,
+    key: Vec<u8>,
+}
+
+impl KeytabEntry {
+    fn read(mut entry: &[u8]) -> Result<Self> {
+        let num_components = entry.read_u16::<BigEndian>()?;
+        let realm = read_string(&mut entry, |entry| entry.read_u16::<BigEndian>())?;
+        let components = (0..num_components)
+            .map(|_| read_string(&mut entry, |entry| entry.read_u16::<BigEndian>()))
+            .collect::<Result<_>>()?;
+        let name_type = entry.read_u32::<BigEndian>()?;
+        let _timestamp = entry.read_u32::<BigEndian>()?;
+        let _key_version = entry.read_u8()?;
+        let etype = entry.read_u16::<BigEndian>()?;
+        let key_len = entry.read_u16::<BigEndian>()?;
+        Ok(Self {
+            principal: Principal {
+                name_type: name_type as u8,
+                components,
+                realm,
+            },
+            etype,
+            key: read_bytes(&mut entry, key_len as usize)?,
+        })
+    }
+
+    fn is_for(&self, client: &Principal, etype: &CipherSuite) -> bool {
+        self.principal.matches(client) && self.etype as u32 == u32::from(etype)
+    }
+}
+
+/// Reads the keys in a keytab file, in the format MIT Kerberos and Heimdal use.
+fn read_keytab(path: &Path) -> Result<Vec<KeytabEntry>> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = &bytes[..];
+    if reader.read_u16::<BigEndian>()? != 0x0502 {
+        return Err(Error::Kerberos(format!(
+            "{} isn't a version 2 keytab",
+            path.display()
+        )));
+    }
+
+    let mut entries = vec![];
+    while !reader.is_empty() {
+        // Entries that have been removed are left as holes with a negative size
+        let size = reader.read_i32::<BigEndian>()?;
+        let entry = read_bytes(&mut reader, size.unsigned_abs() as usize)?;
+        if size > 0 {
+            entries.push(KeytabEntry::read(&entry)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// The tickets in a credential cache file, in the format MIT Kerberos uses for `FILE:` caches.
+struct CredentialCache {
+    principal: Principal,
+    credentials: Vec<(Principal, Credential)>,
+}
+
+impl CredentialCache {
+    fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let mut reader = &bytes[..];
+        let version = reader.read_u16::<BigEndian>()?;
+        match version {
+            0x0504 => {
+                let header_len = reader.read_u16::<BigEndian>()?;
+                read_bytes(&mut reader, header_len as usize)?;
+            }
+            0x0503 => {}
+            _ => {
+                return Err(Error::Kerberos(format!(
+                    "{} isn't a version 3 or 4 credential cache",
+                    path.display()
+                )))
+            }
+        }
+
+        let principal = read_principal(&mut reader)?;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut credentials = vec![];
+        while !reader.is_empty() {
+            let client = read_principal(&mut reader)?;
+            let server = read_principal(&mut reader)?;
+            let etype = reader.read_u16::<BigEndian>()?;
+            if version == 0x0503 {
+                reader.read_u16::<BigEndian>()?;
+            }
+            let key = read_data(&mut reader)?;
+            let _auth_time = reader.read_u32::<BigEndian>()?;
+            let _start_time = reader.read_u32::<BigEndian>()?;
+            let end_time = reader.read_u32::<BigEndian>()?;
+            let _renew_till = reader.read_u32::<BigEndian>()?;
+            let _is_skey = reader.read_u8()?;
+            let _ticket_flags = reader.read_u32::<BigEndian>()?;
+            for _ in 0..reader.read_u32::<BigEndian>()? {
+                let _address_type = reader.read_u16::<BigEndian>()?;
+                read_data(&mut reader)?;
+            }
+            for _ in 0..reader.read_u32::<BigEndian>()? {
+                let _auth_data_type = reader.read_u16::<BigEndian>()?;
+                read_data(&mut reader)?;
+            }
+            let ticket = read_data(&mut reader)?;
+            let _second_ticket = read_data(&mut reader)?;
+
+            // Configuration entries aren't tickets, and we can't use expired ones or ones with
+            // keys we don't support
+            let Ok(etype) = CipherSuite::try_from(etype as usize) else {
+                continue;
+            };
+            if server.realm == "X-CACHECONF:" || (end_time as i64) < now {
+                continue;
+            }
+            credentials.push((
+                server,
+                Credential {
+                    client,
+                    ticket: picky_asn1_der::from_bytes(&ticket)?,
+                    key: Key { etype, value: key },
+                },
+            ));
+        }
+
+        Ok(Self {
+            principal,
+            credentials,
+        })
+    }
+
+    fn find(&self, server: &Principal) -> Option<Credential> {
+        self.credentials
+            .iter()
+            .find(|(principal, _)| principal.matches(server))
+            .map(|(_, credential)| credential.clone())
+    }
+}
+
+fn read_principal(reader: &mut &[u8]) -> Result<Principal> {
+    let name_type = reader.read_u32::<BigEndian>()?;
+    let num_components = reader.read_u32::<BigEndian>()?;
+    let realm = read_string(reader, |reader| reader.read_u32::<BigEndian>())?;
+    let components = (0..num_components)
+        .map(|_| read_string(reader, |reader| reader.read_u32::<BigEndian>()))
+        .collect::<Result<_>>()?;
+    Ok(Principal {
+        name_type: name_type as u8,
+        components,
+        realm,
+    })
+}
+
+fn read_bytes(reader: &mut &[u8], len: usize) -> Result<Vec<u8>> {
+    if len > reader.len() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let (bytes, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(bytes.to_vec())
+}
+
+fn read_data(reader: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = reader.read_u32::<BigEndian>()?;
+    read_bytes(reader, len as usize)
+}
+
+fn read_string<LenT: Into<u64>>(
+    reader: &mut &[u8],
+    read_len: impl FnOnce(&mut &[u8]) -> io::Result<LenT>,
+) -> Result<String> {
+    let len = read_len(reader)?.into();
+    let bytes = read_bytes(reader, len as usize)?;
+    String::from_utf8(bytes).map_err(|_| Error::Kerberos("principal name isn't UTF-8".into()))
+}
+
+fn int(value: &IntegerAsn1) -> u32 {
+    value
+        .as_unsigned_bytes_be()
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as u32)
+}
+
+fn integer(value: u32) -> IntegerAsn1 {
+    IntegerAsn1::from_bytes_be_unsigned(value.to_be_bytes().to_vec())
+}
+
+fn etype(value: &IntegerAsn1) -> Result<CipherSuite> {
+    Ok(CipherSuite::try_from(int(value) as usize)?)
+}
+
+fn kerberos_string(value: &str) -> Result<KerberosStringAsn1> {
+    let string = Ia5String::from_string(value.into())
+        .map_err(|_| Error::Kerberos(format!("{value:?} isn't ASCII")))?;
+    Ok(string.into())
+}
+
+fn now() -> (KerberosTime, Microseconds) {
+    let now = OffsetDateTime::now_utc();
+    (
+        GeneralizedTime::from(now).into(),
+        integer(now.microsecond()),
+    )
+}
+
+/// A random number for matching replies to requests. It is kept positive, as some implementations
+/// take it to be signed.
+fn nonce() -> u32 {
+    OsRng.gen::<u32>() >> 1
+}
+
+fn pa_data(padata_type: &[u8], data: Vec<u8>) -> PaData {
+    PaData {
+        padata_type: ExplicitContextTag1::from(IntegerAsn1::from(padata_type.to_vec())),
+        padata_data: ExplicitContextTag2::from(OctetStringAsn1::from(data)),
+    }
+}
+
+fn req_body(
+    options: [u8; 4],
+    client: Option<&Principal>,
+    server: &Principal,
+    nonce: u32,
+    etypes: &[CipherSuite],
+) -> Result<KdcReqBody> {
+    let till = OffsetDateTime::now_utc() + TICKET_LIFETIME;
+    Ok(KdcReqBody {
+        kdc_options: ExplicitContextTag0::from(KerberosFlags::from(BitString::with_bytes(
+            options.to_vec(),
+        ))),
+        cname: Optional::from(
+            client
+                .map(|client| client.name().map(ExplicitContextTag1::from))
+                .transpose()?,
+        ),
+        realm: ExplicitContextTag2::from(server.realm()?),
+        sname: Optional::from(Some(ExplicitContextTag3::from(server.name()?))),
+        from: Optional::from(None),
+        till: ExplicitContextTag5::from(KerberosTime::from(GeneralizedTime::from(till))),
+        rtime: Optional::from(None),
+        nonce: ExplicitContextTag7::from(integer(nonce)),
+        etype: ExplicitContextTag8::from(Asn1SequenceOf::from(
+            etypes
+                .iter()
+                .map(|etype| integer(u32::from(etype)))
+                .collect::<Vec<_>>(),
+        )),
+        addresses: Optional::from(None),
+        enc_authorization_data: Optional::from(None),
+        additional_tickets: Optional::from(None),
+    })
+}
+
+fn kdc_req(msg_type: u8, padata: Vec<PaData>, req_body: KdcReqBody) -> KdcReq {
+    KdcReq {
+        pvno: ExplicitContextTag1::from(integer(KERBEROS_VERSION)),
+        msg_type: ExplicitContextTag2::from(integer(msg_type as u32)),
+        padata: Optional::from(Some(ExplicitContextTag3::from(Asn1SequenceOf::from(
+            padata,
+        )))),
+        req_body: ExplicitContextTag4::from(req_body),
+    }
+}
+
+fn authenticator(
+    client: &Principal,
+    checksum: Checksum,
+    subkey: Option<&Key>,
+    seq_number: Option<u32>,
+) -> Result<Authenticator> {
+    let (ctime, cusec) = now();
+    Ok(Authenticator::from(AuthenticatorInner {
+        authenticator_bno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+        crealm: ExplicitContextTag1::from(client.realm()?),
+        cname: ExplicitContextTag2::from(client.name()?),
+        cksum: Optional::from(Some(ExplicitContextTag3::from(checksum))),
+        cusec: ExplicitContextTag4::from(cusec),
+        ctime: ExplicitContextTag5::from(ctime),
+        subkey: Optional::from(subkey.map(|key| ExplicitContextTag6::from(key.to_asn1()))),
+        seq_number: Optional::from(seq_number.map(|seq| ExplicitContextTag7::from(integer(seq)))),
+        authorization_data: Optional::from(None),
+    }))
+}
+
+fn ap_req(
+    credential: &Credential,
+    options: [u8; 4],
+    authenticator: &Authenticator,
+    key_usage: i32,
+) -> Result<ApReq> {
+    let authenticator = picky_asn1_der::to_vec(authenticator)?;
+    Ok(ApReq::from(ApReqInner {
+        pvno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+        msg_type: ExplicitContextTag1::from(integer(AP_REQ_MSG_TYPE as u32)),
+        ap_options: ExplicitContextTag2::from(ApOptions::from(BitString::with_bytes(
+            options.to_vec(),
+        ))),
+        ticket: ExplicitContextTag3::from(credential.ticket.clone()),
+        authenticator: ExplicitContextTag4::from(
+            credential.key.encrypt(key_usage, &authenticator)?,
+        ),
+    }))
+}
+
+enum KdcReply {
+    Rep(KdcRep),
+    Error(KrbErrorInner),
+}
+
+/// Sends a request to the KDC over TCP, where messages are preceded by their length.
+async fn send_to_kdc(kdc: &str, request: &[u8]) -> Result<KdcReply> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let mut stream = TcpStream::connect(kdc).await?;
+    stream.write_u32(request.len() as u32).await?;
+    stream.write_all(request).await?;
+
+    let len = stream.read_u32().await?;
+    let mut reply = vec![0; len as usize];
+    stream.read_exact(&mut reply).await?;
+
+    match reply.first() {
+        Some(0x6b) => Ok(KdcReply::Rep(
+            picky_asn1_der::from_bytes::<AsRep>(&reply)?.0,
+        )),
+        Some(0x6d) => Ok(KdcReply::Rep(
+            picky_asn1_der::from_bytes::<TgsRep>(&reply)?.0,
+        )),
+        Some(0x7e) => Ok(KdcReply::Error(
+            picky_asn1_der::from_bytes::<KrbError>(&reply)?.0,
+        )),
+        _ => Err(Error::Kerberos("unexpected reply from the KDC".into())),
+    }
+}
+
+/// Decrypts the part of a reply from the KDC meant for us, which holds the ticket's session key.
+fn credential_from_reply(
+    reply: KdcRep,
+    key: &Key,
+    key_usage: i32,
+    nonce: u32,
+) -> Result<Credential> {
+    let enc_part = key.decrypt(key_usage, &reply.enc_part.0)?;
+    // Windows tags the encrypted part of AS-REPs as if they were TGS-REPs
+    let enc_part = match enc_part.first() {
+        Some(0x79) => picky_asn1_der::from_bytes::<EncAsRepPart>(&enc_part)?.0,
+        _ => picky_asn1_der::from_bytes::<EncTgsRepPart>(&enc_part)?.0,
+    };
+    if int(&enc_part.nonce.0) != nonce {
+        return Err(Error::Kerberos("KDC replied to a different request".into()));
+    }
+    Ok(Credential {
+        client: Principal::from_asn1(&reply.cname.0, &reply.crealm.0),
+        ticket: reply.ticket.0,
+        key: Key::from_asn1(&enc_part.key.0)?,
+    })
+}
+
+/// What the KDC told us about deriving the client's key, in the PA-ETYPE-INFO2 among its padata.
+fn etype_info(
+    padata: &[PaData],
+    etypes: &[CipherSuite],
+) -> Result<Option<(CipherSuite, Option<String>)>> {
+    let Some(padata) = padata
+        .iter()
+        .find(|padata| padata.padata_type.0.as_unsigned_bytes_be() == PA_ETYPE_INFO2_TYPE)
+    else {
+        return Ok(None);
+    };
+    let entries: EtypeInfo2 = picky_asn1_der::from_bytes(&padata.padata_data.0 .0)?;
+    for entry in entries.0 {
+        let Ok(etype) = etype(&entry.etype.0) else {
+            continue;
+        };
+        if etypes.contains(&etype) {
+            let salt = entry.salt.0.map(|salt| salt.0 .0.to_string());
+            return Ok(Some((etype, salt)));
+        }
+    }
+    Ok(None)
+}
+
+/// Gets a ticket granting ticket from the KDC's authentication service. If the KDC wants proof
+/// we know the key first, it tells us how to derive it and we try again.
+async fn request_tgt(kdc: &str, client: &Principal, secret: &Secret) -> Result<Credential> {
+    let etypes = secret.etypes(client);
+    if etypes.is_empty() {
+        return Err(Error::Kerberos(format!("no usable key for {client}")));
+    }
+    let tgs = Principal::tgs(&client.realm);
+    let pac_request = pa_data(
+        &PA_PAC_REQUEST_TYPE,
+        picky_asn1_der::to_vec(&KerbPaPacRequest {
+            include_pac: ExplicitContextTag0::from(true),
+        })?,
+    );
+
+    let mut padata = vec![pac_request.clone()];
+    let mut key_info = None;
+    loop {
+        let nonce = nonce();
+        let body = req_body(AS_REQ_OPTIONS, Some(client), &tgs, nonce, &etypes)?;
+        let request = AsReq::from(kdc_req(AS_REQ_MSG_TYPE, padata, body));
+        match send_to_kdc(kdc, &picky_asn1_der::to_vec(&request)?).await? {
+            KdcReply::Rep(reply) => {
+                let reply_padata = reply.padata.0.as_ref().map(|p| &p.0 .0[..]);
+                let (etype, salt) = match etype_info(reply_padata.unwrap_or_default(), &etypes)? {
+                    Some(info) => info,
+                    None => key_info.unwrap_or((etype(&reply.enc_part.0.etype.0)?, None)),
+                };
+                let key = secret.key(client, &etype, salt.as_deref())?;
+                return credential_from_reply(reply, &key, AS_REP_ENC, nonce);
+            }
+            KdcReply::Error(error)
+                if error.error_code.0 == KDC_ERR_PREAUTH_REQUIRED && key_info.is_none() =>
+            {
+                let method_data: Asn1SequenceOf<PaData> = match &error.e_data.0 {
+                    Some(e_data) => picky_asn1_der::from_bytes(&e_data.0 .0)?,
+                    None => Asn1SequenceOf::from(vec![]),
+                };
+                let (etype, salt) = etype_info(&method_data.0, &etypes)?
+                    .unwrap_or_else(|| (etypes[0].clone(), None));
+                let key = secret.key(client, &etype, salt.as_deref())?;
+
+                let (patimestamp, pausec) = now();
+                let timestamp = PaEncTsEnc {
+                    patimestamp: ExplicitContextTag0::from(patimestamp),
+                    pausec: Optional::from(Some(ExplicitContextTag1::from(pausec))),
+                };
+                let timestamp =
+                    key.encrypt(AS_REQ_TIMESTAMP, &picky_asn1_der::to_vec(&timestamp)?)?;
+                padata = vec![
+                    pa_data(&PA_ENC_TIMESTAMP, picky_asn1_der::to_vec(&timestamp)?),
+                    pac_request.clone(),
+                ];
+                key_info = Some((etype, salt));
+            }
+            KdcReply::Error(error) => return Err(Error::KrbError(error.error_code.0)),
+        }
+    }
+}
+
+/// Gets a ticket for a service from the KDC's ticket granting service.
+async fn request_service_ticket(
+    kdc: &str,
+    tgt: &Credential,
+    service: &Principal,
+) -> Result<Credential> {
+    let nonce = nonce();
+    let body = req_body(TGS_REQ_OPTIONS, None, service, nonce, &ETYPES)?;
+    let checksum = tgt.key.checksum(
+        TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR_CKSUM,
+        &picky_asn1_der::to_vec(&body)?,
+    )?;
+    let authenticator = authenticator(&tgt.client, checksum, None, None)?;
+    let ap_req = ap_req(
+        tgt,
+        [0; 4],
+        &authenticator,
+        TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR,
+    )?;
+    let padata = vec![pa_data(&PA_TGS_REQ_TYPE, picky_asn1_der::to_vec(&ap_req)?)];
+    let request = TgsReq::from(kdc_req(TGS_REQ_MSG_TYPE, padata, body));
+
+    match send_to_kdc(kdc, &picky_asn1_der::to_vec(&request)?).await? {
+        KdcReply::Rep(reply) => {
+            credential_from_reply(reply, &tgt.key, TGS_REP_ENC_SESSION_KEY, nonce)
+        }
+        KdcReply::Error(error) => Err(Error::KrbError(error.error_code.0)),
+    }
+}
+
+/// Takes apart a token framed as in RFC 2743 3.1, returning its token id and the message in it.
+fn unwrap_token(token: &[u8]) -> Result<([u8; 2], &[u8])> {
+    let invalid = || Error::Kerberos("invalid GSS-API token".into());
+    let (tag, contents, _) = split_der(token).ok_or_else(invalid)?;
+    if tag != 0x60 {
+        return Err(invalid());
+    }
+    let (_mechanism, _, rest) = split_der(contents).ok_or_else(invalid)?;
+    let (token_id, message) = rest.split_first_chunk::<2>().ok_or_else(invalid)?;
+    Ok((*token_id, message))
+}
+
+/// A security context with the server, established with a ticket for it.
+pub(crate) struct KerberosContext {
+    ticket: Credential,
+    subkey: Key,
+    acceptor_subkey: Option<Key>,
+}
+
+impl KerberosContext {
+    /// Gets a ticket for the server, from the credential cache if it has one and otherwise from
+    /// the KDC.
+    pub(crate) async fn new(
+        options: &KerberosOptions,
+        username: &str,
+        password: &str,
+    ) -> Result<Self> {
+        let tgt = match &options.credentials {
+            KerberosCredentials::Password => {
+                let client = Principal::user(username)?;
+                let secret = Secret::Password(password.into());
+                request_tgt(&options.kdc(&client.realm), &client, &secret).await?
+            }
+            KerberosCredentials::Keytab(path) => {
+                let client = Principal::user(username)?;
+                let secret = Secret::Keytab(read_keytab(path)?);
+                request_tgt(&options.kdc(&client.realm), &client, &secret).await?
+            }
+            KerberosCredentials::CredentialCache(path) => {
+                let cache = CredentialCache::read(path)?;
+                let realm = &cache.principal.realm;
+                let service = Principal::service("cifs", &options.server, realm);
+                if let Some(ticket) = cache.find(&service) {
+                    return Ok(Self::with_ticket(ticket));
+                }
+                cache.find(&Principal::tgs(realm)).ok_or_else(|| {
+                    Error::Kerberos(format!("no ticket granting ticket in {}", path.display()))
+                })?
+            }
+        };
+
+        let service = Principal::service("cifs", &options.server, &tgt.client.realm);
+        let kdc = options.kdc(&tgt.client.realm);
+        let ticket = request_service_ticket(&kdc, &tgt, &service).await?;
+        Ok(Self::with_ticket(ticket))
+    }
+
+    fn with_ticket(ticket: Credential) -> Self {
+        Self {
+            subkey: Key::random(&ticket.key.etype),
+            ticket,
+            acceptor_subkey: None,
+        }
+    }
+}
+
+impl Mechanism for KerberosContext {
+    fn oids(&self) -> Vec<MechType> {
+        vec![oids::krb5().into(), oids::ms_krb5().into()]
+    }
+
+    /// The AP-REQ is the only message we send.
+    fn step(&mut self, input: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        if input.is_some() {
+            return Err(Error::Kerberos(
+                "server asked for more than the AP-REQ".into(),
+            ));
+        }
+
+        let checksum = Checksum {
+            cksumtype: ExplicitContextTag0::from(IntegerAsn1::from(
+                AUTHENTICATOR_CHECKSUM_TYPE.to_vec(),
+            )),
+            checksum: ExplicitContextTag1::from(OctetStringAsn1::from(GSS_CHECKSUM.to_vec())),
+        };
+        let authenticator = authenticator(
+            &self.ticket.client,
+            checksum,
+            Some(&self.subkey),
+            Some(nonce()),
+        )?;
+        let ap_req = ap_req(
+            &self.ticket,
+            AP_OPTIONS_MUTUAL_REQUIRED,
+            &authenticator,
+            AP_REQ_AUTHENTICATOR,
+        )?;
+        let token: ApplicationTag<_, 0> = ApplicationTag::from(KrbMessage {
+            krb5_oid: oids::krb5().into(),
+            krb5_token_id: AP_REQ_TOKEN_ID,
+            krb_msg: ap_req,
+        });
+        Ok(picky_asn1_der::to_vec(&token)?)
+    }
+
+    /// Checks the AP-REP the server answered with. Only the server could decrypt our session key
+    /// from the ticket, so being able to decrypt its reply with it authenticates the server.
+    fn finish(&mut self, input: Option<Vec<u8>>) -> Result<()> {
+        let token = input.ok_or_else(|| Error::Kerberos("server sent no AP-REP".into()))?;
+        let (token_id, message) = unwrap_token(&token)?;
+        match token_id {
+            AP_REP_TOKEN_ID => {}
+            KRB_ERROR_TOKEN_ID => {
+                let error: KrbError = picky_asn1_der::from_bytes(message)?;
+                return Err(Error::KrbError(error.0.error_code.0));
+            }
+            _ => return Err(Error::Kerberos("server sent no AP-REP".into())),
+        }
+
+        let ap_rep: ApRep = picky_asn1_der::from_bytes(message)?;
+        let enc_part = self.ticket.key.decrypt(AP_REP_ENC, &ap_rep.0.enc_part.0)?;
+        let enc_part: EncApRepPart = picky_asn1_der::from_bytes(&enc_part)?;
+        self.acceptor_subkey = enc_part
+            .0
+            .subkey
+            .0
+            .map(|key| Key::from_asn1(&key.0))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// The server's subkey if it chose one, otherwise ours.
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        let key = self.acceptor_subkey.as_ref().unwrap_or(&self.subkey);
+        Ok(Some(key.value.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests;
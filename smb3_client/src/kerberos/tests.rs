@@ -0,0 +1,429 @@
+use super::*;
+use picky_asn1::wrapper::{ExplicitContextTag10, ExplicitContextTag12, ExplicitContextTag9};
+use picky_krb::constants::error_codes::KDC_ERR_PREAUTH_FAILED;
+use picky_krb::constants::key_usages::TICKET_REP;
+use picky_krb::constants::types::{
+    AP_REP_MSG_TYPE, AS_REP_MSG_TYPE, KRB_ERROR_MSG_TYPE, TGS_REP_MSG_TYPE,
+};
+use picky_krb::data_types::{EncApRepPartInner, EtypeInfo2Entry, TicketInner};
+use picky_krb::messages::{ApRepInner, EncKdcRepPart, TgsReq};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpListener;
+
+const REALM: &str = "EXAMPLE.COM";
+const USERNAME: &str = "alice@example.com";
+const PASSWORD: &str = "hunter2";
+const SERVER: &str = "fileserver.example.com";
+
+/// Not the default salt, so we know the client used the one the KDC told it about.
+const SALT: &str = "EXAMPLE.COMsaltyalice";
+
+/// A KDC that knows one user and one service. Real tickets hold an EncTicketPart, but as only the
+/// KDC and the service read them, ours just hold the session key.
+struct FakeKdc {
+    user_key: Key,
+    krbtgt_key: Key,
+    service_key: Key,
+}
+
+impl FakeKdc {
+    fn new() -> Self {
+        let etype = CipherSuite::Aes256CtsHmacSha196;
+        Self {
+            user_key: Key::from_password(&etype, PASSWORD, SALT).unwrap(),
+            krbtgt_key: Key::random(&etype),
+            service_key: Key::random(&etype),
+        }
+    }
+
+    /// Serves requests until the test ends, returning the address to send them to.
+    async fn start(self: Arc<Self>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u32().await.unwrap();
+                let mut request = vec![0; len as usize];
+                stream.read_exact(&mut request).await.unwrap();
+
+                let reply = self.reply(&request);
+                stream.write_u32(reply.len() as u32).await.unwrap();
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn reply(&self, request: &[u8]) -> Vec<u8> {
+        if request[0] == 0x6a {
+            let request: AsReq = picky_asn1_der::from_bytes(request).unwrap();
+            self.as_reply(request.0)
+        } else {
+            let request: TgsReq = picky_asn1_der::from_bytes(request).unwrap();
+            self.tgs_reply(request.0)
+        }
+    }
+
+    fn as_reply(&self, request: KdcReq) -> Vec<u8> {
+        let padata = request.padata.0.unwrap().0 .0;
+        let Some(timestamp) = find_padata(&padata, &PA_ENC_TIMESTAMP) else {
+            let etype_info = vec![EtypeInfo2Entry {
+                etype: ExplicitContextTag0::from(integer(u32::from(&self.user_key.etype))),
+                salt: Optional::from(Some(ExplicitContextTag1::from(
+                    kerberos_string(SALT).unwrap(),
+                ))),
+                s2kparams: Optional::from(None),
+            }];
+            let etype_info = picky_asn1_der::to_vec(&Asn1SequenceOf::from(etype_info)).unwrap();
+            let method_data = vec![pa_data(&PA_ETYPE_INFO2_TYPE, etype_info)];
+            return krb_error(
+                KDC_ERR_PREAUTH_REQUIRED,
+                picky_asn1_der::to_vec(&Asn1SequenceOf::from(method_data)).unwrap(),
+            );
+        };
+
+        let timestamp: EncryptedData = picky_asn1_der::from_bytes(&timestamp).unwrap();
+        let Ok(timestamp) = self.user_key.decrypt(AS_REQ_TIMESTAMP, &timestamp) else {
+            return krb_error(KDC_ERR_PREAUTH_FAILED, vec![]);
+        };
+        let _: PaEncTsEnc = picky_asn1_der::from_bytes(&timestamp).unwrap();
+
+        let body = request.req_body.0;
+        let client = body.cname.0.unwrap().0;
+        assert_eq!(
+            Principal::from_asn1(&client, &body.realm.0),
+            Principal::user(USERNAME).unwrap()
+        );
+        let tgs = Principal::tgs(REALM);
+        let enc_part =
+            EncAsRepPart::from(self.enc_kdc_rep_part(&tgs, int(&body.nonce.0), &self.tgt_key()));
+        let reply = AsRep::from(KdcRep {
+            pvno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+            msg_type: ExplicitContextTag1::from(integer(AS_REP_MSG_TYPE as u32)),
+            padata: Optional::from(None),
+            crealm: ExplicitContextTag3::from(body.realm.0),
+            cname: ExplicitContextTag4::from(client),
+            ticket: ExplicitContextTag5::from(ticket(&tgs, &self.krbtgt_key, &self.tgt_key())),
+            enc_part: ExplicitContextTag6::from(
+                self.user_key
+                    .encrypt(AS_REP_ENC, &picky_asn1_der::to_vec(&enc_part).unwrap())
+                    .unwrap(),
+            ),
+        });
+        picky_asn1_der::to_vec(&reply).unwrap()
+    }
+
+    fn tgs_reply(&self, request: KdcReq) -> Vec<u8> {
+        let padata = request.padata.0.unwrap().0 .0;
+        let ap_req = find_padata(&padata, &PA_TGS_REQ_TYPE).unwrap();
+        let ap_req: ApReq = picky_asn1_der::from_bytes(&ap_req).unwrap();
+        let tgt_key = ticket_key(&ap_req.0.ticket.0, &self.krbtgt_key);
+        let authenticator = tgt_key
+            .decrypt(
+                TGS_REQ_PA_DATA_AP_REQ_AUTHENTICATOR,
+                &ap_req.0.authenticator.0,
+            )
+            .unwrap();
+        let authenticator: Authenticator = picky_asn1_der::from_bytes(&authenticator).unwrap();
+        let client = &authenticator.0.cname.0;
+
+        let body = request.req_body.0;
+        let service = Principal::from_asn1(&body.sname.0.unwrap().0, &body.realm.0);
+        assert_eq!(service, Principal::service("cifs", SERVER, REALM));
+        let service_ticket_key = Key::random(&self.service_key.etype);
+        let enc_part = EncTgsRepPart::from(self.enc_kdc_rep_part(
+            &service,
+            int(&body.nonce.0),
+            &service_ticket_key,
+        ));
+        let reply = TgsRep::from(KdcRep {
+            pvno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+            msg_type: ExplicitContextTag1::from(integer(TGS_REP_MSG_TYPE as u32)),
+            padata: Optional::from(None),
+            crealm: ExplicitContextTag3::from(authenticator.0.crealm.0.clone()),
+            cname: ExplicitContextTag4::from(client.clone()),
+            ticket: ExplicitContextTag5::from(ticket(
+                &service,
+                &self.service_key,
+                &service_ticket_key,
+            )),
+            enc_part: ExplicitContextTag6::from(
+                tgt_key
+                    .encrypt(
+                        TGS_REP_ENC_SESSION_KEY,
+                        &picky_asn1_der::to_vec(&enc_part).unwrap(),
+                    )
+                    .unwrap(),
+            ),
+        });
+        picky_asn1_der::to_vec(&reply).unwrap()
+    }
+
+    /// Every TGT gets the same session key, which is fine for a test.
+    fn tgt_key(&self) -> Key {
+        Key {
+            etype: self.krbtgt_key.etype.clone(),
+            value: vec![0x5a; 32],
+        }
+    }
+
+    fn enc_kdc_rep_part(&self, server: &Principal, nonce: u32, key: &Key) -> EncKdcRepPart {
+        let (now, _) = now();
+        let end_time = GeneralizedTime::from(OffsetDateTime::now_utc() + TICKET_LIFETIME);
+        EncKdcRepPart {
+            key: ExplicitContextTag0::from(key.to_asn1()),
+            last_req: ExplicitContextTag1::from(Asn1SequenceOf::from(vec![])),
+            nonce: ExplicitContextTag2::from(integer(nonce)),
+            key_expiration: Optional::from(None),
+            flags: ExplicitContextTag4::from(KerberosFlags::from(BitString::with_bytes(vec![
+                0;
+                4
+            ]))),
+            auth_time: ExplicitContextTag5::from(now),
+            start_time: Optional::from(None),
+            end_time: ExplicitContextTag7::from(KerberosTime::from(end_time)),
+            renew_till: Optional::from(None),
+            srealm: ExplicitContextTag9::from(server.realm().unwrap()),
+            sname: ExplicitContextTag10::from(server.name().unwrap()),
+            caadr: Optional::from(None),
+            encrypted_pa_data: Optional::from(None),
+        }
+    }
+}
+
+fn find_padata(padata: &[PaData], padata_type: &[u8]) -> Option<Vec<u8>> {
+    padata
+        .iter()
+        .find(|padata| padata.padata_type.0 .0 == padata_type)
+        .map(|padata| padata.padata_data.0 .0.clone())
+}
+
+fn krb_error(error_code: u32, e_data: Vec<u8>) -> Vec<u8> {
+    let (stime, susec) = now();
+    let error = KrbError::from(KrbErrorInner {
+        pvno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+        msg_type: ExplicitContextTag1::from(integer(KRB_ERROR_MSG_TYPE as u32)),
+        ctime: Optional::from(None),
+        cusec: Optional::from(None),
+        stime: ExplicitContextTag4::from(stime),
+        susec: ExplicitContextTag5::from(susec),
+        error_code: ExplicitContextTag6::from(error_code),
+        crealm: Optional::from(None),
+        cname: Optional::from(None),
+        realm: ExplicitContextTag9::from(kerberos_string(REALM).unwrap()),
+        sname: ExplicitContextTag10::from(Principal::tgs(REALM).name().unwrap()),
+        e_text: Optional::from(None),
+        e_data: Optional::from(Some(ExplicitContextTag12::from(OctetStringAsn1::from(
+            e_data,
+        )))),
+    });
+    picky_asn1_der::to_vec(&error).unwrap()
+}
+
+fn ticket(server: &Principal, server_key: &Key, session_key: &Key) -> Ticket {
+    Ticket::from(TicketInner {
+        tkt_vno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+        realm: ExplicitContextTag1::from(server.realm().unwrap()),
+        sname: ExplicitContextTag2::from(server.name().unwrap()),
+        enc_part: ExplicitContextTag3::from(
+            server_key.encrypt(TICKET_REP, &session_key.value).unwrap(),
+        ),
+    })
+}
+
+fn ticket_key(ticket: &Ticket, server_key: &Key) -> Key {
+    Key {
+        etype: server_key.etype.clone(),
+        value: server_key
+            .decrypt(TICKET_REP, &ticket.0.enc_part.0)
+            .unwrap(),
+    }
+}
+
+/// Does what the SMB server does with the AP-REQ, returning its AP-REP and the session key it
+/// settled on.
+fn accept(token: &[u8], service_key: &Key) -> (Vec<u8>, Vec<u8>) {
+    let (token_id, message) = unwrap_token(token).unwrap();
+    assert_eq!(token_id, AP_REQ_TOKEN_ID);
+    let ap_req: ApReq = picky_asn1_der::from_bytes(message).unwrap();
+    assert_eq!(
+        ap_req.0.ap_options.0 .0.payload_view(),
+        AP_OPTIONS_MUTUAL_REQUIRED
+    );
+
+    let ticket_key = ticket_key(&ap_req.0.ticket.0, service_key);
+    let authenticator = ticket_key
+        .decrypt(AP_REQ_AUTHENTICATOR, &ap_req.0.authenticator.0)
+        .unwrap();
+    let authenticator: Authenticator = picky_asn1_der::from_bytes(&authenticator).unwrap();
+    let checksum = authenticator.0.cksum.0.unwrap().0;
+    assert_eq!(checksum.cksumtype.0 .0, AUTHENTICATOR_CHECKSUM_TYPE);
+    assert_eq!(checksum.checksum.0 .0, GSS_CHECKSUM);
+    assert!(authenticator.0.subkey.0.is_some());
+
+    let acceptor_subkey = Key::random(&ticket_key.etype);
+    let (ctime, cusec) = now();
+    let enc_part = EncApRepPart::from(EncApRepPartInner {
+        ctime: ExplicitContextTag0::from(ctime),
+        cusec: ExplicitContextTag1::from(cusec),
+        subkey: Optional::from(Some(ExplicitContextTag2::from(acceptor_subkey.to_asn1()))),
+        seq_number: Optional::from(None),
+    });
+    let ap_rep = ApRep::from(ApRepInner {
+        pvno: ExplicitContextTag0::from(integer(KERBEROS_VERSION)),
+        msg_type: ExplicitContextTag1::from(integer(AP_REP_MSG_TYPE as u32)),
+        enc_part: ExplicitContextTag2::from(
+            ticket_key
+                .encrypt(AP_REP_ENC, &picky_asn1_der::to_vec(&enc_part).unwrap())
+                .unwrap(),
+        ),
+    });
+    let token: ApplicationTag<_, 0> = ApplicationTag::from(KrbMessage {
+        krb5_oid: oids::krb5().into(),
+        krb5_token_id: AP_REP_TOKEN_ID,
+        krb_msg: ap_rep,
+    });
+    (
+        picky_asn1_der::to_vec(&token).unwrap(),
+        acceptor_subkey.value,
+    )
+}
+
+/// Runs the client's side of the exchange against `accept`.
+async fn authenticate(options: &KerberosOptions, service_key: &Key) {
+    let mut context = KerberosContext::new(options, USERNAME, PASSWORD)
+        .await
+        .unwrap();
+    let (ap_rep, session_key) = accept(&context.step(None).unwrap(), service_key);
+    context.finish(Some(ap_rep)).unwrap();
+    assert_eq!(context.session_key().unwrap(), Some(session_key));
+}
+
+#[tokio::test]
+async fn password_authentication() {
+    let kdc = Arc::new(FakeKdc::new());
+    let options = KerberosOptions {
+        server: SERVER.into(),
+        kdc: Some(kdc.clone().start().await),
+        credentials: KerberosCredentials::Password,
+    };
+    authenticate(&options, &kdc.service_key).await;
+}
+
+#[tokio::test]
+async fn keytab_authentication() {
+    let kdc = Arc::new(FakeKdc::new());
+
+    // A deleted entry, then the user's key
+    let mut entry = vec![];
+    entry.extend(1u16.to_be_bytes());
+    for string in [REALM, "alice"] {
+        entry.extend((string.len() as u16).to_be_bytes());
+        entry.extend(string.as_bytes());
+    }
+    entry.extend((NT_PRINCIPAL as u32).to_be_bytes());
+    entry.extend(0u32.to_be_bytes());
+    entry.push(1);
+    entry.extend((u32::from(&kdc.user_key.etype) as u16).to_be_bytes());
+    entry.extend((kdc.user_key.value.len() as u16).to_be_bytes());
+    entry.extend(&kdc.user_key.value);
+    let mut keytab = vec![0x05, 0x02];
+    keytab.extend((-4i32).to_be_bytes());
+    keytab.extend([0; 4]);
+    keytab.extend((entry.len() as i32).to_be_bytes());
+    keytab.extend(entry);
+
+    let path = std::env::temp_dir().join(format!("smb3_client_test_{}.keytab", nonce()));
+    std::fs::write(&path, keytab).unwrap();
+    let options = KerberosOptions {
+        server: SERVER.into(),
+        kdc: Some(kdc.clone().start().await),
+        credentials: KerberosCredentials::Keytab(path.clone()),
+    };
+    authenticate(&options, &kdc.service_key).await;
+    std::fs::remove_file(path).unwrap();
+}
+
+fn write_principal(cache: &mut Vec<u8>, principal: &Principal) {
+    cache.extend((principal.name_type as u32).to_be_bytes());
+    cache.extend((principal.components.len() as u32).to_be_bytes());
+    for string in std::iter::once(&principal.realm).chain(&principal.components) {
+        cache.extend((string.len() as u32).to_be_bytes());
+        cache.extend(string.as_bytes());
+    }
+}
+
+fn write_credential(cache: &mut Vec<u8>, server: &Principal, key: &Key, ticket: &[u8]) {
+    write_principal(cache, &Principal::user(USERNAME).unwrap());
+    write_principal(cache, server);
+    cache.extend((u32::from(&key.etype) as u16).to_be_bytes());
+    cache.extend((key.value.len() as u32).to_be_bytes());
+    cache.extend(&key.value);
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u32;
+    for time in [now, now, now + 3600, 0] {
+        cache.extend(time.to_be_bytes());
+    }
+    cache.push(0);
+    cache.extend([0; 4]);
+    cache.extend([0; 4]);
+    cache.extend([0; 4]);
+    cache.extend((ticket.len() as u32).to_be_bytes());
+    cache.extend(ticket);
+    cache.extend([0; 4]);
+}
+
+#[tokio::test]
+async fn credential_cache_authentication() {
+    // The cache already has a ticket for the server, so there's no need for a KDC
+    let kdc = FakeKdc::new();
+    let service = Principal::service("cifs", SERVER, REALM);
+    let session_key = Key::random(&kdc.service_key.etype);
+    let ticket = ticket(&service, &kdc.service_key, &session_key);
+
+    let mut cache = vec![0x05, 0x04, 0x00, 0x00];
+    write_principal(&mut cache, &Principal::user(USERNAME).unwrap());
+    let config = Principal {
+        name_type: 0,
+        components: vec!["krb5_ccache_conf_data".into(), "fast_avail".into()],
+        realm: "X-CACHECONF:".into(),
+    };
+    write_credential(
+        &mut cache,
+        &config,
+        &Key::random(&session_key.etype),
+        b"yes",
+    );
+    write_credential(
+        &mut cache,
+        &service,
+        &session_key,
+        &picky_asn1_der::to_vec(&ticket).unwrap(),
+    );
+
+    let path = std::env::temp_dir().join(format!("smb3_client_test_{}.ccache", nonce()));
+    std::fs::write(&path, cache).unwrap();
+    let options = KerberosOptions {
+        server: SERVER.into(),
+        kdc: Some("127.0.0.1:1".into()),
+        credentials: KerberosCredentials::CredentialCache(path.clone()),
+    };
+    authenticate(&options, &kdc.service_key).await;
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn wrong_password_fails() {
+    let kdc = Arc::new(FakeKdc::new());
+    let options = KerberosOptions {
+        server: SERVER.into(),
+        kdc: Some(kdc.clone().start().await),
+        credentials: KerberosCredentials::Password,
+    };
+    let result = KerberosContext::new(&options, USERNAME, "hunter3").await;
+    assert!(matches!(
+        result,
+        Err(Error::KrbError(KDC_ERR_PREAUTH_FAILED))
+    ));
+}
@@ -1,24 +1,55 @@
 use sspi_bobbobbio as sspi;
 
+use aes_gcm::aead::{
+    consts::{U11, U16},
+    generic_array::{typenum::Unsigned as _, GenericArray},
+    AeadInPlace,
+};
 use cmac::Mac as _;
 use derive_more::From;
+use futures::stream::{self, StreamExt as _, TryStreamExt as _};
+use rand::rngs::OsRng;
 use rand::Rng as _;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
 use sha2::Digest as _;
 use smb3::*;
-use sspi::builders::EmptyInitializeSecurityContext;
-use sspi::{
-    AuthIdentity, ClientRequestFlags, CredentialUse, DataRepresentation, Ntlm, SecurityBuffer,
-    SecurityBufferType, SecurityStatus, Sspi, SspiImpl,
-};
-use std::mem;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
 use std::path::{Component, Path};
-use rand::rngs::OsRng;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use tokio::io::{self, AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+
+pub use authenticator::{Authenticator, KerberosAuthenticator, NtlmAuthenticator};
+pub use cache::CachedFile;
+pub use kerberos::{KerberosCredentials, KerberosOptions, KDC_PORT};
+pub use lock::{LockGuard, LockMode};
+pub use oplock::{CachingRequest, LeasedFile};
+pub use reconnect::{Connector, ReconnectingClient, RetryOptions};
+
+mod authenticator;
+mod cache;
+mod kerberos;
+mod lock;
+mod ntlm;
+mod oplock;
+mod reconnect;
+mod spnego;
+mod watch;
 
 pub const PORT: u16 = 445;
 
-const IO_SIZE: usize = 4096 * 16;
+/// The amount of payload a single credit pays for.
+const CREDIT_PAYLOAD_SIZE: u32 = 64 * 1024;
+
+/// Upper bound on a single read or write, regardless of what the server allows.
+const MAX_IO_SIZE: u32 = 8 * 1024 * 1024;
+
+/// How many credits we ask the server to keep us supplied with.
+const TARGET_CREDITS: u32 = 512;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -28,375 +59,2456 @@ pub enum Error {
     Sspi(sspi::Error),
     Seralization(serde_smb::Error),
     Io(std::io::Error),
+    Asn1(picky_asn1_der::Asn1DerError),
+    KerberosCrypto(picky_krb::crypto::KerberosCryptoError),
+    #[from(ignore)]
+    Kerberos(String),
+    /// The KDC or the server answered with a KRB-ERROR with this error code.
+    #[from(ignore)]
+    KrbError(u32),
+    /// The server doesn't support the security mechanism we were configured to use.
+    #[from(ignore)]
+    UnsupportedMechanism,
+    #[from(ignore)]
+    DecryptionFailed,
+    #[from(ignore)]
+    EncryptionNotSupported,
+    #[from(ignore)]
+    InvalidSignature,
+    #[from(ignore)]
+    InsufficientCredits,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NtStatus(status) => write!(f, "server responded with an error: {status}"),
+            Self::Sspi(error) => write!(f, "authentication failed: {error}"),
+            Self::Seralization(error) => write!(f, "malformed message: {error}"),
+            Self::Io(error) => fmt::Display::fmt(error, f),
+            Self::Asn1(error) => write!(f, "malformed ASN.1: {error}"),
+            Self::KerberosCrypto(error) => write!(f, "Kerberos cryptography failed: {error}"),
+            Self::Kerberos(message) => write!(f, "Kerberos authentication failed: {message}"),
+            Self::KrbError(code) => write!(f, "Kerberos authentication failed with error {code}"),
+            Self::UnsupportedMechanism => {
+                f.write_str("the server doesn't support the authentication mechanism")
+            }
+            Self::DecryptionFailed => f.write_str("failed to decrypt a message"),
+            Self::EncryptionNotSupported => {
+                f.write_str("encryption is required but can't be done on this session")
+            }
+            Self::InvalidSignature => f.write_str("a message has an invalid signature"),
+            Self::InsufficientCredits => {
+                f.write_str("the server didn't grant enough credits for the request")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Sspi(error) => Some(error),
+            Self::Seralization(error) => Some(error),
+            Self::Io(error) => Some(error),
+            Self::Asn1(error) => Some(error),
+            Self::KerberosCrypto(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
 }
 
-pub trait Transport: io::AsyncRead + io::AsyncWrite + Unpin {}
+/// The closest `io::ErrorKind` to a status the server responded with.
+fn nt_status_kind(status: NtStatus) -> io::ErrorKind {
+    use io::ErrorKind::*;
+    match status {
+        NtStatus::ObjectNameNotFound
+        | NtStatus::ObjectPathNotFound
+        | NtStatus::NoSuchFile
+        | NtStatus::NoSuchDevice
+        | NtStatus::BadNetworkName
+        | NtStatus::FileDeleted
+        | NtStatus::DeletePending => NotFound,
+        NtStatus::AccessDenied
+        | NtStatus::NetworkAccessDenied
+        | NtStatus::PrivilegeNotHeld
+        | NtStatus::CannotDelete
+        | NtStatus::LogonFailure
+        | NtStatus::WrongPassword
+        | NtStatus::NoSuchUser
+        | NtStatus::AccountDisabled
+        | NtStatus::AccountLockedOut
+        | NtStatus::AccountRestriction
+        | NtStatus::PasswordExpired => PermissionDenied,
+        NtStatus::ObjectNameCollision => AlreadyExists,
+        NtStatus::SharingViolation | NtStatus::FileLockConflict | NtStatus::LockNotGranted => {
+            ResourceBusy
+        }
+        NtStatus::DirectoryNotEmpty => DirectoryNotEmpty,
+        NtStatus::NotADirectory => NotADirectory,
+        NtStatus::FileIsADirectory => IsADirectory,
+        NtStatus::DiskFull => StorageFull,
+        NtStatus::QuotaExceeded => QuotaExceeded,
+        NtStatus::MediaWriteProtected => ReadOnlyFilesystem,
+        NtStatus::NotSameDevice => CrossesDevices,
+        NtStatus::ObjectNameInvalid | NtStatus::ObjectPathSyntaxBad | NtStatus::NameTooLong => {
+            InvalidFilename
+        }
+        NtStatus::InvalidParameter
+        | NtStatus::InvalidInfoClass
+        | NtStatus::InfoLengthMismatch
+        | NtStatus::InvalidHandle
+        | NtStatus::FileClosed
+        | NtStatus::ObjectPathInvalid => InvalidInput,
+        NtStatus::NotSupported | NtStatus::NotImplemented | NtStatus::InvalidDeviceRequest => {
+            Unsupported
+        }
+        NtStatus::EndOfFile => UnexpectedEof,
+        NtStatus::IoTimeout => TimedOut,
+        NtStatus::Cancelled => Interrupted,
+        NtStatus::NoMemory | NtStatus::InsufficientResources => OutOfMemory,
+        NtStatus::TooManyOpenedFiles => QuotaExceeded,
+        NtStatus::PipeBroken | NtStatus::PipeDisconnected => BrokenPipe,
+        NtStatus::ConnectionReset => ConnectionReset,
+        NtStatus::ConnectionDisconnected
+        | NtStatus::NetworkNameDeleted
+        | NtStatus::UserSessionDeleted
+        | NtStatus::Networksessionexpired => ConnectionAborted,
+        _ => Other,
+    }
+}
 
-impl<T> Transport for T where T: io::AsyncRead + io::AsyncWrite + Unpin {}
+impl Error {
+    /// The closest `io::ErrorKind`, which is also what the error has when it is converted to an
+    /// `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::NtStatus(status) => nt_status_kind(*status),
+            Self::Io(error) => error.kind(),
+            Self::Sspi(_) | Self::Kerberos(_) | Self::KrbError(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            Self::Seralization(_)
+            | Self::Asn1(_)
+            | Self::DecryptionFailed
+            | Self::InvalidSignature => io::ErrorKind::InvalidData,
+            Self::UnsupportedMechanism | Self::EncryptionNotSupported => io::ErrorKind::Unsupported,
+            Self::KerberosCrypto(_) | Self::InsufficientCredits => io::ErrorKind::Other,
+        }
+    }
 
-struct UnauthenticatedClient<TransportT> {
+    fn disconnected() -> Self {
+        Self::Io(io::ErrorKind::NotConnected.into())
+    }
+
+    /// What to tell the requests that were waiting on a connection that failed with this error.
+    fn connection_lost(&self) -> Self {
+        let kind = match self {
+            Self::Io(error) => error.kind(),
+            _ => io::ErrorKind::ConnectionAborted,
+        };
+        Self::Io(io::Error::new(kind, format!("connection lost: {self}")))
+    }
+}
+
+pub trait Transport: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> Transport for T where T: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static {}
+
+/// The parameters the server chose during negotiate.
+#[derive(Clone, Debug)]
+pub struct NegotiateInfo {
+    pub dialect: Dialect,
+    pub security_mode: SecurityMode,
+    pub capabilities: Capabilities,
+    pub server_guid: Uuid,
+    pub max_transaction_size: u32,
+    pub max_read_size: u32,
+    pub max_write_size: u32,
+    pub cipher: Option<CipherId>,
+    pub signing_algorithm: SigningAlgorithmId,
+    /// The server's SPNEGO hint at which security mechanisms it supports.
+    pub security_blob: Vec<u8>,
+}
+
+impl NegotiateInfo {
+    pub fn signing_required(&self) -> bool {
+        self.security_mode.contains(SecurityMode::SIGNING_REQURED)
+    }
+}
+
+pub struct ClientOptions {
+    /// The dialects to offer the server, which picks the highest one it supports.
+    pub dialects: Vec<Dialect>,
+    /// How many reads or writes `read_all` and `write_all` keep in flight at once.
+    pub max_outstanding_io: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            dialects: vec![
+                Dialect::Smb2_0_2,
+                Dialect::Smb2_1,
+                Dialect::Smb3_0,
+                Dialect::Smb3_0_2,
+                Dialect::Smb3_1_1,
+            ],
+            max_outstanding_io: 8,
+        }
+    }
+}
+
+/// Tracks the credits the server has granted us. Each credit allows the use of one message id.
+#[derive(Clone)]
+struct CreditManager {
     next_message_id: MessageId,
-    transport: TransportT,
-    pre_auth_hash: Vec<u8>,
+    available: u32,
+    /// Message ids below `next_message_id` that were given back unused, and how many of them
+    /// follow each. They count towards `available`.
+    returned: Vec<(MessageId, u32)>,
+}
+
+impl CreditManager {
+    fn new() -> Self {
+        // We start with a single credit, which pays for negotiate
+        Self {
+            next_message_id: MessageId(0),
+            available: 1,
+            returned: vec![],
+        }
+    }
+
+    /// The largest payload a request can carry with the credits we have. Its message ids have to
+    /// follow each other.
+    fn max_payload_size(&self) -> u32 {
+        let returned = self.returned.iter().map(|&(_, count)| count);
+        let contiguous = returned.clone().max().unwrap_or(0);
+        let contiguous = contiguous.max(self.available - returned.sum::<u32>());
+        contiguous.max(1).saturating_mul(CREDIT_PAYLOAD_SIZE)
+    }
+
+    /// Consumes the credits for some requests sent together and returns the first of the message
+    /// ids each one uses. Either all of them get their credits or none do.
+    fn acquire(&mut self, credit_charges: &[Credits]) -> Result<Vec<MessageId>> {
+        let mut credits = self.clone();
+        let message_ids = credit_charges
+            .iter()
+            .map(|&charge| credits.take(charge))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::InsufficientCredits)?;
+        *self = credits;
+        Ok(message_ids)
+    }
+
+    /// Takes the message ids for a single request, preferring ones that were given back.
+    fn take(&mut self, credit_charge: Credits) -> Option<MessageId> {
+        // A request with a charge of zero still uses a message id
+        let cost = credit_charge.0.max(1) as u32;
+        let message_id = match self.returned.iter().position(|&(_, count)| count >= cost) {
+            Some(i) => {
+                let (message_id, count) = &mut self.returned[i];
+                let taken = *message_id;
+                *message_id = MessageId(message_id.0 + cost as u64);
+                *count -= cost;
+                if *count == 0 {
+                    self.returned.remove(i);
+                }
+                taken
+            }
+            None => {
+                let returned: u32 = self.returned.iter().map(|&(_, count)| count).sum();
+                if cost > self.available - returned {
+                    return None;
+                }
+                let taken = self.next_message_id;
+                self.next_message_id = MessageId(taken.0 + cost as u64);
+                taken
+            }
+        };
+        self.available -= cost;
+        Some(message_id)
+    }
+
+    /// Gives back the message ids of a request that was never sent, so they can be used again.
+    fn release(&mut self, message_id: MessageId, credit_charge: Credits) {
+        self.available += credit_charge.0.max(1) as u32;
+        self.returned
+            .push((message_id, credit_charge.0.max(1) as u32));
+        // Those right below the next message id just make it go back
+        while let Some(i) = self
+            .returned
+            .iter()
+            .position(|&(message_id, count)| message_id.0 + count as u64 == self.next_message_id.0)
+        {
+            self.next_message_id = self.returned.remove(i).0;
+        }
+    }
+
+    fn grant(&mut self, credits_granted: Credits) {
+        self.available += credits_granted.0 as u32;
+    }
+
+    /// How many credits to ask for in a request, enough to replace what it uses and to work
+    /// our way up to `TARGET_CREDITS`.
+    fn credits_to_request(&self, credit_charge: Credits) -> Credits {
+        let wanted = TARGET_CREDITS.saturating_sub(self.available) + credit_charge.0.max(1) as u32;
+        Credits(wanted.min(u16::MAX as u32) as u16)
+    }
+}
+
+/// A message as it came off the wire, after decryption.
+struct ReceivedMessage {
+    bytes: Vec<u8>,
+    encrypted: bool,
+}
+
+type ResponseSender = oneshot::Sender<Result<ReceivedMessage>>;
+type ResponseReceiver = oneshot::Receiver<Result<ReceivedMessage>>;
+
+/// The message id and credits requested for a request, and where its response will arrive.
+type AcquiredCredits = (MessageId, Credits, ResponseReceiver);
+
+/// The state of the connection shared between the requests in flight and the tasks doing the
+/// reading and writing.
+struct ConnectionState {
+    credits: CreditManager,
+    pending: HashMap<MessageId, ResponseSender>,
+    /// Requests we stopped waiting for. Their responses still grant us credits.
+    cancelled: HashSet<MessageId>,
+    /// Requests the server told us it is completing asynchronously.
+    async_operations: HashMap<AsyncId, MessageId>,
+    decryption: HashMap<SessionId, Arc<Encryption>>,
+    /// Where to deliver break notifications for the handles holding oplocks and leases.
+    breaks: HashMap<BreakKey, mpsc::UnboundedSender<Break>>,
+    /// Oplock breaks can arrive before the create response has been handed over, and with it
+    /// which handle they belong to. While handles are being opened with oplocks, breaks nobody
+    /// has claimed yet wait here.
+    early_breaks: Vec<OplockBreak>,
+    oplock_opens: usize,
+    disconnected: bool,
+}
+
+/// What a break notification is delivered by: the handle for an oplock, the lease key for a
+/// lease.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum BreakKey {
+    File(FileId),
+    Lease(LeaseKey),
+}
+
+/// A break notification, which the server sends unsolicited.
+#[derive(Debug)]
+enum Break {
+    Oplock(OplockBreak),
+    Lease(LeaseBreakNotification),
+}
+
+struct Connection {
+    state: Mutex<ConnectionState>,
+    credits_granted: Notify,
+}
+
+impl Connection {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(ConnectionState {
+                credits: CreditManager::new(),
+                pending: HashMap::new(),
+                cancelled: HashSet::new(),
+                async_operations: HashMap::new(),
+                decryption: HashMap::new(),
+                breaks: HashMap::new(),
+                early_breaks: vec![],
+                oplock_opens: 0,
+                disconnected: false,
+            }),
+            credits_granted: Notify::new(),
+        }
+    }
+
+    fn disconnect(&self, error: Error) {
+        let mut state = self.state.lock().unwrap();
+        state.disconnected = true;
+        state.async_operations.clear();
+        state.cancelled.clear();
+        for (_, sender) in state.pending.drain() {
+            let _ = sender.send(Err(error.connection_lost()));
+        }
+        drop(state);
+        self.credits_granted.notify_waiters();
+    }
+
+    async fn receive(&self, reader: &mut (impl io::AsyncRead + Unpin)) -> Result<()> {
+        let len = reader.read_u32().await?;
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes).await?;
+
+        let encrypted = bytes.starts_with(b"\xfdSMB");
+        if encrypted {
+            let header_bytes = bytes
+                .get(..TRANSFORM_HEADER_SIZE)
+                .ok_or(Error::DecryptionFailed)?;
+            let header: TransformHeader = serde_smb::from_slice(header_bytes)?;
+            let encryption = self
+                .state
+                .lock()
+                .unwrap()
+                .decryption
+                .get(&header.session_id)
+                .cloned();
+            bytes = encryption.ok_or(Error::DecryptionFailed)?.decrypt(&bytes)?;
+        }
+
+        // A compound response holds several messages, each pointing to the one after it
+        let mut start = 0;
+        loop {
+            let header: ResponseHeader = serde_smb::from_slice(&bytes[start..])?;
+            let end = match header.chain_offset {
+                0 => bytes.len(),
+                offset => (start + offset as usize).min(bytes.len()),
+            };
+            self.route(header, bytes[start..end].to_vec(), encrypted);
+            if end == bytes.len() {
+                return Ok(());
+            }
+            start = end;
+        }
+    }
+
+    /// Hands a response to the request waiting for it.
+    fn route(&self, header: ResponseHeader, bytes: Vec<u8>, encrypted: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.credits.grant(header.credits_granted);
+        self.credits_granted.notify_waiters();
+
+        if header.message_id == MessageId(u64::MAX) {
+            if header.command == Command::OplockBreak {
+                route_break(&mut state, &bytes[HEADER_SIZE.min(bytes.len())..]);
+            }
+            return;
+        }
+
+        // Interim responses only tell us the request is going to take a while, and what async id
+        // the final response will carry
+        if header.nt_status == NtStatus::Pending {
+            if let Some(async_id) = header.async_id() {
+                state.async_operations.insert(async_id, header.message_id);
+            }
+            return;
+        }
+
+        let message_id = header
+            .async_id()
+            .and_then(|async_id| state.async_operations.remove(&async_id))
+            .unwrap_or(header.message_id);
+
+        // The request may have been abandoned, in which case nobody is interested
+        if let Some(sender) = state.pending.remove(&message_id) {
+            let _ = sender.send(Ok(ReceivedMessage { bytes, encrypted }));
+        } else {
+            state.cancelled.remove(&message_id);
+        }
+    }
+
+    /// Delivers break notifications for the handle or lease to `breaks`.
+    fn register_breaks(&self, key: BreakKey, breaks: mpsc::UnboundedSender<Break>) {
+        let mut state = self.state.lock().unwrap();
+        if let BreakKey::File(file_id) = key {
+            state.early_breaks.retain(|early| {
+                early.file_id != file_id || breaks.send(Break::Oplock(early.clone())).is_err()
+            });
+        }
+        state.breaks.insert(key, breaks);
+    }
+
+    fn unregister_breaks(&self, key: BreakKey) {
+        self.state.lock().unwrap().breaks.remove(&key);
+    }
+
+    /// Takes over delivering the break notifications of a connection this one replaces.
+    fn take_breaks(&self, previous: &Connection) {
+        let breaks = std::mem::take(&mut previous.state.lock().unwrap().breaks);
+        self.state.lock().unwrap().breaks.extend(breaks);
+    }
+
+    /// Delivers a break notification that didn't come from the server.
+    fn deliver_break(&self, key: BreakKey, notification: Break) {
+        if let Some(breaks) = self.state.lock().unwrap().breaks.get(&key) {
+            let _ = breaks.send(notification);
+        }
+    }
+
+    /// Keeps oplock breaks that arrive until the returned guard is dropped, for the handle being
+    /// opened to claim them.
+    fn oplock_open(&self) -> OplockOpen<'_> {
+        self.state.lock().unwrap().oplock_opens += 1;
+        OplockOpen { connection: self }
+    }
+}
+
+struct OplockOpen<'a> {
+    connection: &'a Connection,
+}
+
+impl Drop for OplockOpen<'_> {
+    fn drop(&mut self) {
+        let mut state = self.connection.state.lock().unwrap();
+        state.oplock_opens -= 1;
+        if state.oplock_opens == 0 {
+            state.early_breaks.clear();
+        }
+    }
+}
+
+/// Hands a break notification to the handle it is for. Oplock and lease breaks share a command,
+/// and are told apart by their size.
+fn route_break(state: &mut ConnectionState, body: &[u8]) {
+    let (key, notification) = if body.starts_with(&44u16.to_le_bytes()) {
+        let Ok(notification) = serde_smb::from_slice::<LeaseBreakNotification>(body) else {
+            return;
+        };
+        (
+            BreakKey::Lease(notification.lease_key),
+            Break::Lease(notification),
+        )
+    } else {
+        let Ok(notification) = serde_smb::from_slice::<OplockBreak>(body) else {
+            return;
+        };
+        if !state
+            .breaks
+            .contains_key(&BreakKey::File(notification.file_id))
+        {
+            if state.oplock_opens > 0 {
+                state.early_breaks.push(notification);
+            }
+            return;
+        }
+        (
+            BreakKey::File(notification.file_id),
+            Break::Oplock(notification),
+        )
+    };
+    if let Some(breaks) = state.breaks.get(&key) {
+        let _ = breaks.send(notification);
+    }
+}
+
+async fn read_messages(mut reader: impl io::AsyncRead + Unpin, connection: Arc<Connection>) {
+    let error = loop {
+        if let Err(error) = connection.receive(&mut reader).await {
+            break error;
+        }
+    };
+    connection.disconnect(error);
+}
+
+/// What the writing task is asked to do.
+enum Outgoing {
+    Frame(Vec<u8>),
+    /// Shuts down the writing half of the transport, once the frames queued before are written.
+    Shutdown(oneshot::Sender<Result<()>>),
+}
+
+async fn write_messages(
+    mut writer: impl io::AsyncWrite + Unpin,
+    mut messages: mpsc::UnboundedReceiver<Outgoing>,
+    connection: Arc<Connection>,
+) {
+    while let Some(message) = messages.recv().await {
+        match message {
+            Outgoing::Frame(frame) => {
+                if let Err(error) = writer.write_all(&frame).await {
+                    connection.disconnect(error.into());
+                    return;
+                }
+            }
+            Outgoing::Shutdown(done) => {
+                let result = writer.shutdown().await.map_err(Error::from);
+                connection.disconnect(Error::disconnected());
+                let _ = done.send(result);
+                return;
+            }
+        }
+    }
+}
+
+/// Cancels the requests that were sent if we stop waiting for their responses.
+struct CancelOnDrop<'a, TransportT> {
+    client: &'a UnauthenticatedClient<TransportT>,
+    requests: Vec<(MessageId, TreeId)>,
+    session_id: SessionId,
+    signature_func: Option<SignatureFuncRef<'a>>,
+    encryption: Option<&'a Encryption>,
+}
+
+impl<TransportT> Drop for CancelOnDrop<'_, TransportT> {
+    fn drop(&mut self) {
+        // Parts of a compound whose responses already arrived are done with
+        let pending: Vec<_> = {
+            let state = self.client.connection.state.lock().unwrap();
+            self.requests
+                .iter()
+                .copied()
+                .filter(|(message_id, _)| state.pending.contains_key(message_id))
+                .collect()
+        };
+        for (message_id, tree_id) in pending {
+            // If it fails the connection is gone, and the request with it
+            let _ = self.client.cancel(
+                message_id,
+                self.session_id,
+                tree_id,
+                self.signature_func,
+                self.encryption,
+            );
+        }
+    }
 }
 
-type SignatureFuncRef<'a> = &'a mut (dyn FnMut(&[u8]) -> Result<Signature>+Send);
+/// A request that has been serialized, but whose header is yet to be filled in.
+struct OutgoingRequest {
+    credit_charge: Credits,
+    tree_id: Option<TreeId>,
+    /// Whether it operates on the file the previous request in the compound did.
+    related: bool,
+    /// Whether it repeats a request that may have reached the server before the connection was
+    /// lost.
+    replay: bool,
+    bytes: Vec<u8>,
+}
+
+impl OutgoingRequest {
+    fn new<T: serde::Serialize + HasCommand>(
+        credit_charge: Credits,
+        tree_id: Option<TreeId>,
+        related: bool,
+        request: T,
+    ) -> Result<Self> {
+        // The body is serialized along with a header, as its offsets count from the start of it
+        let header = request_header(T::command(), MessageId(0), SessionId(0), TreeId(0));
+        Ok(Self {
+            credit_charge,
+            tree_id,
+            related,
+            replay: false,
+            bytes: serde_smb::to_vec(&(header, request))?,
+        })
+    }
+
+    fn replay(self) -> Self {
+        Self {
+            replay: true,
+            ..self
+        }
+    }
+}
+
+fn request_header(
+    command: Command,
+    message_id: MessageId,
+    session_id: SessionId,
+    tree_id: TreeId,
+) -> RequestHeader {
+    RequestHeader {
+        protocol_id: ProtocolId::new(),
+        header_length: 64,
+        credit_charge: Credits(0),
+        channel_sequence: 0,
+        command,
+        credits_requested: Credits(0),
+        flags: HeaderFlags::new(),
+        chain_offset: 0,
+        message_id,
+        process_id: ProcessId(0),
+        tree_id,
+        session_id,
+        signature: Signature([0; 16]),
+    }
+}
+
+struct UnauthenticatedClient<TransportT> {
+    connection: Arc<Connection>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    /// Only kept when 3.1.1 is offered, and until the server picks another dialect.
+    pre_auth_hash: Mutex<Option<Vec<u8>>>,
+    negotiate_info: OnceLock<NegotiateInfo>,
+    _transport: PhantomData<fn() -> TransportT>,
+}
+
+type SignatureFuncRef<'a> = &'a (dyn Fn(&[u8]) -> Result<Signature> + Sync);
+
+impl<TransportT> Drop for UnauthenticatedClient<TransportT> {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+impl<TransportT> UnauthenticatedClient<TransportT> {
+    /// Signs or encrypts the messages and queues them to be written. Several messages make up a
+    /// compound, which is sent in a single frame.
+    fn send(
+        &self,
+        messages: Vec<Vec<u8>>,
+        session_id: SessionId,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+    ) -> Result<()> {
+        let mut message = vec![];
+        for mut part in messages {
+            // Encryption covers the whole compound, signatures each message in it
+            if let (None, Some(func)) = (encryption, signature_func) {
+                let sig = func(&part[..])?;
+                part[48..64].clone_from_slice(&sig.0[..]);
+            }
+            message.extend(part);
+        }
+        if let Some(encryption) = encryption {
+            message = encryption.encrypt(session_id, message)?;
+        }
+
+        let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+        frame.extend(message);
+        self.outgoing
+            .send(Outgoing::Frame(frame))
+            .map_err(|_| Error::disconnected())
+    }
+
+    /// Shuts down the transport after the messages already queued are written. Requests still
+    /// waiting for a response fail, and so does anything sent afterwards.
+    async fn shutdown(&self) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.outgoing
+            .send(Outgoing::Shutdown(done))
+            .map_err(|_| Error::disconnected())?;
+        result.await.map_err(|_| Error::disconnected())?
+    }
+
+    /// Asks the server to stop working on a request. We stop waiting for its response right away.
+    /// A cancel doesn't use up a message id or credits, and gets no response of its own.
+    fn cancel(
+        &self,
+        message_id: MessageId,
+        session_id: SessionId,
+        tree_id: TreeId,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+    ) -> Result<()> {
+        // Once the server has gone async on a request, it is identified by its async id
+        let async_id = {
+            let mut state = self.connection.state.lock().unwrap();
+            if state.pending.remove(&message_id).is_some() {
+                state.cancelled.insert(message_id);
+            }
+            let async_id = state
+                .async_operations
+                .iter()
+                .find_map(|(async_id, id)| (*id == message_id).then_some(*async_id));
+            if let Some(async_id) = &async_id {
+                state.async_operations.remove(async_id);
+            }
+            async_id
+        };
+
+        let mut header = request_header(Command::Cancel, message_id, session_id, tree_id);
+        header.flags = header
+            .flags
+            .with_signing(signature_func.is_some() && encryption.is_none());
+        if let Some(async_id) = async_id {
+            header.set_async_id(async_id);
+        }
+
+        let req_bytes = serde_smb::to_vec(&(header, CancelRequest))?;
+        self.send(vec![req_bytes], session_id, signature_func, encryption)
+    }
+}
 
 impl<TransportT: Transport> UnauthenticatedClient<TransportT> {
+    /// Takes ownership of the transport, reading and writing it from background tasks so many
+    /// requests can be in flight at once.
     fn new(transport: TransportT) -> Self {
+        let connection = Arc::new(Connection::new());
+        let (reader, writer) = io::split(transport);
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
         Self {
-            next_message_id: MessageId(0),
-            transport,
-            pre_auth_hash: vec![0; 64],
+            reader: tokio::spawn(read_messages(reader, connection.clone())),
+            writer: tokio::spawn(write_messages(
+                writer,
+                outgoing_receiver,
+                connection.clone(),
+            )),
+            connection,
+            outgoing,
+            pre_auth_hash: Mutex::new(None),
+            negotiate_info: OnceLock::new(),
+            _transport: PhantomData,
+        }
+    }
+
+    fn negotiate_info(&self) -> &NegotiateInfo {
+        self.negotiate_info.get().expect("negotiate not done")
+    }
+
+    /// The preauth integrity hash is only maintained for 3.1.1, but we don't know the dialect
+    /// until negotiate is done. It covers negotiate and session setup only.
+    fn update_pre_auth_hash(&self, message: &[u8]) {
+        if let Some(pre_auth_hash) = &mut *self.pre_auth_hash.lock().unwrap() {
+            let mut hasher = sha2::Sha512::new();
+            hasher.update(&*pre_auth_hash);
+            hasher.update(message);
+            *pre_auth_hash = hasher.finalize().to_vec();
+        }
+    }
+
+    /// Takes the hash once session setup is done, which stops it being maintained. Empty unless
+    /// 3.1.1 was negotiated.
+    fn take_pre_auth_hash(&self) -> Vec<u8> {
+        self.pre_auth_hash
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default()
+    }
+
+    /// Responses on this session that arrive encrypted are decrypted with the given keys.
+    fn add_decryption(&self, session_id: SessionId, encryption: Arc<Encryption>) {
+        let mut state = self.connection.state.lock().unwrap();
+        state.decryption.insert(session_id, encryption);
+    }
+
+    /// Reserves the message ids for some requests sent together, waiting for the server to grant
+    /// us more credits if needed. The credit charges are worked out from the credits we have each
+    /// time we try. Returns the charges, and the first message id of each request, how many
+    /// credits it asks for, and where its response will be delivered.
+    async fn acquire_credits(
+        &self,
+        mut credit_charges: impl FnMut(&CreditManager) -> Vec<Credits>,
+    ) -> Result<(Vec<Credits>, Vec<AcquiredCredits>)> {
+        loop {
+            let credits_granted = self.connection.credits_granted.notified();
+            {
+                let mut state = self.connection.state.lock().unwrap();
+                if state.disconnected {
+                    return Err(Error::disconnected());
+                }
+                let mut credit_charges = credit_charges(&state.credits);
+                // 2.0.2 doesn't have multi-credit requests, the field is reserved
+                if let Some(info) = self.negotiate_info.get() {
+                    if info.dialect == Dialect::Smb2_0_2 {
+                        credit_charges.fill(Credits(0));
+                    }
+                }
+                match state.credits.acquire(&credit_charges) {
+                    Ok(message_ids) => {
+                        let mut acquired = vec![];
+                        for (i, (message_id, &credit_charge)) in
+                            message_ids.into_iter().zip(&credit_charges).enumerate()
+                        {
+                            // One request asking for more is enough to keep the window growing
+                            let credits_requested = if i == 0 {
+                                state.credits.credits_to_request(credit_charge)
+                            } else {
+                                Credits(credit_charge.0.max(1))
+                            };
+                            let (sender, receiver) = oneshot::channel();
+                            state.pending.insert(message_id, sender);
+                            acquired.push((message_id, credits_requested, receiver));
+                        }
+                        return Ok((credit_charges, acquired));
+                    }
+                    // The responses to the requests in flight will grant us more, including those
+                    // of requests that were cancelled
+                    Err(Error::InsufficientCredits)
+                        if !state.pending.is_empty() || !state.cancelled.is_empty() => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            credits_granted.await;
+        }
+    }
+
+    /// Gives back the credits of requests that were never sent. Nothing will answer them, so they
+    /// mustn't keep others waiting for credits either.
+    fn release_unsent(&self, acquired: &[AcquiredCredits], credit_charges: &[Credits]) {
+        let mut state = self.connection.state.lock().unwrap();
+        for ((message_id, ..), &credit_charge) in acquired.iter().zip(credit_charges) {
+            state.pending.remove(message_id);
+            state.credits.release(*message_id, credit_charge);
         }
+        drop(state);
+        self.connection.credits_granted.notify_waiters();
     }
 
     #[allow(clippy::too_many_arguments)]
     async fn request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
-        &mut self,
+        &self,
         credit_charge: Credits,
-        credits_requested: Credits,
         session_id: Option<SessionId>,
         signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
         tree_id: Option<TreeId>,
         request: T,
     ) -> Result<(ResponseHeader, R)> {
-        let command = T::command();
-        let header = RequestHeader {
-            protocol_id: ProtocolId::new(),
-            header_length: 64,
-            credit_charge,
-            channel_sequence: 0,
-            command,
-            credits_requested,
-            flags: HeaderFlags::new().with_signing(signature_func.is_some()),
-            chain_offset: 0,
-            message_id: self.next_message_id,
-            process_id: ProcessId(0),
-            tree_id: tree_id.unwrap_or(TreeId(0)),
-            session_id: session_id.unwrap_or(SessionId(0)),
-            signature: Signature([0; 16]),
-        };
-        self.next_message_id = MessageId(self.next_message_id.0 + 1);
+        let (header, response, _) = self
+            .request_with_bytes(
+                credit_charge,
+                session_id,
+                signature_func,
+                encryption,
+                tree_id,
+                request,
+            )
+            .await?;
+        Ok((header, response))
+    }
 
-        let mut req_bytes = serde_smb::to_vec(&(header, request))?;
+    /// Like `request`, but also returns the bytes of the response message.
+    #[allow(clippy::too_many_arguments)]
+    async fn request_with_bytes<
+        T: serde::Serialize + HasCommand,
+        R: serde::de::DeserializeOwned,
+    >(
+        &self,
+        credit_charge: Credits,
+        session_id: Option<SessionId>,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+        tree_id: Option<TreeId>,
+        request: T,
+    ) -> Result<(ResponseHeader, R, Vec<u8>)> {
+        let request = OutgoingRequest::new(credit_charge, tree_id, false, request)?;
+        let mut responses = self
+            .exchange(vec![request], session_id, signature_func, encryption)
+            .await?;
+        let (response_header, response_bytes) = responses.remove(0)?;
+        let response_body = response_body(&response_header, &response_bytes)?;
+        Ok((response_header, response_body, response_bytes))
+    }
 
-        if let Some(func) = signature_func {
-            let sig = func(&req_bytes[..])?;
-            req_bytes[48..64].clone_from_slice(&sig.0[..]);
-        } else {
-            let mut hasher = sha2::Sha512::new();
-            hasher.update(&self.pre_auth_hash);
-            hasher.update(&req_bytes);
-            self.pre_auth_hash = hasher.finalize().to_vec();
-        }
+    /// Like `request`, for reads and writes that are as large as the credits we have allow, up to
+    /// `max_size`. The request is built once its credits are acquired, as other requests can use
+    /// them up in the meantime.
+    #[allow(clippy::too_many_arguments)]
+    async fn sized_request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
+        &self,
+        max_size: u32,
+        session_id: Option<SessionId>,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+        tree_id: Option<TreeId>,
+        replay: bool,
+        build: impl FnOnce(u32) -> T,
+    ) -> Result<(ResponseHeader, R)> {
+        let mut size = max_size;
+        let (credit_charges, acquired) = self
+            .acquire_credits(|credits| {
+                size = max_size.min(credits.max_payload_size());
+                vec![credit_charge(size)]
+            })
+            .await?;
+        let request = match OutgoingRequest::new(credit_charge(size), tree_id, false, build(size)) {
+            Ok(request) => OutgoingRequest { replay, ..request },
+            Err(error) => {
+                self.release_unsent(&acquired, &credit_charges);
+                return Err(error);
+            }
+        };
+        let mut responses = self
+            .exchange_acquired(
+                vec![request],
+                credit_charges,
+                acquired,
+                session_id,
+                signature_func,
+                encryption,
+            )
+            .await?;
+        let (response_header, response_bytes) = responses.remove(0)?;
+        let response_body = response_body(&response_header, &response_bytes)?;
+        Ok((response_header, response_body))
+    }
+
+    /// Sends the requests in a single frame, as a compound if there are several, and waits for all
+    /// of their responses. Each response is checked on its own.
+    async fn exchange(
+        &self,
+        requests: Vec<OutgoingRequest>,
+        session_id: Option<SessionId>,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+    ) -> Result<Vec<Result<(ResponseHeader, Vec<u8>)>>> {
+        let (credit_charges, acquired) = self
+            .acquire_credits(|_| {
+                requests
+                    .iter()
+                    .map(|request| request.credit_charge)
+                    .collect()
+            })
+            .await?;
+        self.exchange_acquired(
+            requests,
+            credit_charges,
+            acquired,
+            session_id,
+            signature_func,
+            encryption,
+        )
+        .await
+    }
+
+    /// Sends requests whose credits were acquired, and waits for their responses.
+    async fn exchange_acquired(
+        &self,
+        requests: Vec<OutgoingRequest>,
+        credit_charges: Vec<Credits>,
+        acquired: Vec<AcquiredCredits>,
+        session_id: Option<SessionId>,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+    ) -> Result<Vec<Result<(ResponseHeader, Vec<u8>)>>> {
+        let session_id = session_id.unwrap_or(SessionId(0));
+        let sent = match self.send_acquired(
+            requests,
+            &acquired,
+            &credit_charges,
+            session_id,
+            signature_func,
+            encryption,
+        ) {
+            Ok(sent) => sent,
+            Err(error) => {
+                self.release_unsent(&acquired, &credit_charges);
+                return Err(error);
+            }
+        };
+        let receivers = acquired.into_iter().map(|(_, _, receiver)| receiver);
+
+        // If we stop waiting, the server can stop working on them
+        let mut cancel_on_drop = CancelOnDrop {
+            client: self,
+            requests: sent,
+            session_id,
+            signature_func,
+            encryption,
+        };
+        let mut responses = vec![];
+        for receiver in receivers {
+            let received = receiver.await.map_err(|_| Error::disconnected())?;
+            responses.push(received.and_then(|received| {
+                self.check_response(received, signature_func, encryption.is_some())
+            }));
+        }
+        cancel_on_drop.requests.clear();
+
+        Ok(responses)
+    }
+
+    /// Fills in the headers of requests whose credits were acquired, and sends them. Returns the
+    /// message and tree id of each.
+    fn send_acquired(
+        &self,
+        requests: Vec<OutgoingRequest>,
+        acquired: &[AcquiredCredits],
+        credit_charges: &[Credits],
+        session_id: SessionId,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encryption: Option<&Encryption>,
+    ) -> Result<Vec<(MessageId, TreeId)>> {
+        let count = requests.len();
+        let mut messages = vec![];
+        let mut sent = vec![];
+        for (i, (request, &(message_id, credits_requested, _))) in
+            requests.into_iter().zip(acquired).enumerate()
+        {
+            let tree_id = request.tree_id.unwrap_or(TreeId(0));
+            let mut header: RequestHeader = serde_smb::from_slice(&request.bytes)?;
+            header.credit_charge = credit_charges[i];
+            header.credits_requested = credits_requested;
+            header.message_id = message_id;
+            header.session_id = session_id;
+            header.tree_id = tree_id;
+            header.flags = HeaderFlags::new()
+                .with_signing(signature_func.is_some() && encryption.is_none())
+                .with_chained(request.related)
+                .with_replay(request.replay);
+
+            // Each message in a compound starts 8 byte aligned
+            let mut bytes = request.bytes;
+            if i + 1 < count {
+                bytes.resize(bytes.len().next_multiple_of(8), 0);
+                header.chain_offset = bytes.len() as u32;
+            }
+            bytes[..HEADER_SIZE].copy_from_slice(&serde_smb::to_vec(&header)?);
+
+            if matches!(header.command, Command::Negotiate | Command::SessionSetup) {
+                self.update_pre_auth_hash(&bytes);
+            }
+            messages.push(bytes);
+            sent.push((message_id, tree_id));
+        }
+        self.send(messages, session_id, signature_func, encryption)?;
+        Ok(sent)
+    }
+
+    /// Makes sure a response is authentic, and keeps the preauth integrity hash up to date.
+    /// Responses to encrypted requests have to be encrypted too.
+    fn check_response(
+        &self,
+        received: ReceivedMessage,
+        signature_func: Option<SignatureFuncRef<'_>>,
+        encrypted_request: bool,
+    ) -> Result<(ResponseHeader, Vec<u8>)> {
+        let ReceivedMessage {
+            bytes: response_bytes,
+            encrypted,
+        } = received;
+        if encrypted_request && !encrypted {
+            return Err(Error::DecryptionFailed);
+        }
+        let response_header: ResponseHeader = serde_smb::from_slice(&response_bytes)?;
+
+        // Encrypted responses are already authenticated
+        if let Some(func) = signature_func {
+            if !encrypted {
+                if response_header.flags.signing() {
+                    verify_signature(func, &response_bytes)?;
+                } else if self.negotiate_info().signing_required() {
+                    return Err(Error::InvalidSignature);
+                }
+            }
+        }
+
+        // The final session setup response isn't included, as it is signed with keys derived
+        // from the hash
+        let pre_auth = match response_header.command {
+            Command::Negotiate => true,
+            Command::SessionSetup => response_header.nt_status == NtStatus::MoreProcessingRequired,
+            _ => false,
+        };
+        if pre_auth {
+            self.update_pre_auth_hash(&response_bytes);
+        }
+        Ok((response_header, response_bytes))
+    }
+
+    async fn negotiate(&self, dialects: &[Dialect], client_guid: &Uuid) -> Result<()> {
+        let mut rng = OsRng;
+        let pre_auth_salt = rng.gen::<[u8; 32]>().to_vec();
+
+        let ciphers = vec![
+            CipherId::Aes128Gcm,
+            CipherId::Aes128Ccm,
+            CipherId::Aes256Gcm,
+            CipherId::Aes256Ccm,
+        ];
+        let signing_algorithms = vec![SigningAlgorithmId::AesGmac, SigningAlgorithmId::AesCmac];
+
+        // Negotiate contexts and the preauth integrity hash are only understood by 3.1.1
+        let mut negotiate_contexts = vec![];
+        if dialects.contains(&Dialect::Smb3_1_1) {
+            *self.pre_auth_hash.lock().unwrap() = Some(vec![0; 64]);
+            negotiate_contexts = vec![
+                NegotiateContext::Smb2PreauthIntegrityCapabilities(
+                    Smb2PreauthIntegrityCapabilities {
+                        data_length: 38,
+                        hash_algorithms: vec![HashAlgorithm::Sha512],
+                        salt: pre_auth_salt,
+                    },
+                ),
+                NegotiateContext::Smb2EncryptionCapabilities(Smb2EncryptionCapabilities {
+                    data_length: (2 + ciphers.len() * 2) as u16,
+                    ciphers,
+                }),
+                NegotiateContext::Smb2SigningCapabilities(Smb2SigningCapabilities {
+                    data_length: (2 + signing_algorithms.len() * 2) as u16,
+                    signing_algorithms,
+                }),
+            ];
+        }
+
+        let request = NegotiateRequest {
+            security_mode: SecurityMode::SIGNING_ENABLED,
+            capabilities: Capabilities::ENCRYPTION
+                | Capabilities::LARGE_MTU
+                | Capabilities::LEASING
+                | Capabilities::PERSISTENT_HANDLES,
+            client_guid: client_guid.clone(),
+            dialects: dialects.to_owned(),
+            negotiate_contexts,
+        };
+
+        let (_, response): (_, NegotiateResponse) = self
+            .request(Credits(0), None, None, None, None, request)
+            .await?;
+        if response.dialect != Dialect::Smb3_1_1 {
+            *self.pre_auth_hash.lock().unwrap() = None;
+        }
+
+        let cipher = match response.dialect {
+            Dialect::Smb2_0_2 | Dialect::Smb2_1 => None,
+            Dialect::Smb3_0 | Dialect::Smb3_0_2 => response
+                .capabilities
+                .contains(Capabilities::ENCRYPTION)
+                .then_some(CipherId::Aes128Ccm),
+            // The server picks at most one cipher, or none if we have nothing in common
+            Dialect::Smb3_1_1 => response.negotiate_contexts.iter().find_map(|c| match c {
+                NegotiateContext::Smb2EncryptionCapabilities(e) => e.ciphers.first().copied(),
+                _ => None,
+            }),
+        };
+
+        // Without a signing capabilities context the algorithm is implied by the dialect
+        let signing_algorithm = match response.dialect {
+            Dialect::Smb2_0_2 | Dialect::Smb2_1 => SigningAlgorithmId::HmacSha256,
+            _ => response
+                .negotiate_contexts
+                .iter()
+                .find_map(|c| match c {
+                    NegotiateContext::Smb2SigningCapabilities(s) => {
+                        s.signing_algorithms.first().copied()
+                    }
+                    _ => None,
+                })
+                .unwrap_or(SigningAlgorithmId::AesCmac),
+        };
+
+        let negotiate_info = NegotiateInfo {
+            dialect: response.dialect,
+            security_mode: response.security_mode,
+            capabilities: response.capabilities,
+            server_guid: response.server_guid,
+            max_transaction_size: response.max_transaction_size,
+            max_read_size: response.max_read_size,
+            max_write_size: response.max_write_size,
+            cipher,
+            signing_algorithm,
+            security_blob: response.security_blob,
+        };
+        self.negotiate_info
+            .set(negotiate_info)
+            .expect("negotiate done twice");
+
+        Ok(())
+    }
+}
+
+/// A session set up on a connection. Reconnecting sets up a new one for the same user, which
+/// takes its place.
+struct SessionChannel<TransportT> {
+    unauth_client: UnauthenticatedClient<TransportT>,
+    session_id: SessionId,
+    session_flags: SessionFlags,
+    /// Sessions without a session key can't be signed.
+    signing: Option<Signing>,
+    encryption: Option<Arc<Encryption>>,
+    encrypt_session: bool,
+}
+
+impl<TransportT: Transport> SessionChannel<TransportT> {
+    /// Negotiates and sets up a session, which replaces the previous session if there was one.
+    async fn new(
+        transport: TransportT,
+        mut authenticator: impl Authenticator,
+        dialects: &[Dialect],
+        client_guid: &Uuid,
+        previous_session_id: SessionId,
+    ) -> Result<Self> {
+        let unauth_client = UnauthenticatedClient::new(transport);
+
+        unauth_client.negotiate(dialects, client_guid).await?;
+
+        let mut request = SessionSetupRequest {
+            session_binding_request: false,
+            security_mode: SecurityMode::SIGNING_ENABLED,
+            capabilities: Capabilities::empty(),
+            channel: 0,
+            previous_session_id,
+            security_blob: authenticator.step(&unauth_client.negotiate_info().security_blob)?,
+        };
+
+        let (mut resp_header, mut response, mut resp_bytes): (_, SessionSetupResponse, _) =
+            unauth_client
+                .request_with_bytes(Credits(0), None, None, None, None, request.clone())
+                .await?;
+
+        let session_id = resp_header.session_id;
+
+        while resp_header.nt_status == NtStatus::MoreProcessingRequired {
+            request.security_blob = authenticator.step(&response.security_blob)?;
+
+            (resp_header, response, resp_bytes) = unauth_client
+                .request_with_bytes(
+                    Credits(0),
+                    Some(session_id),
+                    None,
+                    None,
+                    None,
+                    request.clone(),
+                )
+                .await?;
+        }
+        authenticator.finish(&response.security_blob)?;
+
+        let negotiate_info = unauth_client.negotiate_info();
+        let pre_auth_hash = unauth_client.take_pre_auth_hash();
+        let session_flags = response.flags;
+        let mut signing = None;
+        let mut encryption = None;
+
+        // The server doesn't have a key for guest and null sessions even if we have one, so they
+        // can't be signed or encrypted
+        let session_key = if session_flags.intersects(SessionFlags::GUEST | SessionFlags::NULL) {
+            None
+        } else {
+            authenticator.session_key()?
+        };
+        if let Some(session_key) = session_key {
+            // Signing and the 128 bit ciphers use the first 16 bytes of the session key, padded
+            // if it is shorter, and the 256 bit ciphers use all of it
+            let mut short_session_key = session_key.clone();
+            short_session_key.resize(16, 0);
+            let session_signing = Signing::new(
+                negotiate_info.dialect,
+                negotiate_info.signing_algorithm,
+                &short_session_key,
+                &pre_auth_hash,
+            );
+
+            // The final response is signed with the key derived from everything before it
+            if resp_header.flags.signing() {
+                verify_signature(&|bytes: &[u8]| Ok(session_signing.sign(bytes)), &resp_bytes)?;
+            } else if negotiate_info.signing_required() {
+                return Err(Error::InvalidSignature);
+            }
+            signing = Some(session_signing);
+
+            encryption = negotiate_info.cipher.map(|cipher| {
+                let session_key = match cipher {
+                    CipherId::Aes128Ccm | CipherId::Aes128Gcm => &short_session_key,
+                    CipherId::Aes256Ccm | CipherId::Aes256Gcm => &session_key,
+                };
+                Arc::new(Encryption::new(
+                    negotiate_info.dialect,
+                    cipher,
+                    session_key,
+                    &pre_auth_hash,
+                ))
+            });
+        }
+
+        let encrypt_session = session_flags.contains(SessionFlags::ENCRYPT);
+        if encrypt_session && encryption.is_none() {
+            return Err(Error::EncryptionNotSupported);
+        }
+        if let Some(encryption) = &encryption {
+            unauth_client.add_decryption(session_id, encryption.clone());
+        }
+
+        Ok(Self {
+            unauth_client,
+            session_id,
+            session_flags,
+            signing,
+            encryption,
+            encrypt_session,
+        })
+    }
+
+    fn signature_func(&self) -> Option<impl Fn(&[u8]) -> Result<Signature> + Sync + '_> {
+        self.signing
+            .as_ref()
+            .map(|signing| |bytes: &[u8]| Ok(signing.sign(bytes)))
+    }
+
+    async fn request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
+        &self,
+        tree_id: Option<TreeId>,
+        encrypt: bool,
+        credit_charge: Credits,
+        request: T,
+    ) -> Result<(ResponseHeader, R)> {
+        let sig_func = self.signature_func();
+        self.unauth_client
+            .request(
+                credit_charge,
+                Some(self.session_id),
+                sig_func
+                    .as_ref()
+                    .map(|sig_func| sig_func as SignatureFuncRef<'_>),
+                self.encryption.as_deref().filter(|_| encrypt),
+                tree_id,
+                request,
+            )
+            .await
+    }
+
+    async fn sized_request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
+        &self,
+        tree_id: Option<TreeId>,
+        encrypt: bool,
+        max_size: u32,
+        replay: bool,
+        build: impl FnOnce(u32) -> T,
+    ) -> Result<(ResponseHeader, R)> {
+        let sig_func = self.signature_func();
+        self.unauth_client
+            .sized_request(
+                max_size,
+                Some(self.session_id),
+                sig_func
+                    .as_ref()
+                    .map(|sig_func| sig_func as SignatureFuncRef<'_>),
+                self.encryption.as_deref().filter(|_| encrypt),
+                tree_id,
+                replay,
+                build,
+            )
+            .await
+    }
+
+    async fn compound(
+        &self,
+        requests: Vec<OutgoingRequest>,
+        encrypt: bool,
+    ) -> Result<Vec<Result<(ResponseHeader, Vec<u8>)>>> {
+        let sig_func = self.signature_func();
+        self.unauth_client
+            .exchange(
+                requests,
+                Some(self.session_id),
+                sig_func
+                    .as_ref()
+                    .map(|sig_func| sig_func as SignatureFuncRef<'_>),
+                self.encryption.as_deref().filter(|_| encrypt),
+            )
+            .await
+    }
+
+    /// Connects to a share. Shares that encrypt data can only be used if the session can be
+    /// encrypted.
+    async fn tree_connect(&self, path: &str) -> Result<(TreeId, ShareInfo)> {
+        let (header, response): (_, TreeConnectResponse) = self
+            .request(
+                None,
+                self.encrypt_session,
+                Credits(1),
+                TreeConnectRequest {
+                    flags: TreeConnectFlags::empty(),
+                    path: path.into(),
+                },
+            )
+            .await?;
+        let info = ShareInfo::from(response);
+        if info.encrypts_data() && self.encryption.is_none() {
+            return Err(Error::EncryptionNotSupported);
+        }
+        Ok((header.tree_id, info))
+    }
+}
+
+/// A session, and the trees and handles on it. When reconnecting it is set up again on the new
+/// connection, and the trees and durable handles with it. They keep the ids they were first given,
+/// which are translated to the ones they have on the new connection.
+struct AuthenticatedClient<TransportT> {
+    channel: RwLock<Arc<SessionChannel<TransportT>>>,
+    negotiate_info: NegotiateInfo,
+    client_guid: Uuid,
+    /// The trees connected, and the paths to connect them again with.
+    trees: Mutex<Vec<(TreeId, String)>>,
+    /// The ids of the trees on the current connection, where they differ.
+    tree_ids: Mutex<HashMap<TreeId, TreeId>>,
+    encrypted_trees: Mutex<Vec<TreeId>>,
+    /// Handles that are still open, so they can be closed when shutting down and the durable ones
+    /// reopened when reconnecting.
+    open_files: Mutex<Vec<OpenFile>>,
+}
+
+struct OpenFile {
+    tree_id: TreeId,
+    file_id: FileId,
+    /// For a durable handle, the create request it was opened with.
+    durable: Option<CreateRequest>,
+}
+
+impl<TransportT: Transport> AuthenticatedClient<TransportT> {
+    async fn new(
+        transport: TransportT,
+        authenticator: impl Authenticator,
+        options: &ClientOptions,
+    ) -> Result<Self> {
+        // Durable handles can only be reconnected by the client that opened them
+        let client_guid = Uuid::new(&mut OsRng);
+        let channel = SessionChannel::new(
+            transport,
+            authenticator,
+            &options.dialects,
+            &client_guid,
+            SessionId(0),
+        )
+        .await?;
+        Ok(Self {
+            negotiate_info: channel.unauth_client.negotiate_info().clone(),
+            channel: RwLock::new(Arc::new(channel)),
+            client_guid,
+            trees: Mutex::new(vec![]),
+            tree_ids: Mutex::new(HashMap::new()),
+            encrypted_trees: Mutex::new(vec![]),
+            open_files: Mutex::new(vec![]),
+        })
+    }
+
+    fn channel(&self) -> Arc<SessionChannel<TransportT>> {
+        self.channel.read().unwrap().clone()
+    }
+
+    fn is_connected(&self) -> bool {
+        let channel = self.channel();
+        let state = channel.unauth_client.connection.state.lock().unwrap();
+        !state.disconnected
+    }
+
+    /// The id the tree has on the current connection.
+    fn current_tree_id(&self, tree_id: TreeId) -> TreeId {
+        self.tree_ids
+            .lock()
+            .unwrap()
+            .get(&tree_id)
+            .copied()
+            .unwrap_or(tree_id)
+    }
+
+    async fn request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
+        &self,
+        tree_id: Option<TreeId>,
+        credit_charge: Credits,
+        request: T,
+    ) -> Result<(ResponseHeader, R)> {
+        let channel = self.channel();
+        let encrypt = channel.encrypt_session
+            || tree_id.is_some_and(|tree_id| self.is_encrypted_tree(tree_id));
+        let tree_id = tree_id.map(|tree_id| self.current_tree_id(tree_id));
+        channel
+            .request(tree_id, encrypt, credit_charge, request)
+            .await
+    }
+
+    /// Sends a read or write as large as the credits we have allow, up to `max_size`.
+    async fn sized_request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
+        &self,
+        tree_id: TreeId,
+        max_size: u32,
+        replay: bool,
+        build: impl FnOnce(u32) -> T,
+    ) -> Result<(ResponseHeader, R)> {
+        let channel = self.channel();
+        let encrypt = channel.encrypt_session || self.is_encrypted_tree(tree_id);
+        let tree_id = self.current_tree_id(tree_id);
+        channel
+            .sized_request(Some(tree_id), encrypt, max_size, replay, build)
+            .await
+    }
+
+    /// Sends the requests as a compound. It is encrypted if any of them needs to be.
+    async fn compound(
+        &self,
+        mut requests: Vec<OutgoingRequest>,
+    ) -> Result<Vec<Result<(ResponseHeader, Vec<u8>)>>> {
+        let channel = self.channel();
+        let encrypt = channel.encrypt_session
+            || requests.iter().any(|request| {
+                request
+                    .tree_id
+                    .is_some_and(|tree_id| self.is_encrypted_tree(tree_id))
+            });
+        for request in &mut requests {
+            request.tree_id = request.tree_id.map(|tree_id| self.current_tree_id(tree_id));
+        }
+        channel.compound(requests, encrypt).await
+    }
+
+    fn is_encrypted_tree(&self, tree_id: TreeId) -> bool {
+        self.encrypted_trees.lock().unwrap().contains(&tree_id)
+    }
+
+    async fn tree_connect(&self, path: &str) -> Result<Tree> {
+        let (current_id, info) = self.channel().tree_connect(path).await?;
+
+        // After reconnecting, the id may already be taken by a tree that was connected before
+        let mut trees = self.trees.lock().unwrap();
+        let mut tree_id = current_id;
+        while trees.iter().any(|&(id, _)| id == tree_id) {
+            tree_id = TreeId(tree_id.0.wrapping_add(1));
+        }
+        if tree_id != current_id {
+            self.tree_ids.lock().unwrap().insert(tree_id, current_id);
+        }
+        if info.encrypts_data() {
+            self.encrypted_trees.lock().unwrap().push(tree_id);
+        }
+        trees.push((tree_id, path.into()));
+
+        Ok(Tree { id: tree_id, info })
+    }
+
+    /// Disconnects the tree. The server closes whatever handles are still open on it.
+    async fn tree_disconnect(&self, tree_id: TreeId) -> Result<()> {
+        // If it fails the tree is still connected, and set up again after reconnecting
+        let (_, _response): (_, TreeDisconnectResponse) = self
+            .request(Some(tree_id), Credits(1), TreeDisconnectRequest)
+            .await?;
+        self.trees.lock().unwrap().retain(|&(id, _)| id != tree_id);
+        self.open_files
+            .lock()
+            .unwrap()
+            .retain(|open| open.tree_id != tree_id);
+        self.encrypted_trees
+            .lock()
+            .unwrap()
+            .retain(|&id| id != tree_id);
+        self.tree_ids.lock().unwrap().remove(&tree_id);
+        Ok(())
+    }
+
+    fn track_open(&self, tree_id: TreeId, file_id: FileId) {
+        self.open_files.lock().unwrap().push(OpenFile {
+            tree_id,
+            file_id,
+            durable: None,
+        });
+    }
+
+    fn track_durable_open(&self, tree_id: TreeId, file_id: FileId, request: CreateRequest) {
+        self.open_files.lock().unwrap().push(OpenFile {
+            tree_id,
+            file_id,
+            durable: Some(request),
+        });
+    }
+
+    fn is_durable(&self, file_id: FileId) -> bool {
+        self.open_files
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|open| open.file_id == file_id && open.durable.is_some())
+    }
+
+    fn track_close(&self, file_id: FileId) {
+        self.open_files
+            .lock()
+            .unwrap()
+            .retain(|open| open.file_id != file_id);
+    }
+
+    /// Sets the session up again on a new transport, after the connection was lost. The trees
+    /// are connected again and the durable handles reopened, so the clients on the session and
+    /// the handles keep working. Other handles were closed by the server.
+    ///
+    /// Handles that fail to reopen are forgotten, and the first error is returned once the others
+    /// have been tried.
+    async fn reconnect(
+        &self,
+        transport: TransportT,
+        authenticator: impl Authenticator,
+    ) -> Result<()> {
+        let previous = self.channel();
+        let channel = SessionChannel::new(
+            transport,
+            authenticator,
+            &[self.negotiate_info.dialect],
+            &self.client_guid,
+            previous.session_id,
+        )
+        .await?;
+
+        let trees = self.trees.lock().unwrap().clone();
+        let mut tree_ids = HashMap::new();
+        for (tree_id, path) in trees {
+            let (current_id, _) = channel.tree_connect(&path).await?;
+            if current_id != tree_id {
+                tree_ids.insert(tree_id, current_id);
+            }
+        }
+
+        // Break notifications for the handles are delivered by the new connection from here
+        channel
+            .unauth_client
+            .connection
+            .take_breaks(&previous.unauth_client.connection);
+        *self.tree_ids.lock().unwrap() = tree_ids;
+        *self.channel.write().unwrap() = Arc::new(channel);
+
+        let mut result = Ok(());
+        let open_files = std::mem::take(&mut *self.open_files.lock().unwrap());
+        for open in open_files {
+            let Some(request) = &open.durable else {
+                continue;
+            };
+            match self
+                .reopen(open.tree_id, open.file_id, request.clone())
+                .await
+            {
+                Ok(()) => self.open_files.lock().unwrap().push(open),
+                Err(error) => result = result.and(Err(error)),
+            }
+        }
+        result
+    }
+
+    /// Reconnects a durable handle. It is sent as a replay of the create that opened it.
+    async fn reopen(
+        &self,
+        tree_id: TreeId,
+        file_id: FileId,
+        mut request: CreateRequest,
+    ) -> Result<()> {
+        for context in &mut request.create_contexts {
+            if let CreateContext::DurableHandleRequestV2(durable) = &context.body {
+                context.body = CreateContext::DurableHandleReconnectV2(DurableHandleReconnectV2 {
+                    file_id,
+                    create_guid: durable.create_guid.clone(),
+                    flags: durable.flags,
+                });
+            }
+        }
+        let request = OutgoingRequest::new(Credits(1), Some(tree_id), false, request)?.replay();
+        let (header, bytes) = self.compound(vec![request]).await?.remove(0)?;
+        let response: CreateResponse = response_body(&header, &bytes)?;
+
+        // The lease may have been broken while we were gone
+        if let Some(lease) = response.lease()? {
+            self.channel().unauth_client.connection.deliver_break(
+                BreakKey::Lease(lease.lease_key),
+                Break::Lease(LeaseBreakNotification {
+                    new_epoch: lease.epoch,
+                    flags: LeaseBreakFlags::empty(),
+                    lease_key: lease.lease_key,
+                    current_lease_state: lease.lease_state,
+                    new_lease_state: lease.lease_state,
+                }),
+            );
+        }
+        Ok(())
+    }
+
+    /// Closes the handles still open, disconnects the trees and logs off, then shuts down the
+    /// transport. It keeps going if any of that fails, and returns the first error.
+    async fn shutdown(&self) -> Result<()> {
+        let mut result = Ok(());
+        let open_files = std::mem::take(&mut *self.open_files.lock().unwrap());
+        for OpenFile {
+            tree_id, file_id, ..
+        } in open_files
+        {
+            let response: Result<(_, CloseResponse)> = self
+                .request(
+                    Some(tree_id),
+                    Credits(1),
+                    CloseRequest {
+                        flags: CloseFlags::empty(),
+                        file_id,
+                    },
+                )
+                .await;
+            result = result.and(response.map(drop));
+        }
+        let trees = std::mem::take(&mut *self.trees.lock().unwrap());
+        for (tree_id, _) in trees {
+            let response: Result<(_, TreeDisconnectResponse)> = self
+                .request(Some(tree_id), Credits(1), TreeDisconnectRequest)
+                .await;
+            result = result.and(response.map(drop));
+        }
+        let response: Result<(_, LogoffResponse)> =
+            self.request(None, Credits(1), LogoffRequest).await;
+        result = result.and(response.map(drop));
+
+        result.and(self.channel().unauth_client.shutdown().await)
+    }
+}
+
+/// Sends whatever request cleans up after something that was dropped, without waiting for it. If
+/// it fails the handle or the connection is already gone, which cleans up just the same, and
+/// without a runtime there is no connection left either.
+fn spawn_cleanup<T: Send + 'static>(request: impl Future<Output = Result<T>> + Send + 'static) {
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn(async move {
+            let _ = request.await;
+        });
+    }
+}
+
+fn credit_charge(payload_size: u32) -> Credits {
+    Credits((1 + payload_size.saturating_sub(1) / CREDIT_PAYLOAD_SIZE) as u16)
+}
+
+/// Parses the body of a response, unless the server responded with an error.
+fn response_body<R: serde::de::DeserializeOwned>(
+    header: &ResponseHeader,
+    bytes: &[u8],
+) -> Result<R> {
+    if header.nt_status == NtStatus::Success || header.nt_status == NtStatus::MoreProcessingRequired
+    {
+        let (_, body): (ResponseHeader, R) = serde_smb::from_slice(bytes)?;
+        Ok(body)
+    } else {
+        Err(Error::NtStatus(header.nt_status))
+    }
+}
+
+fn verify_signature(signature_func: SignatureFuncRef<'_>, message: &[u8]) -> Result<()> {
+    let mut unsigned = message.to_owned();
+    unsigned[48..64].fill(0);
+    if signature_func(&unsigned)?.0[..] == message[48..64] {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+fn sp800_108_counter_kdf(key_len: usize, secret: &[u8], label: &[u8], salt: &[u8]) -> Vec<u8> {
+    let length: u32 = (key_len * 8).try_into().unwrap();
+
+    let mut p = vec![];
+    let mut counter: u32 = 1;
+
+    while p.len() < key_len {
+        let mut hmac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).unwrap();
+        hmac.update(&counter.to_be_bytes());
+        hmac.update(label);
+        hmac.update(&[0u8]);
+        hmac.update(salt);
+        hmac.update(&length.to_be_bytes());
+        p.extend(hmac.finalize().into_bytes());
+        counter += 1;
+    }
+
+    p.resize(key_len, 0);
+
+    p
+}
+
+struct Signing {
+    algorithm: SigningAlgorithmId,
+    key: Vec<u8>,
+}
+
+impl Signing {
+    fn new(
+        dialect: Dialect,
+        algorithm: SigningAlgorithmId,
+        session_key: &[u8],
+        pre_auth_hash: &[u8],
+    ) -> Self {
+        let key = match (algorithm, dialect) {
+            (SigningAlgorithmId::HmacSha256, _) => session_key.to_owned(),
+            (_, Dialect::Smb3_1_1) => {
+                sp800_108_counter_kdf(16, session_key, b"SMBSigningKey\0", pre_auth_hash)
+            }
+            _ => sp800_108_counter_kdf(16, session_key, b"SMB2AESCMAC\0", b"SmbSign\0"),
+        };
+        Self { algorithm, key }
+    }
+
+    /// Expects the signature field of the given message to be zeroed.
+    fn sign(&self, message: &[u8]) -> Signature {
+        match self.algorithm {
+            SigningAlgorithmId::HmacSha256 => {
+                let mut hmac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.key).unwrap();
+                hmac.update(message);
+                let mut signature = Signature([0; 16]);
+                signature
+                    .0
+                    .copy_from_slice(&hmac.finalize().into_bytes()[..16]);
+                signature
+            }
+            SigningAlgorithmId::AesCmac => {
+                let mut mac = cmac::Cmac::<aes::Aes128>::new_from_slice(&self.key).unwrap();
+                mac.update(message);
+                Signature(mac.finalize().into_bytes().into())
+            }
+            SigningAlgorithmId::AesGmac => {
+                // The nonce is the message id followed by a bit for whether the message is a
+                // response, and a bit for whether it is a cancel request.
+                let mut nonce = [0; 12];
+                nonce[..8].copy_from_slice(&message[24..32]);
+                let response =
+                    HeaderFlags::from_bytes(message[16..20].try_into().unwrap()).response();
+                let cancel = message[12..14] == (Command::Cancel as u16).to_le_bytes();
+                nonce[8] = response as u8 | (cancel as u8) << 1;
+                seal::<aes_gcm::Aes128Gcm>(&self.key, &nonce, message, &mut [])
+            }
+        }
+    }
+}
+
+struct Encryption {
+    cipher: CipherId,
+    encryption_key: Vec<u8>,
+    decryption_key: Vec<u8>,
+}
+
+impl Encryption {
+    fn new(dialect: Dialect, cipher: CipherId, session_key: &[u8], pre_auth_hash: &[u8]) -> Self {
+        let key_len = match cipher {
+            CipherId::Aes128Ccm | CipherId::Aes128Gcm => 16,
+            CipherId::Aes256Ccm | CipherId::Aes256Gcm => 32,
+        };
+        let (encryption_label, encryption_context, decryption_label, decryption_context): (
+            &[u8],
+            &[u8],
+            &[u8],
+            &[u8],
+        ) = if dialect == Dialect::Smb3_1_1 {
+            (
+                b"SMBC2SCipherKey\0",
+                pre_auth_hash,
+                b"SMBS2CCipherKey\0",
+                pre_auth_hash,
+            )
+        } else {
+            (
+                b"SMB2AESCCM\0",
+                b"ServerIn \0",
+                b"SMB2AESCCM\0",
+                b"ServerOut\0",
+            )
+        };
+        Self {
+            cipher,
+            encryption_key: sp800_108_counter_kdf(
+                key_len,
+                session_key,
+                encryption_label,
+                encryption_context,
+            ),
+            decryption_key: sp800_108_counter_kdf(
+                key_len,
+                session_key,
+                decryption_label,
+                decryption_context,
+            ),
+        }
+    }
+
+    fn encrypt(&self, session_id: SessionId, mut message: Vec<u8>) -> Result<Vec<u8>> {
+        let mut nonce = Nonce([0; 16]);
+        let nonce_len = match self.cipher {
+            CipherId::Aes128Ccm | CipherId::Aes256Ccm => 11,
+            CipherId::Aes128Gcm | CipherId::Aes256Gcm => 12,
+        };
+        OsRng.fill(&mut nonce.0[..nonce_len]);
+
+        let mut header = TransformHeader {
+            protocol_id: ProtocolId::new_transform(),
+            signature: Signature([0; 16]),
+            nonce,
+            original_message_size: message.len().try_into().unwrap(),
+            flags: TransformFlags::ENCRYPTED,
+            session_id,
+        };
+        let header_bytes = serde_smb::to_vec(&header)?;
+        let (key, nonce, aad) = (
+            &self.encryption_key[..],
+            &header.nonce.0,
+            &header_bytes[20..],
+        );
+        header.signature = match self.cipher {
+            CipherId::Aes128Ccm => {
+                seal::<ccm::Ccm<aes::Aes128, U16, U11>>(key, nonce, aad, &mut message)
+            }
+            CipherId::Aes128Gcm => seal::<aes_gcm::Aes128Gcm>(key, nonce, aad, &mut message),
+            CipherId::Aes256Ccm => {
+                seal::<ccm::Ccm<aes::Aes256, U16, U11>>(key, nonce, aad, &mut message)
+            }
+            CipherId::Aes256Gcm => seal::<aes_gcm::Aes256Gcm>(key, nonce, aad, &mut message),
+        };
+
+        let mut transformed = serde_smb::to_vec(&header)?;
+        transformed.extend(message);
+        Ok(transformed)
+    }
+
+    fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        if bytes.len() < TRANSFORM_HEADER_SIZE {
+            return Err(Error::DecryptionFailed);
+        }
+        let header: TransformHeader = serde_smb::from_slice(&bytes[..TRANSFORM_HEADER_SIZE])?;
+        let mut message = bytes[TRANSFORM_HEADER_SIZE..].to_vec();
+        if message.len() != header.original_message_size as usize {
+            return Err(Error::DecryptionFailed);
+        }
+
+        let (key, nonce, aad) = (
+            &self.decryption_key[..],
+            &header.nonce.0,
+            &bytes[20..TRANSFORM_HEADER_SIZE],
+        );
+        let tag = &header.signature;
+        match self.cipher {
+            CipherId::Aes128Ccm => {
+                open::<ccm::Ccm<aes::Aes128, U16, U11>>(key, nonce, aad, &mut message, tag)
+            }
+            CipherId::Aes128Gcm => open::<aes_gcm::Aes128Gcm>(key, nonce, aad, &mut message, tag),
+            CipherId::Aes256Ccm => {
+                open::<ccm::Ccm<aes::Aes256, U16, U11>>(key, nonce, aad, &mut message, tag)
+            }
+            CipherId::Aes256Gcm => open::<aes_gcm::Aes256Gcm>(key, nonce, aad, &mut message, tag),
+        }?;
+        Ok(message)
+    }
+}
+
+fn seal<C: AeadInPlace + aes_gcm::KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    buffer: &mut [u8],
+) -> Signature {
+    let cipher = C::new_from_slice(key).unwrap();
+    let nonce = GenericArray::from_slice(&nonce[..C::NonceSize::USIZE]);
+    let tag = cipher
+        .encrypt_in_place_detached(nonce, aad, buffer)
+        .unwrap();
+    let mut signature = Signature([0; 16]);
+    signature.0.copy_from_slice(&tag);
+    signature
+}
+
+fn open<C: AeadInPlace + aes_gcm::KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    buffer: &mut [u8],
+    tag: &Signature,
+) -> Result<()> {
+    let cipher = C::new_from_slice(key).unwrap();
+    let nonce = GenericArray::from_slice(&nonce[..C::NonceSize::USIZE]);
+    cipher
+        .decrypt_in_place_detached(nonce, aad, buffer, GenericArray::from_slice(&tag.0[..]))
+        .map_err(|_| Error::DecryptionFailed)
+}
+
+fn path_str(path: impl AsRef<Path>) -> String {
+    let path_compontents: Vec<_> = path
+        .as_ref()
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(String::from(s.to_str().unwrap())),
+            Component::ParentDir => Some(String::from("..")),
+            _ => None,
+        })
+        .collect();
+    path_compontents.join("\\")
+}
+
+fn open_request(path: impl AsRef<Path>) -> CreateRequest {
+    CreateRequest {
+        requested_oplock_level: OplockLevel::None,
+        impersonation_level: ImpersonationLevel::Impersonation,
+        desired_access: AccessMask::GENERIC_READ
+            | AccessMask::GENERIC_WRITE
+            | AccessMask::FILE_READ_ATTRIBUTES,
+        file_attributes: FileAttributes::empty(),
+        share_access: FileShareAccess::READ | FileShareAccess::WRITE | FileShareAccess::DELETE,
+        create_disposition: FileCreateDisposition::Open,
+        create_options: FileCreateOptions::empty(),
+        name: path_str(path),
+        create_contexts: vec![],
+    }
+}
 
-        self.transport.write_u32(req_bytes.len() as u32).await?;
-        self.transport.write_all(&req_bytes).await?;
+fn query_info_request<Info: HasFileInformationClass>(file_id: FileId) -> QueryInfoRequest {
+    QueryInfoRequest {
+        info_type: InfoType::File,
+        file_info_class: Info::file_information_class(),
+        output_buffer_length: 8293,
+        additional_information: 0,
+        flags: QueryInfoFlags::empty(),
+        file_id,
+        buffer: vec![],
+    }
+}
 
-        let mut response_header: ResponseHeader;
-        let mut response_bytes: Vec<u8>;
-        let mut deser = loop {
-            let len = self.transport.read_u32().await?;
-            response_bytes = vec![0; len as usize];
-            self.transport.read_exact(&mut response_bytes).await?;
+/// Several requests sent to the server in a single frame, built by `Client::compound`.
+pub struct Compound<'a, TransportT> {
+    client: &'a Client<TransportT>,
+    requests: Result<Vec<OutgoingRequest>>,
+}
 
-            let mut deser = serde_smb::Deserializer::new(&response_bytes[..]);
-            response_header = Deserialize::deserialize(&mut deser)?;
+impl<TransportT: Transport> Compound<'_, TransportT> {
+    /// Adds a request that stands on its own.
+    pub fn push<T: Serialize + HasCommand>(self, request: T) -> Self {
+        self.add(false, request)
+    }
 
-            if response_header.signature == Signature([0; 16]) {
-                let mut hasher = sha2::Sha512::new();
-                hasher.update(&self.pre_auth_hash);
-                hasher.update(&response_bytes);
-                self.pre_auth_hash = hasher.finalize().to_vec();
-            }
+    /// Adds a request that operates on the same file as the request before it. If that one opened
+    /// the file, use `FileId::RELATED` to refer to it.
+    pub fn push_related<T: Serialize + HasCommand>(self, request: T) -> Self {
+        self.add(true, request)
+    }
 
-            if response_header.nt_status != NtStatus::Pending {
-                break deser;
-            }
-        };
+    fn add<T: Serialize + HasCommand>(mut self, related: bool, request: T) -> Self {
+        let tree_id = Some(self.client.tree.id);
+        self.requests = self.requests.and_then(|mut requests| {
+            requests.push(OutgoingRequest::new(Credits(1), tree_id, related, request)?);
+            Ok(requests)
+        });
+        self
+    }
 
-        if response_header.nt_status == NtStatus::Success
-            || response_header.nt_status == NtStatus::MoreProcessingRequired
-        {
-            let response_body: R = Deserialize::deserialize(&mut deser)?;
-            Ok((response_header, response_body))
-        } else {
-            Err(Error::NtStatus(response_header.nt_status))
-        }
+    /// Sends the requests and waits for all of their responses, which are in the same order.
+    pub async fn send(self) -> Result<Vec<CompoundResponse>> {
+        let responses = self.client.auth_client.compound(self.requests?).await?;
+        responses
+            .into_iter()
+            .map(|response| {
+                let (header, bytes) = response?;
+                Ok(CompoundResponse { header, bytes })
+            })
+            .collect()
     }
+}
 
-    async fn negotiate(&mut self) -> Result<()> {
-        let mut rng = OsRng;
-        let pre_auth_salt = rng.gen::<[u8; 32]>().to_vec();
-        let uuid = Uuid::new(&mut rng);
+/// One of the responses to a compound.
+pub struct CompoundResponse {
+    header: ResponseHeader,
+    bytes: Vec<u8>,
+}
 
-        let request = NegotiateRequest {
-            security_mode: SecurityMode::SIGNING_ENABLED,
-            capabilities: Capabilities::empty(),
-            client_guid: uuid,
-            dialects: vec![Dialect::Smb3_1_1],
-            negotiate_contexts: vec![NegotiateContext::Smb2PreauthIntegrityCapabilities(
-                Smb2PreauthIntegrityCapabilities {
-                    data_length: 38,
-                    hash_algorithms: vec![HashAlgorithm::Sha512],
-                    salt: pre_auth_salt,
-                },
-            )],
-        };
+impl CompoundResponse {
+    pub fn header(&self) -> &ResponseHeader {
+        &self.header
+    }
 
-        let _response: (_, NegotiateResponse) = self
-            .request(Credits(0), Credits(10), None, None, None, request)
-            .await?;
-        Ok(())
+    /// Parses the body of the response, or returns the error the server responded with. When a
+    /// request in a related chain fails, the ones after it fail the same way.
+    pub fn body<R: DeserializeOwned>(&self) -> Result<R> {
+        response_body(&self.header, &self.bytes)
     }
 }
 
-struct AuthenticatedClient<TransportT> {
-    unauth_client: UnauthenticatedClient<TransportT>,
-    session_id: SessionId,
-    signing_key: Vec<u8>,
+/// What the server told us about a share when we connected to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareInfo {
+    pub share_type: ShareType,
+    pub share_flags: ShareFlags,
+    pub share_capabilities: ShareCapabilities,
+    /// The most access the user can be granted to anything on the share.
+    pub maximal_access: AccessMask,
 }
 
-impl<TransportT: Transport> AuthenticatedClient<TransportT> {
-    async fn new(transport: TransportT, username: &str, password: &str) -> Result<Self> {
-        let mut unauth_client = UnauthenticatedClient::new(transport);
+impl From<TreeConnectResponse> for ShareInfo {
+    fn from(response: TreeConnectResponse) -> Self {
+        Self {
+            share_type: response.share_type,
+            share_flags: response.share_flags,
+            share_capabilities: response.share_capabilities,
+            maximal_access: response.access_mask,
+        }
+    }
+}
 
-        unauth_client.negotiate().await?;
+impl ShareInfo {
+    pub fn caching_mode(&self) -> CachingMode {
+        self.share_flags.caching_mode()
+    }
 
-        let mut ntlm = Ntlm::new();
+    pub fn is_dfs(&self) -> bool {
+        self.share_flags.contains(ShareFlags::DFS)
+            || self.share_capabilities.contains(ShareCapabilities::DFS)
+    }
 
-        let identity = AuthIdentity {
-            username: username.into(),
-            password: String::from(password).into(),
-            domain: None,
-        };
-        let mut acq_cred_result = ntlm
-            .acquire_credentials_handle()
-            .with_credential_use(CredentialUse::Outbound)
-            .with_auth_data(&identity)
-            .execute()?;
-
-        let mut output_buffer = vec![SecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
-
-        let mut builder =
-            EmptyInitializeSecurityContext::<<Ntlm as SspiImpl>::CredentialsHandle>::new()
-                .with_credentials_handle(&mut acq_cred_result.credentials_handle)
-                .with_context_requirements(
-                    ClientRequestFlags::CONFIDENTIALITY | ClientRequestFlags::ALLOCATE_MEMORY,
-                )
-                .with_target_data_representation(DataRepresentation::Native)
-                .with_target_name(&identity.username)
-                .with_output(&mut output_buffer);
+    /// Whether the server wants the share's traffic encrypted. We encrypt it whenever it does.
+    pub fn encrypts_data(&self) -> bool {
+        self.share_flags.contains(ShareFlags::ENCRYPT_DATA)
+    }
 
-        let _result = ntlm.initialize_security_context_impl(&mut builder)?;
+    pub fn compresses_data(&self) -> bool {
+        self.share_flags.contains(ShareFlags::COMPRESS_DATA)
+    }
 
-        let security_blob = output_buffer.pop().unwrap().buffer;
-        let mut request = SessionSetupRequest {
-            session_binding_request: false,
-            security_mode: SecurityMode::SIGNING_ENABLED,
-            capabilities: Capabilities::empty(),
-            channel: 0,
-            previous_session_id: SessionId(0),
-            security_blob,
-        };
+    /// Whether open handles survive the server failing over to another node. Persistent handles
+    /// can only be had on such shares.
+    pub fn is_continuously_available(&self) -> bool {
+        self.share_capabilities
+            .contains(ShareCapabilities::CONTINUOUS_AVAILABILITY)
+    }
 
-        let (mut resp_header, mut response): (ResponseHeader, SessionSetupResponse) = unauth_client
-            .request(Credits(0), Credits(130), None, None, None, request.clone())
-            .await?;
+    pub fn is_scale_out(&self) -> bool {
+        self.share_capabilities
+            .contains(ShareCapabilities::SCALEOUT)
+    }
+}
 
-        let session_id = resp_header.session_id;
+/// A share connected through a session.
+#[derive(Clone, Debug)]
+pub struct Tree {
+    id: TreeId,
+    info: ShareInfo,
+}
 
-        while resp_header.nt_status == NtStatus::MoreProcessingRequired {
-            let mut input_buffer = vec![SecurityBuffer::new(
-                mem::take(&mut response.security_blob),
-                SecurityBufferType::Token,
-            )];
-
-            let mut output_buffer =
-                vec![SecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
-            let mut builder =
-                EmptyInitializeSecurityContext::<<Ntlm as SspiImpl>::CredentialsHandle>::new()
-                    .with_credentials_handle(&mut acq_cred_result.credentials_handle)
-                    .with_context_requirements(
-                        ClientRequestFlags::CONFIDENTIALITY | ClientRequestFlags::ALLOCATE_MEMORY,
-                    )
-                    .with_target_data_representation(DataRepresentation::Native)
-                    .with_target_name(&identity.username)
-                    .with_input(&mut input_buffer)
-                    .with_output(&mut output_buffer);
-
-            let result = ntlm.initialize_security_context_impl(&mut builder)?;
-
-            if [
-                SecurityStatus::CompleteAndContinue,
-                SecurityStatus::CompleteNeeded,
-            ]
-            .contains(&result.status)
-            {
-                ntlm.complete_auth_token(&mut output_buffer)?;
-            }
+impl Tree {
+    pub fn id(&self) -> TreeId {
+        self.id
+    }
 
-            request.security_blob = output_buffer.pop().unwrap().buffer;
+    pub fn info(&self) -> &ShareInfo {
+        &self.info
+    }
+}
 
-            (resp_header, response) = unauth_client
-                .request(
-                    Credits(0),
-                    Credits(130),
-                    Some(session_id),
-                    None,
-                    None,
-                    request.clone(),
-                )
-                .await?;
+/// An authenticated session, through which any number of shares can be connected. It can be
+/// cloned, the clones share the same connection.
+pub struct Session<TransportT> {
+    auth_client: Arc<AuthenticatedClient<TransportT>>,
+    max_read_size: u32,
+    max_write_size: u32,
+    max_outstanding_io: usize,
+}
+
+impl<TransportT> Clone for Session<TransportT> {
+    fn clone(&self) -> Self {
+        Self {
+            auth_client: self.auth_client.clone(),
+            max_read_size: self.max_read_size,
+            max_write_size: self.max_write_size,
+            max_outstanding_io: self.max_outstanding_io,
         }
+    }
+}
 
-        let session_key = ntlm.session_key().unwrap();
-        assert_eq!(session_key.len(), 16);
-        let signing_key = sp800_108_counter_kdf(
-            16,
-            &session_key,
-            b"SMBSigningKey\0",
-            &unauth_client.pre_auth_hash,
-        );
+impl<TransportT: Transport> Session<TransportT> {
+    pub async fn new(transport: TransportT, authenticator: impl Authenticator) -> Result<Self> {
+        Self::with_options(transport, authenticator, ClientOptions::default()).await
+    }
 
-        Ok(Self {
-            unauth_client,
-            session_id,
-            signing_key,
-        })
+    pub async fn with_options(
+        transport: TransportT,
+        authenticator: impl Authenticator,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        let auth_client = AuthenticatedClient::new(transport, authenticator, &options).await?;
+        Ok(Self::from_auth_client(auth_client, &options))
     }
 
-    async fn request<T: serde::Serialize + HasCommand, R: serde::de::DeserializeOwned>(
-        &mut self,
-        tree_id: Option<TreeId>,
-        credit_charge: Credits,
-        credits_requested: Credits,
-        request: T,
-    ) -> Result<(ResponseHeader, R)> {
-        let mut sig_func = |bytes: &[u8]| {
-            let mut mac = cmac::Cmac::<aes::Aes128>::new_from_slice(&self.signing_key[..]).unwrap();
-            mac.update(bytes);
-            Ok(Signature(mac.finalize().into_bytes().into()))
+    fn from_auth_client(
+        auth_client: AuthenticatedClient<TransportT>,
+        options: &ClientOptions,
+    ) -> Self {
+        // Without large MTU support each request can only carry what one credit pays for
+        let info = &auth_client.negotiate_info;
+        let io_size_limit = if info.dialect != Dialect::Smb2_0_2
+            && info.capabilities.contains(Capabilities::LARGE_MTU)
+        {
+            MAX_IO_SIZE
+        } else {
+            CREDIT_PAYLOAD_SIZE
         };
-        self.unauth_client
-            .request(
-                credit_charge,
-                credits_requested,
-                Some(self.session_id),
-                Some(&mut sig_func),
-                tree_id,
-                request,
-            )
-            .await
-    }
+        let max_read_size = info.max_read_size.min(io_size_limit);
+        let max_write_size = info.max_write_size.min(io_size_limit);
 
-    async fn tree_connect(&mut self, path: &str) -> Result<TreeId> {
-        let (header, _): (_, TreeConnectResponse) = self
-            .request(
-                None,
-                Credits(1),
-                Credits(64),
-                TreeConnectRequest {
-                    flags: TreeConnectFlags::empty(),
-                    path: path.into(),
-                },
-            )
-            .await?;
+        Self {
+            auth_client: Arc::new(auth_client),
+            max_read_size,
+            max_write_size,
+            max_outstanding_io: options.max_outstanding_io.max(1),
+        }
+    }
 
-        Ok(header.tree_id)
+    pub fn negotiate_info(&self) -> &NegotiateInfo {
+        &self.auth_client.negotiate_info
     }
-}
 
-fn sp800_108_counter_kdf(key_len: usize, secret: &[u8], label: &[u8], salt: &[u8]) -> Vec<u8> {
-    let length: u32 = (key_len * 8).try_into().unwrap();
+    /// Whether the server made this a guest or null session, and whether it encrypts it.
+    pub fn session_flags(&self) -> SessionFlags {
+        self.auth_client.channel().session_flags
+    }
 
-    let mut p = vec![];
-    let mut counter: u32 = 1;
+    /// Connects to a share, returning a client for working with the files on it.
+    pub async fn tree_connect(&self, path: &str) -> Result<Client<TransportT>> {
+        let tree = self.auth_client.tree_connect(path).await?;
+        Ok(Client {
+            auth_client: self.auth_client.clone(),
+            tree,
+            max_read_size: self.max_read_size,
+            max_write_size: self.max_write_size,
+            max_outstanding_io: self.max_outstanding_io,
+            replay: false,
+        })
+    }
 
-    while p.len() < key_len {
-        let mut hmac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret).unwrap();
-        hmac.update(&counter.to_be_bytes());
-        hmac.update(label);
-        hmac.update(&[0u8]);
-        hmac.update(salt);
-        hmac.update(&length.to_be_bytes());
-        p.extend(hmac.finalize().into_bytes());
-        counter += 1;
+    /// Whether the connection is still up. Once it is lost every request fails, until the session
+    /// is reconnected.
+    pub fn is_connected(&self) -> bool {
+        self.auth_client.is_connected()
     }
 
-    p.resize(key_len, 0);
+    /// Sets the session up again on a new transport after the connection was lost, for the same
+    /// user. The clients on its trees keep working, and the handles opened with `open_durable`
+    /// are reopened, replaying the creates that opened them. Other handles are gone, the server
+    /// closed them with the connection.
+    ///
+    /// It fails if the session or any of its trees can't be set up again, in which case it can
+    /// be tried again on another transport. Handles that can't be reopened are forgotten, and
+    /// the error for the first of them returned.
+    pub async fn reconnect(
+        &self,
+        transport: TransportT,
+        authenticator: impl Authenticator,
+    ) -> Result<()> {
+        self.auth_client.reconnect(transport, authenticator).await
+    }
 
-    p
+    /// Closes the handles that are still open on any of the session's trees, disconnects the
+    /// trees and logs off, then shuts down the transport. Neither the clones of the session nor
+    /// the clients on its trees can be used afterwards.
+    pub async fn shutdown(self) -> Result<()> {
+        self.auth_client.shutdown().await
+    }
 }
 
-fn path_str(path: impl AsRef<Path>) -> String {
-    let path_compontents: Vec<_> = path
-        .as_ref()
-        .components()
-        .filter_map(|c| match c {
-            Component::Normal(s) => Some(String::from(s.to_str().unwrap())),
-            Component::ParentDir => Some(String::from("..")),
-            _ => None,
-        })
-        .collect();
-    path_compontents.join("\\")
+/// A connection to a share. It can be cloned to issue requests from many tasks at once, the
+/// clones share the same connection.
+///
+/// Dropping the future of a call that is in flight cancels the request on the server.
+pub struct Client<TransportT> {
+    auth_client: Arc<AuthenticatedClient<TransportT>>,
+    tree: Tree,
+    max_read_size: u32,
+    max_write_size: u32,
+    max_outstanding_io: usize,
+    /// Whether requests on durable handles repeat ones that may have reached the server before
+    /// the connection was lost.
+    replay: bool,
 }
 
-pub struct Client<TransportT> {
-    auth_client: AuthenticatedClient<TransportT>,
-    tree_id: TreeId,
+impl<TransportT> Clone for Client<TransportT> {
+    fn clone(&self) -> Self {
+        Self {
+            auth_client: self.auth_client.clone(),
+            tree: self.tree.clone(),
+            max_read_size: self.max_read_size,
+            max_write_size: self.max_write_size,
+            max_outstanding_io: self.max_outstanding_io,
+            replay: self.replay,
+        }
+    }
 }
 
 impl<TransportT: Transport> Client<TransportT> {
+    /// Sets up a session and connects to a single share through it.
     pub async fn new(
         transport: TransportT,
-        username: &str,
-        password: &str,
+        authenticator: impl Authenticator,
         path: &str,
     ) -> Result<Self> {
-        let mut auth_client = AuthenticatedClient::new(transport, username, password).await?;
-        let tree_id = auth_client.tree_connect(path).await?;
-        Ok(Self {
-            auth_client,
-            tree_id,
-        })
+        Self::with_options(transport, authenticator, path, ClientOptions::default()).await
+    }
+
+    pub async fn with_options(
+        transport: TransportT,
+        authenticator: impl Authenticator,
+        path: &str,
+        options: ClientOptions,
+    ) -> Result<Self> {
+        Session::with_options(transport, authenticator, options)
+            .await?
+            .tree_connect(path)
+            .await
+    }
+
+    pub fn negotiate_info(&self) -> &NegotiateInfo {
+        &self.auth_client.negotiate_info
+    }
+
+    /// Whether the server made this a guest or null session, and whether it encrypts it.
+    pub fn session_flags(&self) -> SessionFlags {
+        self.auth_client.channel().session_flags
+    }
+
+    /// The session the tree is connected through, for connecting to other shares on the server.
+    pub fn session(&self) -> Session<TransportT> {
+        Session {
+            auth_client: self.auth_client.clone(),
+            max_read_size: self.max_read_size,
+            max_write_size: self.max_write_size,
+            max_outstanding_io: self.max_outstanding_io,
+        }
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// The largest amount of data a single `read` will return.
+    pub fn max_read_size(&self) -> u32 {
+        self.max_read_size
+    }
+
+    /// The largest amount of data a single `write` will accept.
+    pub fn max_write_size(&self) -> u32 {
+        self.max_write_size
+    }
+
+    /// A client for repeating requests after reconnecting. Those on durable handles are marked as
+    /// replays, so the server doesn't do them twice if the first attempt did reach it.
+    fn replaying(&self) -> Self {
+        Self {
+            replay: true,
+            ..self.clone()
+        }
+    }
+
+    fn replays(&self, file_id: FileId) -> bool {
+        self.replay && self.auth_client.is_durable(file_id)
+    }
+
+    /// Sends a request on an open file. Everything that operates on a handle goes through here,
+    /// so repeats on durable handles are marked as replays.
+    async fn file_request<T: Serialize + HasCommand, R: DeserializeOwned>(
+        &self,
+        file_id: FileId,
+        request: T,
+    ) -> Result<(ResponseHeader, R)> {
+        let request = OutgoingRequest::new(Credits(1), Some(self.tree.id), false, request)?;
+        let request = OutgoingRequest {
+            replay: self.replays(file_id),
+            ..request
+        };
+        let (header, bytes) = self.auth_client.compound(vec![request]).await?.remove(0)?;
+        let body = response_body(&header, &bytes)?;
+        Ok((header, body))
     }
 
-    pub async fn look_up(&mut self, path: impl AsRef<Path>) -> Result<FileId> {
+    pub async fn look_up(&self, path: impl AsRef<Path>) -> Result<FileId> {
         let (_, response): (_, CreateResponse) = self
             .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
-                CreateRequest {
-                    requested_oplock_level: OplockLevel::None,
-                    impersonation_level: ImpersonationLevel::Impersonation,
-                    desired_access: AccessMask::GENERIC_READ
-                        | AccessMask::GENERIC_WRITE
-                        | AccessMask::FILE_READ_ATTRIBUTES,
-                    file_attributes: FileAttributes::empty(),
-                    share_access: FileShareAccess::READ
-                        | FileShareAccess::WRITE
-                        | FileShareAccess::DELETE,
-                    create_disposition: FileCreateDisposition::Open,
-                    create_options: FileCreateOptions::empty(),
-                    name: path_str(path),
-                    create_contexts: vec![],
-                },
-            )
+            .request(Some(self.tree.id), Credits(1), open_request(path))
             .await?;
+        self.auth_client.track_open(self.tree.id, response.file_id);
         Ok(response.file_id)
     }
 
-    pub async fn create_file(&mut self, path: impl AsRef<Path>) -> Result<FileId> {
+    pub async fn create_file(&self, path: impl AsRef<Path>) -> Result<FileId> {
         let (_, response): (_, CreateResponse) = self
             .auth_client
             .request(
-                Some(self.tree_id),
+                Some(self.tree.id),
                 Credits(1),
-                Credits(64),
                 CreateRequest {
                     requested_oplock_level: OplockLevel::None,
                     impersonation_level: ImpersonationLevel::Impersonation,
@@ -412,16 +2524,16 @@ impl<TransportT: Transport> Client<TransportT> {
                 },
             )
             .await?;
+        self.auth_client.track_open(self.tree.id, response.file_id);
         Ok(response.file_id)
     }
 
-    pub async fn delete(&mut self, path: impl AsRef<Path>) -> Result<()> {
+    pub async fn delete(&self, path: impl AsRef<Path>) -> Result<()> {
         let (_, response): (_, CreateResponse) = self
             .auth_client
             .request(
-                Some(self.tree_id),
+                Some(self.tree.id),
                 Credits(1),
-                Credits(64),
                 CreateRequest {
                     requested_oplock_level: OplockLevel::None,
                     impersonation_level: ImpersonationLevel::Impersonation,
@@ -442,18 +2554,15 @@ impl<TransportT: Transport> Client<TransportT> {
     }
 
     pub async fn query_directory(
-        &mut self,
+        &self,
         file_id: FileId,
     ) -> Result<Vec<FileIdBothDirectoryInformation>> {
         let mut output = vec![];
 
         loop {
             let res = self
-                .auth_client
-                .request(
-                    Some(self.tree_id),
-                    Credits(1),
-                    Credits(64),
+                .file_request(
+                    file_id,
                     QueryDirectoryRequest {
                         file_information_class:
                             FileInformationClass::FileIdFullDirectoryInformation,
@@ -476,13 +2585,16 @@ impl<TransportT: Transport> Client<TransportT> {
         Ok(output)
     }
 
-    pub async fn write(&mut self, file_id: FileId, offset: u64, data: Vec<u8>) -> Result<u32> {
+    /// Writes as much of the data as fits in one request, returning how much was written.
+    pub async fn write(&self, file_id: FileId, offset: u64, mut data: Vec<u8>) -> Result<u32> {
+        // Until the server grants us enough credits, writes are smaller than the maximum
+        let max_size = self
+            .max_write_size
+            .min(data.len().try_into().unwrap_or(u32::MAX));
         let (_, response): (_, WriteResponse) = self
             .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
+            .sized_request(self.tree.id, max_size, self.replays(file_id), |size| {
+                data.truncate(size as usize);
                 WriteRequest {
                     file_id,
                     offset,
@@ -491,48 +2603,55 @@ impl<TransportT: Transport> Client<TransportT> {
                     flags: WriteFlags::empty(),
                     data,
                     channel_data: vec![],
-                },
-            )
+                }
+            })
             .await?;
         Ok(response.count)
     }
 
     pub async fn write_all(
-        &mut self,
+        &self,
         file_id: FileId,
-        mut source: impl io::AsyncRead + Unpin,
+        source: impl io::AsyncRead + Unpin,
     ) -> Result<()> {
-        let mut offset = 0;
-        loop {
-            let mut buf = vec![0; IO_SIZE];
+        let chunk_size = self.max_write_size as usize;
+        let chunks = stream::try_unfold((source, 0), |(mut source, offset)| async move {
+            let mut buf = vec![0; chunk_size];
             let amount_read = source.read(&mut buf[..]).await?;
             if amount_read == 0 {
-                break;
+                return Ok(None);
             }
 
             buf.resize(amount_read, 0);
+            Ok(Some(((offset, buf), (source, offset + amount_read as u64))))
+        });
+        chunks
+            .map_ok(|(offset, buf)| self.write_chunk(file_id, offset, buf))
+            .try_buffer_unordered(self.max_outstanding_io)
+            .try_collect()
+            .await
+    }
 
-            while !buf.is_empty() {
-                let count = self.write(file_id, offset, buf.clone()).await?;
-                buf = buf[count as usize..].to_owned();
-            }
-
-            offset += amount_read as u64;
+    /// Writes all of the data, using as many requests as it takes.
+    async fn write_chunk(&self, file_id: FileId, mut offset: u64, mut buf: Vec<u8>) -> Result<()> {
+        while !buf.is_empty() {
+            let count = self.write(file_id, offset, buf.clone()).await?;
+            buf.drain(..count as usize);
+            offset += count as u64;
         }
         Ok(())
     }
 
-    pub async fn read(&mut self, file_id: FileId, offset: u64, count: u32) -> Result<Vec<u8>> {
+    pub async fn read(&self, file_id: FileId, offset: u64, count: u32) -> Result<Vec<u8>> {
+        // Until the server grants us enough credits, reads are smaller than the maximum
+        let max_size = count.min(self.max_read_size);
         let (_, response): (_, ReadResponse) = self
             .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(9),
+            .sized_request(self.tree.id, max_size, self.replays(file_id), |length| {
                 ReadRequest {
                     padding: 0,
                     flags: ReadFlags::empty(),
-                    length: count,
+                    length,
                     offset,
                     file_id,
                     minimum_bytes: 0,
@@ -540,95 +2659,116 @@ impl<TransportT: Transport> Client<TransportT> {
                     remaining_bytes: 0,
                     // this can't be empty for some reason
                     channel_data: vec![0],
-                },
-            )
+                }
+            })
             .await?;
         Ok(response.data)
     }
 
     pub async fn read_all(
-        &mut self,
+        &self,
         file_id: FileId,
         mut sink: impl io::AsyncWrite + Unpin,
     ) -> Result<()> {
-        let mut offset = 0;
-        loop {
-            match self.read(file_id, offset, IO_SIZE as u32).await {
-                Ok(read_data) => {
-                    offset += read_data.len() as u64;
-                    sink.write_all(&read_data).await?;
-                }
+        // Reads past the end of the file are wasted, but they allow us to keep many in flight
+        let chunk_size = self.max_read_size;
+        let mut chunks = stream::iter((0..).map(|i| i * chunk_size as u64))
+            .map(|offset| self.read_chunk(file_id, offset, chunk_size))
+            .buffered(self.max_outstanding_io);
+        while let Some(chunk) = chunks.try_next().await? {
+            sink.write_all(&chunk).await?;
+            if chunk.len() < chunk_size as usize {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `count` bytes using as many requests as it takes, or fewer if the end of the file is
+    /// reached.
+    async fn read_chunk(&self, file_id: FileId, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        while data.len() < count as usize {
+            let remaining = count - data.len() as u32;
+            match self
+                .read(file_id, offset + data.len() as u64, remaining)
+                .await
+            {
+                Ok(read_data) if read_data.is_empty() => break,
+                Ok(read_data) => data.extend(read_data),
                 Err(Error::NtStatus(NtStatus::EndOfFile)) => break,
                 Err(e) => return Err(e),
             }
         }
-        Ok(())
+        Ok(data)
     }
 
     pub async fn query_info<Info: DeserializeOwned + HasFileInformationClass>(
-        &mut self,
+        &self,
         file_id: FileId,
     ) -> Result<Info> {
         let (_, response): (_, QueryInfoResponse<Info>) = self
-            .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
-                QueryInfoRequest {
-                    info_type: InfoType::File,
-                    file_info_class: Info::file_information_class(),
-                    output_buffer_length: 8293,
-                    additional_information: 0,
-                    flags: QueryInfoFlags::empty(),
-                    file_id,
-                    buffer: vec![],
-                },
-            )
+            .file_request(file_id, query_info_request::<Info>(file_id))
             .await?;
         Ok(response.info)
     }
 
-    pub async fn close(&mut self, file_id: FileId) -> Result<CloseResponse> {
+    /// Opens a file, queries it and closes it again, all in one round trip.
+    pub async fn query_info_path<Info: DeserializeOwned + HasFileInformationClass>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Info> {
+        let responses = self
+            .compound()
+            .push(open_request(path))
+            .push_related(query_info_request::<Info>(FileId::RELATED))
+            .push_related(CloseRequest {
+                flags: CloseFlags::empty(),
+                file_id: FileId::RELATED,
+            })
+            .send()
+            .await?;
+        responses[0].body::<CreateResponse>()?;
+        let response: QueryInfoResponse<Info> = responses[1].body()?;
+        Ok(response.info)
+    }
+
+    /// Starts building a compound, for sending several requests in a single frame.
+    pub fn compound(&self) -> Compound<'_, TransportT> {
+        Compound {
+            client: self,
+            requests: Ok(vec![]),
+        }
+    }
+
+    pub async fn close(&self, file_id: FileId) -> Result<CloseResponse> {
         let (_, response): (_, CloseResponse) = self
-            .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
+            .file_request(
+                file_id,
                 CloseRequest {
                     flags: CloseFlags::empty(),
                     file_id,
                 },
             )
             .await?;
+        self.auth_client.track_close(file_id);
         Ok(response)
     }
 
-    pub async fn flush(&mut self, file_id: FileId) -> Result<()> {
-        let (_, _response): (_, FlushResponse) = self
-            .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
-                FlushRequest { file_id },
-            )
-            .await?;
+    pub async fn flush(&self, file_id: FileId) -> Result<()> {
+        let (_, _response): (_, FlushResponse) =
+            self.file_request(file_id, FlushRequest { file_id }).await?;
         Ok(())
     }
 
     pub async fn set_info<Info: Serialize + HasFileInformationClass>(
-        &mut self,
+        &self,
         file_id: FileId,
         info: Info,
     ) -> Result<()> {
         let (_, _response): (_, SetInfoResponse) = self
-            .auth_client
-            .request(
-                Some(self.tree_id),
-                Credits(1),
-                Credits(64),
+            .file_request(
+                file_id,
                 SetInfoRequest {
                     info_type: InfoType::File,
                     file_info_class: Info::file_information_class(),
@@ -641,7 +2781,7 @@ impl<TransportT: Transport> Client<TransportT> {
         Ok(())
     }
 
-    pub async fn rename(&mut self, file_id: FileId, path: impl AsRef<Path>) -> Result<()> {
+    pub async fn rename(&self, file_id: FileId, path: impl AsRef<Path>) -> Result<()> {
         self.set_info(
             file_id,
             FileRenameInformation {
@@ -653,9 +2793,23 @@ impl<TransportT: Transport> Client<TransportT> {
         Ok(())
     }
 
-    pub async fn resize(&mut self, file_id: FileId, size: i64) -> Result<()> {
+    pub async fn resize(&self, file_id: FileId, size: i64) -> Result<()> {
         self.set_info(file_id, FileEndOfFileInformation { end_of_file: size })
             .await?;
         Ok(())
     }
+
+    /// Disconnects the tree, leaving the session connected. Handles still open on it are closed
+    /// by the server.
+    pub async fn tree_disconnect(self) -> Result<()> {
+        self.auth_client.tree_disconnect(self.tree.id).await
+    }
+
+    /// Shuts down the whole session, as `Session::shutdown` does.
+    pub async fn shutdown(self) -> Result<()> {
+        self.auth_client.shutdown().await
+    }
 }
+
+#[cfg(test)]
+mod tests;
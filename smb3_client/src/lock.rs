@@ -0,0 +1,134 @@
+//! Byte range locks, which the server enforces against other opens of the file.
+
+use crate::{spawn_cleanup, Client, Result, Transport};
+use smb3::{FileId, LockElement, LockFlags, LockRequest, LockResponse};
+
+/// Whether other opens can still lock the range for reading.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// A locked range of a file. It is unlocked when dropped, without waiting for the server to
+/// confirm it, or by `unlock` which does wait. Closing the file releases its locks too.
+pub struct LockGuard<TransportT: Transport> {
+    client: Client<TransportT>,
+    file_id: FileId,
+    offset: u64,
+    length: u64,
+    locked: bool,
+}
+
+impl<TransportT: Transport> LockGuard<TransportT> {
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub async fn unlock(mut self) -> Result<()> {
+        self.locked = false;
+        self.client
+            .unlock(self.file_id, self.offset, self.length)
+            .await
+    }
+}
+
+impl<TransportT: Transport> Drop for LockGuard<TransportT> {
+    fn drop(&mut self) {
+        if !self.locked {
+            return;
+        }
+        let client = self.client.clone();
+        let (file_id, offset, length) = (self.file_id, self.offset, self.length);
+        spawn_cleanup(async move { client.unlock(file_id, offset, length).await });
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Locks `length` bytes of the file starting at `offset`, waiting for as long as it takes
+    /// conflicting locks to be released. Dropping the future gives up on the lock.
+    pub async fn lock(
+        &self,
+        file_id: FileId,
+        offset: u64,
+        length: u64,
+        mode: LockMode,
+    ) -> Result<LockGuard<TransportT>> {
+        self.lock_range(file_id, offset, length, mode, LockFlags::empty())
+            .await
+    }
+
+    /// Like `lock`, but fails with `NtStatus::LockNotGranted` right away if the range is locked
+    /// by another open.
+    pub async fn try_lock(
+        &self,
+        file_id: FileId,
+        offset: u64,
+        length: u64,
+        mode: LockMode,
+    ) -> Result<LockGuard<TransportT>> {
+        self.lock_range(file_id, offset, length, mode, LockFlags::FAIL_IMMEDIATELY)
+            .await
+    }
+
+    /// Unlocks a range, which has to match one that was locked exactly.
+    pub async fn unlock(&self, file_id: FileId, offset: u64, length: u64) -> Result<()> {
+        self.send_lock(file_id, offset, length, LockFlags::UNLOCK)
+            .await
+    }
+
+    async fn lock_range(
+        &self,
+        file_id: FileId,
+        offset: u64,
+        length: u64,
+        mode: LockMode,
+        flags: LockFlags,
+    ) -> Result<LockGuard<TransportT>> {
+        let flags = flags
+            | match mode {
+                LockMode::Shared => LockFlags::SHARED_LOCK,
+                LockMode::Exclusive => LockFlags::EXCLUSIVE_LOCK,
+            };
+        self.send_lock(file_id, offset, length, flags).await?;
+        Ok(LockGuard {
+            client: self.clone(),
+            file_id,
+            offset,
+            length,
+            locked: true,
+        })
+    }
+
+    async fn send_lock(
+        &self,
+        file_id: FileId,
+        offset: u64,
+        length: u64,
+        flags: LockFlags,
+    ) -> Result<()> {
+        let (_, _response): (_, LockResponse) = self
+            .file_request(
+                file_id,
+                LockRequest {
+                    lock_sequence: 0,
+                    file_id,
+                    locks: vec![LockElement {
+                        offset,
+                        length,
+                        flags,
+                    }],
+                },
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use crate::spnego::Mechanism;
+use crate::sspi;
+use crate::Result;
+use hmac::{Hmac, Mac as _};
+use md5::{Digest as _, Md5};
+use picky_asn1_x509::oids;
+use picky_krb::gss_api::MechType;
+use sspi::builders::EmptyInitializeSecurityContext;
+use sspi::{
+    AuthIdentity, ClientRequestFlags, CredentialUse, DataRepresentation, Ntlm, SecurityBuffer,
+    SecurityBufferType, SecurityStatus, Sspi, SspiImpl,
+};
+
+const CLIENT_SIGNING_MAGIC: &[u8] = b"session key to client-to-server signing key magic constant\0";
+const CLIENT_SEALING_MAGIC: &[u8] = b"session key to client-to-server sealing key magic constant\0";
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NEGOTIATE_MESSAGE_TYPE: u32 = 1;
+const CHALLENGE_MESSAGE_TYPE: u32 = 2;
+const AUTHENTICATE_MESSAGE_TYPE: u32 = 3;
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ANONYMOUS: u32 = 0x0000_0800;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSION_SECURITY: u32 = 0x0008_0000;
+
+/// The account servers give guest sessions.
+const GUEST_USERNAME: &str = "Guest";
+
+/// NTLM authentication, done by sspi.
+pub(crate) struct NtlmContext {
+    ntlm: Ntlm,
+    identity: AuthIdentity,
+    credentials_handle: <Ntlm as SspiImpl>::CredentialsHandle,
+}
+
+impl NtlmContext {
+    pub(crate) fn new(username: &str, password: &str) -> Result<Self> {
+        Self::with_identity(username, password.into())
+    }
+
+    pub(crate) fn with_nt_hash(username: &str, nt_hash: [u8; 16]) -> Result<Self> {
+        Self::with_identity(username, nt_hash_password(nt_hash))
+    }
+
+    fn with_identity(username: &str, password: String) -> Result<Self> {
+        let (username, domain) = split_username(username);
+        let mut ntlm = Ntlm::new();
+        let identity = AuthIdentity {
+            username: username.into(),
+            password: password.into(),
+            domain: domain.map(String::from),
+        };
+        let credentials_handle = ntlm
+            .acquire_credentials_handle()
+            .with_credential_use(CredentialUse::Outbound)
+            .with_auth_data(&identity)
+            .execute()?
+            .credentials_handle;
+        Ok(Self {
+            ntlm,
+            identity,
+            credentials_handle,
+        })
+    }
+}
+
+impl Mechanism for NtlmContext {
+    fn oids(&self) -> Vec<MechType> {
+        vec![oids::ntlm_ssp().into()]
+    }
+
+    fn step(&mut self, input: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        let mut input_buffer = input
+            .map(|input| vec![SecurityBuffer::new(input, SecurityBufferType::Token)])
+            .unwrap_or_default();
+        let mut output_buffer = vec![SecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+
+        let mut builder =
+            EmptyInitializeSecurityContext::<<Ntlm as SspiImpl>::CredentialsHandle>::new()
+                .with_credentials_handle(&mut self.credentials_handle)
+                .with_context_requirements(
+                    ClientRequestFlags::CONFIDENTIALITY | ClientRequestFlags::ALLOCATE_MEMORY,
+                )
+                .with_target_data_representation(DataRepresentation::Native)
+                .with_target_name(&self.identity.username)
+                .with_output(&mut output_buffer);
+        if !input_buffer.is_empty() {
+            builder = builder.with_input(&mut input_buffer);
+        }
+
+        let result = self.ntlm.initialize_security_context_impl(&mut builder)?;
+        if [
+            SecurityStatus::CompleteAndContinue,
+            SecurityStatus::CompleteNeeded,
+        ]
+        .contains(&result.status)
+        {
+            self.ntlm.complete_auth_token(&mut output_buffer)?;
+        }
+
+        Ok(output_buffer.pop().unwrap().buffer)
+    }
+
+    /// The exported session key, once authentication is done.
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        let session_key = self.ntlm.session_key().ok_or_else(|| {
+            sspi::Error::new(
+                sspi::ErrorKind::InternalError,
+                "NTLM authentication didn't produce a session key",
+            )
+        })?;
+        Ok(Some(session_key.to_vec()))
+    }
+
+    /// Signs the mechanism list SPNEGO offered, proving it wasn't tampered with. This is the first
+    /// message signed with the session's keys, so it has sequence number zero.
+    fn mech_list_mic(&self, mech_types: &[u8]) -> Option<Vec<u8>> {
+        let session_key = self.ntlm.session_key()?;
+        let signing_key = Md5::new()
+            .chain_update(session_key)
+            .chain_update(CLIENT_SIGNING_MAGIC)
+            .finalize();
+        let sealing_key = Md5::new()
+            .chain_update(session_key)
+            .chain_update(CLIENT_SEALING_MAGIC)
+            .finalize();
+
+        let sequence_number = 0u32.to_le_bytes();
+        let mut mac = Hmac::<Md5>::new_from_slice(&signing_key).unwrap();
+        mac.update(&sequence_number);
+        mac.update(mech_types);
+        let checksum = rc4(&sealing_key, &mac.finalize().into_bytes()[..8]);
+
+        let mut mic = 1u32.to_le_bytes().to_vec();
+        mic.extend(checksum);
+        mic.extend(sequence_number);
+        Some(mic)
+    }
+}
+
+/// Splits a user name qualified with a domain, as either `DOMAIN\user` or `user@domain`.
+fn split_username(username: &str) -> (&str, Option<&str>) {
+    if let Some((domain, user)) = username.split_once('\\') {
+        (user, Some(domain))
+    } else if let Some((user, domain)) = username.rsplit_once('@') {
+        (user, Some(domain))
+    } else {
+        (username, None)
+    }
+}
+
+/// sspi takes an NT hash in place of the password when the password is its hex digits followed
+/// by `SSPI_CREDENTIALS_HASH_LENGTH_OFFSET` bytes of padding. It looks at the UTF-16 encoding of
+/// the password though, so each character has to encode two of the digits. None of that is sspi's
+/// API, hence the exact version in Cargo.toml.
+fn nt_hash_password(nt_hash: [u8; 16]) -> String {
+    const PADDING: usize = 512;
+    let digits: Vec<u8> = nt_hash
+        .iter()
+        .flat_map(|byte| format!("{byte:02X}").into_bytes())
+        .collect();
+    let mut password: String = digits
+        .chunks(2)
+        .map(|pair| char::from_u32(u32::from(pair[0]) | u32::from(pair[1]) << 8).unwrap())
+        .collect();
+    password.extend(std::iter::repeat_n('\0', PADDING / 2));
+    password
+}
+
+/// A guest session. The server doesn't know the guest's key, so it won't sign or check anything
+/// signed with it.
+pub(crate) struct GuestContext(NtlmContext);
+
+impl GuestContext {
+    /// Authenticates as the guest account, which has no password.
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self(NtlmContext::new(GUEST_USERNAME, "")?))
+    }
+}
+
+impl Mechanism for GuestContext {
+    fn oids(&self) -> Vec<MechType> {
+        self.0.oids()
+    }
+
+    fn step(&mut self, input: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        self.0.step(input)
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// An anonymous session (MS-NLMP 3.2.5.1.2), which sspi can't do as it insists on a user name. It
+/// sends no credentials, so all there is to it is the framing of the messages.
+#[derive(Default)]
+pub(crate) struct AnonymousContext {
+    flags: u32,
+}
+
+impl AnonymousContext {
+    fn negotiate(&mut self) -> Vec<u8> {
+        self.flags = NEGOTIATE_UNICODE
+            | REQUEST_TARGET
+            | NEGOTIATE_NTLM
+            | NEGOTIATE_ALWAYS_SIGN
+            | NEGOTIATE_EXTENDED_SESSION_SECURITY;
+
+        let mut message = SIGNATURE.to_vec();
+        message.extend(NEGOTIATE_MESSAGE_TYPE.to_le_bytes());
+        message.extend(self.flags.to_le_bytes());
+        // No domain or workstation
+        message.extend([0; 16]);
+        message
+    }
+
+    fn authenticate(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if challenge.len() < 24
+            || &challenge[..8] != SIGNATURE
+            || challenge[8..12] != CHALLENGE_MESSAGE_TYPE.to_le_bytes()
+        {
+            return Err(sspi::Error::new(
+                sspi::ErrorKind::InvalidToken,
+                "invalid challenge message",
+            )
+            .into());
+        }
+        let server_flags = u32::from_le_bytes(challenge[20..24].try_into().unwrap());
+        let flags = (self.flags & server_flags) | NEGOTIATE_ANONYMOUS;
+
+        // The LM response is a single zero byte, everything else is empty and points past it
+        const HEADER_LEN: u32 = 64;
+        let field = |len: u16, offset: u32| {
+            let mut field = len.to_le_bytes().to_vec();
+            field.extend(len.to_le_bytes());
+            field.extend(offset.to_le_bytes());
+            field
+        };
+        let mut message = SIGNATURE.to_vec();
+        message.extend(AUTHENTICATE_MESSAGE_TYPE.to_le_bytes());
+        message.extend(field(1, HEADER_LEN));
+        for _ in 0..5 {
+            message.extend(field(0, HEADER_LEN + 1));
+        }
+        message.extend(flags.to_le_bytes());
+        message.push(0);
+        Ok(message)
+    }
+}
+
+impl Mechanism for AnonymousContext {
+    fn oids(&self) -> Vec<MechType> {
+        vec![oids::ntlm_ssp().into()]
+    }
+
+    fn step(&mut self, input: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        match input {
+            None => Ok(self.negotiate()),
+            Some(challenge) => self.authenticate(&challenge),
+        }
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            let k = state[state[i as usize].wrapping_add(state[j as usize]) as usize];
+            byte ^ k
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,108 @@
+use super::*;
+use md4::Md4;
+use sspi::{AcceptSecurityContextResult, ServerRequestFlags};
+
+#[test]
+fn usernames_with_domains() {
+    assert_eq!(split_username("alice"), ("alice", None));
+    assert_eq!(split_username("CORP\\alice"), ("alice", Some("CORP")));
+    assert_eq!(
+        split_username("alice@corp.example.com"),
+        ("alice", Some("corp.example.com"))
+    );
+}
+
+/// Runs the client against sspi's server side, which knows the user's password. The server checks
+/// the MIC of the client's messages, which is keyed by what the client derived from its secret.
+fn authenticate(
+    client: &mut NtlmContext,
+    username: &str,
+    domain: &str,
+    password: &str,
+) -> sspi::Result<SecurityStatus> {
+    let mut server = Ntlm::new();
+    let identity = AuthIdentity {
+        username: username.into(),
+        password: String::from(password).into(),
+        domain: Some(domain.into()),
+    };
+    let mut credentials_handle = server
+        .acquire_credentials_handle()
+        .with_credential_use(CredentialUse::Inbound)
+        .with_auth_data(&identity)
+        .execute()
+        .unwrap()
+        .credentials_handle;
+
+    let mut input = client.step(None).unwrap();
+    loop {
+        let mut input_buffer = vec![SecurityBuffer::new(input, SecurityBufferType::Token)];
+        let mut output_buffer = vec![SecurityBuffer::new(Vec::new(), SecurityBufferType::Token)];
+        let AcceptSecurityContextResult { status, .. } = server
+            .accept_security_context()
+            .with_credentials_handle(&mut credentials_handle)
+            .with_context_requirements(ServerRequestFlags::ALLOCATE_MEMORY)
+            .with_target_data_representation(DataRepresentation::Native)
+            .with_input(&mut input_buffer)
+            .with_output(&mut output_buffer)
+            .execute()
+            .unwrap();
+        if status != SecurityStatus::ContinueNeeded {
+            break;
+        }
+        input = client
+            .step(Some(output_buffer.pop().unwrap().buffer))
+            .unwrap();
+    }
+    server.complete_auth_token(&mut [])
+}
+
+fn nt_hash(password: &str) -> [u8; 16] {
+    let utf16: Vec<u8> = password
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    Md4::digest(utf16).into()
+}
+
+/// Logging in with an NT hash relies on how sspi recognises one (see `nt_hash_password`), which
+/// isn't part of its API. The version of sspi is pinned for it, and this catches it changing.
+#[test]
+fn nt_hash_authenticates_like_the_password() {
+    let mut client = NtlmContext::with_nt_hash("CORP\\alice", nt_hash("hunter2")).unwrap();
+    assert!(authenticate(&mut client, "alice", "CORP", "hunter2").is_ok());
+
+    let mut client = NtlmContext::with_nt_hash("CORP\\alice", nt_hash("hunter3")).unwrap();
+    assert!(authenticate(&mut client, "alice", "CORP", "hunter2").is_err());
+}
+
+#[test]
+fn anonymous_authenticate_message() {
+    let mut context = AnonymousContext::default();
+    let negotiate = context.step(None).unwrap();
+    assert_eq!(&negotiate[..8], SIGNATURE);
+
+    let server_flags = NEGOTIATE_UNICODE | NEGOTIATE_NTLM | 0x2000_0000;
+    let mut challenge = SIGNATURE.to_vec();
+    challenge.extend(CHALLENGE_MESSAGE_TYPE.to_le_bytes());
+    challenge.extend([0; 8]);
+    challenge.extend(server_flags.to_le_bytes());
+    challenge.extend([0x11; 8]);
+    let authenticate = context.step(Some(challenge)).unwrap();
+
+    let field = |offset: usize| {
+        let len = u16::from_le_bytes(authenticate[offset..offset + 2].try_into().unwrap());
+        let start = u32::from_le_bytes(authenticate[offset + 4..offset + 8].try_into().unwrap());
+        &authenticate[start as usize..start as usize + len as usize]
+    };
+    assert_eq!(field(12), [0], "LM response");
+    for offset in [20, 28, 36, 44, 52] {
+        assert!(field(offset).is_empty());
+    }
+    let flags = u32::from_le_bytes(authenticate[60..64].try_into().unwrap());
+    assert_eq!(
+        flags,
+        NEGOTIATE_UNICODE | NEGOTIATE_NTLM | NEGOTIATE_ANONYMOUS
+    );
+    assert_eq!(context.session_key().unwrap(), None);
+}
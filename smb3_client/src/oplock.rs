@@ -0,0 +1,358 @@
+//! Oplocks and leases, which let us cache a file until the server breaks them because someone
+//! else opened it.
+
+use crate::{open_request, spawn_cleanup, Break, BreakKey, Client, Result, Transport};
+use futures::future::BoxFuture;
+use rand::rngs::OsRng;
+use rand::Rng as _;
+use smb3::{
+    Capabilities, CloseResponse, CreateContext, CreateResponse, Credits, Dialect,
+    DurableHandleFlags, DurableHandleRequestV2, DurableHandleResponseV2, FileId,
+    LeaseBreakAcknowledgment, LeaseBreakFlags, LeaseFlags, LeaseKey, LeaseState, OplockBreak,
+    OplockLevel, RequestLease, RequestLeaseV1, Uuid,
+};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+
+/// Runs when the server breaks an oplock or lease, with the caching state it is broken to, and
+/// is done with before the break is acknowledged.
+pub(crate) type BreakHandler =
+    Arc<dyn Fn(FileId, LeaseState) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// What to ask the server to let us cache when opening a file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CachingRequest {
+    Oplock(OplockLevel),
+    /// Servers that don't do leases get asked for the oplock that caches the same.
+    Lease(LeaseState),
+}
+
+/// A file opened with an oplock or a lease. Breaks are acknowledged as they arrive, and what we
+/// may still cache is in `caching_state`. It is closed when dropped, without waiting for the
+/// server to confirm it, or by `close` which does wait.
+pub struct LeasedFile<TransportT: Transport> {
+    client: Client<TransportT>,
+    file_id: FileId,
+    lease_key: Option<LeaseKey>,
+    state: watch::Receiver<LeaseState>,
+    durable: Option<DurableHandleFlags>,
+    open: bool,
+}
+
+impl<TransportT: Transport> LeasedFile<TransportT> {
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    /// The lease key, if the server granted a lease rather than an oplock.
+    pub fn lease_key(&self) -> Option<LeaseKey> {
+        self.lease_key
+    }
+
+    /// Whether the handle is reopened when the session reconnects.
+    pub fn is_durable(&self) -> bool {
+        self.durable.is_some()
+    }
+
+    /// Whether the handle survives the server failing over, on a continuously available share.
+    pub fn is_persistent(&self) -> bool {
+        self.durable
+            .is_some_and(|flags| flags.contains(DurableHandleFlags::PERSISTENT))
+    }
+
+    /// What we may cache. Oplocks are described by the lease state caching the same: a batch
+    /// oplock by read, write and handle caching, an exclusive one by read and write caching and
+    /// a level II one by read caching.
+    pub fn caching_state(&self) -> LeaseState {
+        *self.state.borrow()
+    }
+
+    /// Watches the caching state, which changes when the server breaks the oplock or lease.
+    pub fn caching_changes(&self) -> watch::Receiver<LeaseState> {
+        self.state.clone()
+    }
+
+    pub async fn close(mut self) -> Result<CloseResponse> {
+        self.open = false;
+        self.unregister();
+        self.client.close(self.file_id).await
+    }
+
+    fn unregister(&self) {
+        let key = match self.lease_key {
+            Some(lease_key) => BreakKey::Lease(lease_key),
+            None => BreakKey::File(self.file_id),
+        };
+        self.client
+            .auth_client
+            .channel()
+            .unauth_client
+            .connection
+            .unregister_breaks(key);
+    }
+}
+
+impl<TransportT: Transport> Drop for LeasedFile<TransportT> {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        self.unregister();
+        let client = self.client.clone();
+        let file_id = self.file_id;
+        spawn_cleanup(async move { client.close(file_id).await.map(drop) });
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Opens a file for reading and writing like `look_up`, asking to cache it. The server may
+    /// grant less than was asked for, or nothing at all.
+    pub async fn open_with_caching(
+        &self,
+        path: impl AsRef<Path>,
+        caching: CachingRequest,
+    ) -> Result<LeasedFile<TransportT>> {
+        self.open_with_break_handler(path, caching, false, None)
+            .await
+    }
+
+    /// Opens a file like `open_with_caching`, with a durable handle that `Session::reconnect`
+    /// reopens after the connection is lost. On continuously available shares the handle is
+    /// persistent if the server supports it. Servers before SMB 3.0 open it like any other.
+    pub async fn open_durable(
+        &self,
+        path: impl AsRef<Path>,
+        caching: CachingRequest,
+    ) -> Result<LeasedFile<TransportT>> {
+        self.open_with_break_handler(path, caching, true, None)
+            .await
+    }
+
+    pub(crate) async fn open_with_break_handler(
+        &self,
+        path: impl AsRef<Path>,
+        caching: CachingRequest,
+        durable: bool,
+        on_break: Option<BreakHandler>,
+    ) -> Result<LeasedFile<TransportT>> {
+        let channel = self.auth_client.channel();
+        let connection = &channel.unauth_client.connection;
+        let mut request = open_request(path);
+        let (breaks, receiver) = mpsc::unbounded_channel();
+
+        let info = self.negotiate_info();
+        let leasing = info.capabilities.contains(Capabilities::LEASING);
+        if durable && info.dialect >= Dialect::Smb3_0 {
+            let persistent = self.tree.info.is_continuously_available()
+                && info.capabilities.contains(Capabilities::PERSISTENT_HANDLES);
+            request.create_contexts.push(
+                CreateContext::DurableHandleRequestV2(DurableHandleRequestV2 {
+                    // The server's default
+                    timeout: 0,
+                    flags: if persistent {
+                        DurableHandleFlags::PERSISTENT
+                    } else {
+                        DurableHandleFlags::empty()
+                    },
+                    create_guid: Uuid::new(&mut OsRng),
+                })
+                .into(),
+            );
+        }
+        let (response, lease_key) = match caching {
+            CachingRequest::Lease(lease_state) if leasing => {
+                let lease_key = LeaseKey(OsRng.gen());
+                request.requested_oplock_level = OplockLevel::Lease;
+                // Version 2 leases came with SMB 3.0
+                let context = if info.dialect < Dialect::Smb3_0 {
+                    CreateContext::RequestLeaseV1(RequestLeaseV1 {
+                        lease_key,
+                        lease_state,
+                        flags: LeaseFlags::empty(),
+                    })
+                } else {
+                    CreateContext::RequestLease(RequestLease {
+                        lease_key,
+                        lease_state,
+                        flags: LeaseFlags::empty(),
+                        parent_lease_key: LeaseKey::default(),
+                        epoch: 0,
+                    })
+                };
+                request.create_contexts.push(context.into());
+                // The lease key is ours, so breaks can be delivered before the response arrives
+                connection.register_breaks(BreakKey::Lease(lease_key), breaks.clone());
+                let response: Result<(_, CreateResponse)> = self
+                    .auth_client
+                    .request(Some(self.tree.id), Credits(1), request.clone())
+                    .await;
+                let response = match response {
+                    Ok((_, response)) => response,
+                    Err(error) => {
+                        connection.unregister_breaks(BreakKey::Lease(lease_key));
+                        return Err(error);
+                    }
+                };
+                (response, Some(lease_key))
+            }
+            caching => {
+                request.requested_oplock_level = match caching {
+                    CachingRequest::Oplock(oplock_level) => oplock_level,
+                    CachingRequest::Lease(lease_state) => lease_oplock_level(lease_state),
+                };
+                let _oplock_open = connection.oplock_open();
+                let (_, response): (_, CreateResponse) = self
+                    .auth_client
+                    .request(Some(self.tree.id), Credits(1), request.clone())
+                    .await?;
+                connection.register_breaks(BreakKey::File(response.file_id), breaks.clone());
+                (response, None)
+            }
+        };
+        // A server asked for a lease may grant an oplock after all, which breaks by handle
+        let granted = match response.oplock_level {
+            OplockLevel::Lease => response.lease().map(|lease| {
+                (
+                    lease_key,
+                    lease.map_or(LeaseState::LEASE_NONE, |lease| lease.lease_state),
+                )
+            }),
+            oplock_level => {
+                if let Some(lease_key) = lease_key {
+                    connection.unregister_breaks(BreakKey::Lease(lease_key));
+                    connection.register_breaks(BreakKey::File(response.file_id), breaks);
+                }
+                Ok((None, oplock_lease_state(oplock_level)))
+            }
+        };
+        let (lease_key, lease_state) = match granted {
+            Ok(granted) => granted,
+            Err(error) => {
+                // We can't tell what we may cache, so the handle is no use
+                if let Some(lease_key) = lease_key {
+                    connection.unregister_breaks(BreakKey::Lease(lease_key));
+                }
+                let _ = self.close(response.file_id).await;
+                return Err(error.into());
+            }
+        };
+
+        let durable = response
+            .create_context(b"DH2Q")
+            .and_then(|data| serde_smb::from_slice::<DurableHandleResponseV2>(data).ok())
+            .map(|durable| durable.flags);
+        if durable.is_some() {
+            self.auth_client
+                .track_durable_open(self.tree.id, response.file_id, request);
+        } else {
+            self.auth_client.track_open(self.tree.id, response.file_id);
+        }
+
+        let (state_sender, state) = watch::channel(lease_state);
+        tokio::spawn(handle_breaks(
+            self.clone(),
+            response.file_id,
+            lease_key,
+            state_sender,
+            receiver,
+            on_break,
+        ));
+
+        Ok(LeasedFile {
+            client: self.clone(),
+            file_id: response.file_id,
+            lease_key,
+            state,
+            durable,
+            open: true,
+        })
+    }
+}
+
+/// Updates the caching state of a handle as breaks arrive, lets the handler give up what is no
+/// longer cached, and acknowledges them. This runs until the handle is closed.
+async fn handle_breaks<TransportT: Transport>(
+    client: Client<TransportT>,
+    file_id: FileId,
+    lease_key: Option<LeaseKey>,
+    state: watch::Sender<LeaseState>,
+    mut breaks: mpsc::UnboundedReceiver<Break>,
+    on_break: Option<BreakHandler>,
+) {
+    let handle_break = |new_state| {
+        state.send_replace(new_state);
+        let handled = on_break
+            .as_ref()
+            .map(|on_break| on_break(file_id, new_state));
+        async move {
+            if let Some(handled) = handled {
+                handled.await;
+            }
+        }
+    };
+    while let Some(notification) = breaks.recv().await {
+        // If the acknowledgment fails the server gives up waiting for it and breaks the oplock or
+        // lease anyway
+        match notification {
+            Break::Oplock(notification) => {
+                let previous = *state.borrow();
+                handle_break(oplock_lease_state(notification.oplock_level)).await;
+                // Level II oplocks are broken without waiting for us
+                if previous.contains(LeaseState::WRITE_CACHING) {
+                    let _: Result<(_, OplockBreak)> = client
+                        .file_request(
+                            file_id,
+                            OplockBreak {
+                                oplock_level: notification.oplock_level,
+                                file_id,
+                            },
+                        )
+                        .await;
+                }
+            }
+            Break::Lease(notification) => {
+                let Some(lease_key) = lease_key else { continue };
+                handle_break(notification.new_lease_state).await;
+                if notification.flags.contains(LeaseBreakFlags::ACK_REQUIRED) {
+                    let _: Result<(_, LeaseBreakAcknowledgment)> = client
+                        .auth_client
+                        .request(
+                            Some(client.tree.id),
+                            Credits(1),
+                            LeaseBreakAcknowledgment {
+                                lease_key,
+                                lease_state: notification.new_lease_state,
+                            },
+                        )
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+/// The lease state that caches what the oplock does.
+fn oplock_lease_state(oplock_level: OplockLevel) -> LeaseState {
+    match oplock_level {
+        OplockLevel::Batch => {
+            LeaseState::READ_CACHING | LeaseState::WRITE_CACHING | LeaseState::HANDLE_CACHING
+        }
+        OplockLevel::Exclusive => LeaseState::READ_CACHING | LeaseState::WRITE_CACHING,
+        OplockLevel::II => LeaseState::READ_CACHING,
+        OplockLevel::None | OplockLevel::Lease => LeaseState::LEASE_NONE,
+    }
+}
+
+/// The oplock that caches the most of what the lease would without caching more.
+fn lease_oplock_level(lease_state: LeaseState) -> OplockLevel {
+    if !lease_state.contains(LeaseState::READ_CACHING) {
+        OplockLevel::None
+    } else if !lease_state.contains(LeaseState::WRITE_CACHING) {
+        OplockLevel::II
+    } else if !lease_state.contains(LeaseState::HANDLE_CACHING) {
+        OplockLevel::Exclusive
+    } else {
+        OplockLevel::Batch
+    }
+}
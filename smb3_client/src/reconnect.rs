@@ -0,0 +1,193 @@
+//! Setting the session up again when the connection to the server is lost, and retrying what
+//! failed along with it.
+
+use crate::{Authenticator, Client, ClientOptions, Error, Result, Session, Transport};
+use futures::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use smb3::{FileId, HasFileInformationClass};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Makes what it takes to set up a session with the server, every time it needs setting up.
+pub trait Connector: Send + Sync + 'static {
+    type Transport: Transport;
+
+    /// Opens a new transport to the server.
+    fn connect(&self) -> BoxFuture<'_, Result<Self::Transport>>;
+
+    /// An authenticator for the user the session is for. Each one is used for a single session
+    /// setup.
+    fn authenticator(&self) -> BoxFuture<'_, Result<Box<dyn Authenticator>>>;
+}
+
+/// How hard `ReconnectingClient` tries before giving up.
+#[derive(Clone, Debug)]
+pub struct RetryOptions {
+    /// How many times an operation is retried, each after trying to reconnect.
+    pub max_retries: u32,
+    /// How long to wait before the first attempt to reconnect. It doubles with each attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A client on a share that sets the session up again when the connection is lost, and retries
+/// the operation that failed with it. Handles opened with `open_durable` survive reconnecting,
+/// so operations on them can be retried too. Other handles are closed by the server along with
+/// the connection.
+///
+/// Only operations that can safely be done twice are retried, since the server may have done them
+/// before the connection was lost. Clones share the connection, and reconnect it only once when
+/// it is lost while several of them are using it.
+pub struct ReconnectingClient<ConnectorT: Connector> {
+    connector: Arc<ConnectorT>,
+    client: Client<ConnectorT::Transport>,
+    retry_options: RetryOptions,
+    /// How many times the session was set up again.
+    reconnections: Arc<Mutex<u64>>,
+}
+
+impl<ConnectorT: Connector> Clone for ReconnectingClient<ConnectorT> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            client: self.client.clone(),
+            retry_options: self.retry_options.clone(),
+            reconnections: self.reconnections.clone(),
+        }
+    }
+}
+
+impl<ConnectorT: Connector> ReconnectingClient<ConnectorT> {
+    /// Sets up a session and connects to the share. This isn't retried.
+    pub async fn connect(
+        connector: ConnectorT,
+        path: &str,
+        options: ClientOptions,
+        retry_options: RetryOptions,
+    ) -> Result<Self> {
+        let transport = connector.connect().await?;
+        let authenticator = connector.authenticator().await?;
+        let session = Session::with_options(transport, authenticator, options).await?;
+        let client = session.tree_connect(path).await?;
+        Ok(Self {
+            connector: Arc::new(connector),
+            client,
+            retry_options,
+            reconnections: Arc::default(),
+        })
+    }
+
+    /// The client on the share, for what shouldn't be retried. It keeps working after
+    /// reconnecting.
+    pub fn client(&self) -> &Client<ConnectorT::Transport> {
+        &self.client
+    }
+
+    /// Runs the operation, and if the connection is lost while it does, reconnects and runs it
+    /// again. It must be safe to run more than once. When it is run again, requests on durable
+    /// handles are marked as replays.
+    ///
+    /// Attempts to reconnect back off as configured, and stop at errors other than I/O errors,
+    /// such as the server refusing the credentials.
+    pub async fn retry<T, F: Future<Output = Result<T>>>(
+        &self,
+        operation: impl Fn(Client<ConnectorT::Transport>) -> F,
+    ) -> Result<T> {
+        let mut retries = 0;
+        let mut backoff = self.retry_options.initial_backoff;
+        let mut client = self.client.clone();
+        loop {
+            let reconnections = *self.reconnections.lock().await;
+            let error = match operation(client.clone()).await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if retries == self.retry_options.max_retries {
+                return Err(error);
+            }
+            retries += 1;
+            client = self.client.replaying();
+
+            let mut current = self.reconnections.lock().await;
+            // Someone else may have reconnected since, in which case it failed on the old
+            // connection
+            if *current == reconnections {
+                if self.client.session().is_connected() {
+                    return Err(error);
+                }
+                loop {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry_options.max_backoff);
+                    match self.reconnect().await {
+                        Ok(()) => break,
+                        Err(Error::Io(_)) if retries < self.retry_options.max_retries => {
+                            retries += 1;
+                        }
+                        Err(error) => return Err(error),
+                    }
+                }
+                *current += 1;
+            }
+        }
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        let transport = self.connector.connect().await?;
+        let authenticator = self.connector.authenticator().await?;
+        let session = self.client.session();
+        match session.reconnect(transport, authenticator).await {
+            // Durable handles that couldn't be reopened fail when they are next used
+            Err(_) if session.is_connected() => Ok(()),
+            result => result,
+        }
+    }
+
+    pub async fn read(&self, file_id: FileId, offset: u64, count: u32) -> Result<Vec<u8>> {
+        self.retry(|client| async move { client.read(file_id, offset, count).await })
+            .await
+    }
+
+    /// Writes as much of the data as fits in one request, returning how much was written.
+    pub async fn write(&self, file_id: FileId, offset: u64, data: Vec<u8>) -> Result<u32> {
+        self.retry(|client| {
+            let data = data.clone();
+            async move { client.write(file_id, offset, data).await }
+        })
+        .await
+    }
+
+    pub async fn flush(&self, file_id: FileId) -> Result<()> {
+        self.retry(|client| async move { client.flush(file_id).await })
+            .await
+    }
+
+    pub async fn query_info<Info: DeserializeOwned + HasFileInformationClass>(
+        &self,
+        file_id: FileId,
+    ) -> Result<Info> {
+        self.retry(|client| async move { client.query_info(file_id).await })
+            .await
+    }
+
+    pub async fn query_info_path<Info: DeserializeOwned + HasFileInformationClass>(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Info> {
+        let path = path.as_ref();
+        self.retry(|client| async move { client.query_info_path(path).await })
+            .await
+    }
+}
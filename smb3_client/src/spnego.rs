@@ -0,0 +1,193 @@
+//! SPNEGO (RFC 4178) picks the security mechanism that authenticates a session and carries that
+//! mechanism's messages wrapped in its own.
+
+use crate::{Authenticator, Error, Result};
+use picky_asn1::wrapper::{
+    Asn1SequenceOf, BitStringAsn1, ExplicitContextTag0, ExplicitContextTag1, ExplicitContextTag2,
+    ExplicitContextTag3, ExplicitContextTag4, ObjectIdentifierAsn1, OctetStringAsn1, Optional,
+};
+use picky_asn1_der::Asn1RawDer;
+use picky_asn1_x509::oids;
+use picky_krb::gss_api::{
+    ApplicationTag0, GssApiNegInit, MechType, MechTypeList, NegTokenInit, NegTokenTarg,
+    NegTokenTarg1,
+};
+use serde::Deserialize;
+
+/// What servers send in the negotiate response as a hint at the mechanisms they support. Unlike a
+/// NegTokenInit it has negHints, which is why the mechListMIC comes after them.
+#[derive(Deserialize)]
+struct NegTokenInit2 {
+    #[serde(default)]
+    mech_types: Optional<Option<ExplicitContextTag0<MechTypeList>>>,
+    #[serde(default)]
+    _req_flags: Optional<Option<ExplicitContextTag1<BitStringAsn1>>>,
+    #[serde(default)]
+    _mech_token: Optional<Option<ExplicitContextTag2<OctetStringAsn1>>>,
+    #[serde(default)]
+    _neg_hints: Optional<Option<ExplicitContextTag3<Asn1RawDer>>>,
+    #[serde(default)]
+    _mech_list_mic: Optional<Option<ExplicitContextTag4<OctetStringAsn1>>>,
+}
+
+#[derive(Deserialize)]
+struct GssApiNegInit2 {
+    _oid: ObjectIdentifierAsn1,
+    neg_token_init: ExplicitContextTag0<NegTokenInit2>,
+}
+
+/// Splits a DER encoded value into its tag, its contents and whatever follows it.
+pub(crate) fn split_der(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = bytes.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let len_len = (first & 0x7f) as usize;
+        if len_len > rest.len() || len_len > std::mem::size_of::<usize>() {
+            return None;
+        }
+        let (len_bytes, rest) = rest.split_at(len_len);
+        let len = len_bytes
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+        (len, rest)
+    };
+    (len <= rest.len()).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The mechanisms the server says it supports in its negotiate response, which may be none if it
+/// didn't give a hint.
+pub(crate) fn server_mechanisms(security_blob: &[u8]) -> Result<Vec<MechType>> {
+    if security_blob.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // The token is an [APPLICATION 0] holding what is otherwise laid out like a SEQUENCE
+    let mut token = security_blob.to_vec();
+    token[0] = 0x30;
+    let token: GssApiNegInit2 = picky_asn1_der::from_bytes(&token)?;
+    Ok(token
+        .neg_token_init
+        .0
+        .mech_types
+        .0
+        .map(|mech_types| mech_types.0 .0)
+        .unwrap_or_default())
+}
+
+/// The first token we send, offering the mechanisms and carrying the first message of the one we
+/// prefer.
+pub(crate) fn neg_token_init(mech_types: &MechTypeList, mech_token: Vec<u8>) -> Result<Vec<u8>> {
+    Ok(picky_asn1_der::to_vec(&ApplicationTag0(GssApiNegInit {
+        oid: oids::spnego().into(),
+        neg_token_init: ExplicitContextTag0::from(NegTokenInit {
+            mech_types: Optional::from(Some(ExplicitContextTag0::from(mech_types.clone()))),
+            req_flags: Optional::from(None),
+            mech_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(
+                mech_token,
+            )))),
+            mech_list_mic: Optional::from(None),
+        }),
+    }))?)
+}
+
+/// The tokens we send after the first one.
+pub(crate) fn neg_token_resp(
+    response_token: Vec<u8>,
+    mech_list_mic: Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    Ok(picky_asn1_der::to_vec(&NegTokenTarg1::from(
+        NegTokenTarg {
+            neg_result: Optional::from(None),
+            supported_mech: Optional::from(None),
+            response_token: Optional::from(Some(ExplicitContextTag2::from(OctetStringAsn1::from(
+                response_token,
+            )))),
+            mech_list_mic: Optional::from(
+                mech_list_mic.map(|mic| ExplicitContextTag3::from(OctetStringAsn1::from(mic))),
+            ),
+        },
+    ))?)
+}
+
+/// The mechanism's message inside a token the server sent us, if there is one.
+pub(crate) fn response_token(security_blob: &[u8]) -> Result<Option<Vec<u8>>> {
+    if security_blob.is_empty() {
+        return Ok(None);
+    }
+    let token: NegTokenTarg1 = picky_asn1_der::from_bytes(security_blob)?;
+    Ok(token.0.response_token.0.map(|token| token.0 .0))
+}
+
+/// A security mechanism SPNEGO can negotiate.
+pub(crate) trait Mechanism: Send {
+    /// The OIDs servers list the mechanism under. We offer it under the first one.
+    fn oids(&self) -> Vec<MechType>;
+
+    /// Produces the next message to send the server, given the one it last sent us.
+    fn step(&mut self, input: Option<Vec<u8>>) -> Result<Vec<u8>>;
+
+    /// Checks the last message the server sent along with its final response.
+    fn finish(&mut self, _input: Option<Vec<u8>>) -> Result<()> {
+        Ok(())
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Signs the DER encoded mechanism list we offered, if the mechanism does that.
+    fn mech_list_mic(&self, _mech_types: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Authenticates with a single mechanism, wrapping its messages in SPNEGO tokens.
+pub(crate) struct Spnego {
+    mechanism: Box<dyn Mechanism>,
+    mech_types: MechTypeList,
+    started: bool,
+}
+
+impl Spnego {
+    pub(crate) fn new(mechanism: impl Mechanism + 'static) -> Self {
+        let mech_types = Asn1SequenceOf::from(vec![mechanism.oids().swap_remove(0)]);
+        Self {
+            mechanism: Box::new(mechanism),
+            mech_types,
+            started: false,
+        }
+    }
+}
+
+impl Authenticator for Spnego {
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>> {
+        if !self.started {
+            self.started = true;
+            let server_mechanisms = server_mechanisms(security_blob)?;
+            let supported = server_mechanisms.is_empty()
+                || self
+                    .mechanism
+                    .oids()
+                    .iter()
+                    .any(|oid| server_mechanisms.contains(oid));
+            if !supported {
+                return Err(Error::UnsupportedMechanism);
+            }
+            return neg_token_init(&self.mech_types, self.mechanism.step(None)?);
+        }
+
+        let mech_token = self.mechanism.step(response_token(security_blob)?)?;
+        let mech_list_mic = self
+            .mechanism
+            .mech_list_mic(&picky_asn1_der::to_vec(&self.mech_types)?);
+        neg_token_resp(mech_token, mech_list_mic)
+    }
+
+    fn finish(&mut self, security_blob: &[u8]) -> Result<()> {
+        self.mechanism.finish(response_token(security_blob)?)
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        self.mechanism.session_key()
+    }
+}
@@ -0,0 +1,2358 @@
+use super::*;
+use futures::future::{BoxFuture, FutureExt as _};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+#[test]
+fn credit_charge_covers_payload() {
+    assert_eq!(credit_charge(0), Credits(1));
+    assert_eq!(credit_charge(1), Credits(1));
+    assert_eq!(credit_charge(CREDIT_PAYLOAD_SIZE), Credits(1));
+    assert_eq!(credit_charge(CREDIT_PAYLOAD_SIZE + 1), Credits(2));
+    assert_eq!(credit_charge(MAX_IO_SIZE), Credits(128));
+}
+
+#[test]
+fn credit_manager_window() {
+    let mut credits = CreditManager::new();
+    assert_eq!(credits.acquire(&[Credits(0)]).unwrap(), [MessageId(0)]);
+    assert!(matches!(
+        credits.acquire(&[Credits(1)]),
+        Err(Error::InsufficientCredits)
+    ));
+
+    credits.grant(Credits(10));
+    assert_eq!(credits.max_payload_size(), 10 * CREDIT_PAYLOAD_SIZE);
+    assert_eq!(credits.acquire(&[Credits(4)]).unwrap(), [MessageId(1)]);
+    assert_eq!(
+        credits.acquire(&[Credits(1), Credits(2)]).unwrap(),
+        [MessageId(5), MessageId(6)]
+    );
+    assert!(matches!(
+        credits.acquire(&[Credits(1), Credits(3)]),
+        Err(Error::InsufficientCredits)
+    ));
+    assert_eq!(credits.credits_to_request(Credits(2)), Credits(511));
+}
+
+#[test]
+fn credit_manager_reuses_message_ids_given_back() {
+    let mut credits = CreditManager::new();
+    credits.grant(Credits(9));
+    assert_eq!(
+        credits
+            .acquire(&[Credits(2), Credits(3), Credits(1)])
+            .unwrap(),
+        [MessageId(0), MessageId(2), MessageId(5)]
+    );
+
+    // Given back in the middle, they are used again by requests that fit
+    credits.release(MessageId(2), Credits(3));
+    assert_eq!(credits.available, 7);
+    assert_eq!(credits.max_payload_size(), 4 * CREDIT_PAYLOAD_SIZE);
+    assert_eq!(
+        credits.acquire(&[Credits(4), Credits(2)]).unwrap(),
+        [MessageId(6), MessageId(2)]
+    );
+    assert_eq!(credits.available, 1);
+
+    // Given back at the end, the next message id goes back
+    credits.release(MessageId(6), Credits(4));
+    assert_eq!(credits.next_message_id, MessageId(6));
+    assert_eq!(credits.returned, [(MessageId(4), 1)]);
+    assert_eq!(credits.acquire(&[Credits(4)]).unwrap(), [MessageId(6)]);
+    assert!(matches!(
+        credits.acquire(&[Credits(2)]),
+        Err(Error::InsufficientCredits)
+    ));
+    assert_eq!(credits.acquire(&[Credits(1)]).unwrap(), [MessageId(4)]);
+    assert!(credits.returned.is_empty());
+}
+
+#[test]
+fn encryption_round_trip() {
+    let session_key = [0x42; 16];
+    let pre_auth_hash = [0x24; 64];
+    let message: Vec<u8> = (0..200).map(|v| v as u8).collect();
+
+    for cipher in [
+        CipherId::Aes128Ccm,
+        CipherId::Aes128Gcm,
+        CipherId::Aes256Ccm,
+        CipherId::Aes256Gcm,
+    ] {
+        let client = Encryption::new(Dialect::Smb3_1_1, cipher, &session_key, &pre_auth_hash);
+        let server = Encryption {
+            cipher,
+            encryption_key: client.decryption_key.clone(),
+            decryption_key: client.encryption_key.clone(),
+        };
+
+        let mut encrypted = client.encrypt(SessionId(0x11), message.clone()).unwrap();
+        assert_eq!(encrypted.len(), TRANSFORM_HEADER_SIZE + message.len());
+        assert_ne!(&encrypted[TRANSFORM_HEADER_SIZE..], &message[..]);
+        assert_eq!(server.decrypt(&encrypted).unwrap(), message);
+
+        // Tampering with the associated data must be detected
+        encrypted[TRANSFORM_HEADER_SIZE - 1] ^= 1;
+        assert!(matches!(
+            server.decrypt(&encrypted),
+            Err(Error::DecryptionFailed)
+        ));
+    }
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// The session key of the example in "Encryption in SMB 3.0: A protocol perspective", on the
+/// Microsoft Open Specifications blog.
+const EXAMPLE_SESSION_KEY: &str = "b4546771b515f766a86735532dd6c4f0";
+
+#[test]
+fn smb3_0_keys_match_the_published_example() {
+    let session_key = hex(EXAMPLE_SESSION_KEY);
+    for dialect in [Dialect::Smb3_0, Dialect::Smb3_0_2] {
+        // 3.0 doesn't have a preauth integrity hash
+        let encryption = Encryption::new(dialect, CipherId::Aes128Ccm, &session_key, &[]);
+        assert_eq!(
+            encryption.encryption_key,
+            hex("261b72350558f2e9dcf613070383edbf")
+        );
+        assert_eq!(
+            encryption.decryption_key,
+            hex("8fe2b57ec34d2db5b1a9727f526bbdb5")
+        );
+        // Derived with the "SMB2AESCMAC" label and "SmbSign" context
+        let signing = Signing::new(dialect, SigningAlgorithmId::AesCmac, &session_key, &[]);
+        assert_eq!(signing.key, hex("f773cd23c18fd1e08ee510cada7cf852"));
+    }
+}
+
+#[test]
+fn smb3_1_1_keys_are_derived_from_the_pre_auth_hash() {
+    let session_key = hex(EXAMPLE_SESSION_KEY);
+    let pre_auth_hash: Vec<u8> = (0..64).collect();
+    let encryption = Encryption::new(
+        Dialect::Smb3_1_1,
+        CipherId::Aes128Gcm,
+        &session_key,
+        &pre_auth_hash,
+    );
+    assert_eq!(
+        encryption.encryption_key,
+        hex("800089edf82aaaea7cf33a454b014260")
+    );
+    assert_eq!(
+        encryption.decryption_key,
+        hex("fa4938790cdbdd18ad2f1c84091ce6ec")
+    );
+    let signing = Signing::new(
+        Dialect::Smb3_1_1,
+        SigningAlgorithmId::AesCmac,
+        &session_key,
+        &pre_auth_hash,
+    );
+    assert_eq!(signing.key, hex("057fbe6337545d2b1dd14adef3de69cf"));
+
+    // The 3.0 labels give other keys for the same session key
+    let smb3_0 = Encryption::new(Dialect::Smb3_0, CipherId::Aes128Gcm, &session_key, &[]);
+    assert_ne!(encryption.encryption_key, smb3_0.encryption_key);
+    assert_ne!(encryption.decryption_key, smb3_0.decryption_key);
+}
+
+/// A flush with message id 0x0102030405060708, without its signature.
+fn signing_test_message(command: Command, flags: HeaderFlags) -> Vec<u8> {
+    let mut message = b"\xfeSMB".to_vec();
+    message.extend(64u16.to_le_bytes());
+    message.extend(1u16.to_le_bytes());
+    message.extend(0u32.to_le_bytes());
+    message.extend((command as u16).to_le_bytes());
+    message.extend(1u16.to_le_bytes());
+    message.extend(flags.into_bytes());
+    message.extend(0u32.to_le_bytes());
+    message.extend(0x0102030405060708u64.to_le_bytes());
+    message.extend(0u32.to_le_bytes());
+    message.extend(1u32.to_le_bytes());
+    message.extend(0x11u64.to_le_bytes());
+    message.extend([0; 16]);
+    message.extend(0..24);
+    message
+}
+
+#[test]
+fn signatures_match_known_answers() {
+    let session_key = hex(EXAMPLE_SESSION_KEY);
+    let message = signing_test_message(Command::Flush, HeaderFlags::new());
+
+    // 2.x signs with HMAC-SHA256 of the session key itself, truncated to 16 bytes
+    for dialect in [Dialect::Smb2_0_2, Dialect::Smb2_1] {
+        let signing = Signing::new(dialect, SigningAlgorithmId::HmacSha256, &session_key, &[]);
+        assert_eq!(
+            signing.sign(&message).0.to_vec(),
+            hex("82dd294b7e95f2b3a0cc8c4a7080cabf")
+        );
+    }
+    let signing = Signing::new(
+        Dialect::Smb3_0,
+        SigningAlgorithmId::AesCmac,
+        &session_key,
+        &[],
+    );
+    assert_eq!(
+        signing.sign(&message).0.to_vec(),
+        hex("2423893a6a5b9bd8c04834b2d2798479")
+    );
+}
+
+#[test]
+fn gmac_signatures_match_known_answers() {
+    // The nonce is the message id, then a bit set for responses and one set for cancels
+    let signing = Signing {
+        algorithm: SigningAlgorithmId::AesGmac,
+        key: hex(EXAMPLE_SESSION_KEY),
+    };
+    for (command, flags, signature) in [
+        (
+            Command::Flush,
+            HeaderFlags::new(),
+            "609897341d860642fe3b16d0643d8fe2",
+        ),
+        (
+            Command::Flush,
+            HeaderFlags::new().with_response(true),
+            "b90c5ad24c763d21ef9bef4d85c60ee9",
+        ),
+        (
+            Command::Cancel,
+            HeaderFlags::new(),
+            "b81e63878a29fb43a8a7b7d49c7b8649",
+        ),
+    ] {
+        let message = signing_test_message(command, flags);
+        assert_eq!(signing.sign(&message).0.to_vec(), hex(signature));
+    }
+}
+
+#[test]
+fn errors_describe_the_status() {
+    let error = Error::NtStatus(NtStatus::AccessDenied);
+    assert_eq!(
+        error.to_string(),
+        "server responded with an error: access is denied (AccessDenied, 0xc0000022)"
+    );
+    assert!(std::error::Error::source(&error).is_none());
+
+    let error = Error::from(io::Error::from(io::ErrorKind::NotConnected));
+    assert!(std::error::Error::source(&error).is_some());
+
+    let error = Error::InvalidSignature.connection_lost();
+    assert_eq!(
+        error.to_string(),
+        "connection lost: a message has an invalid signature"
+    );
+}
+
+#[test]
+fn errors_convert_to_io_errors() {
+    for (status, kind) in [
+        (NtStatus::ObjectNameNotFound, io::ErrorKind::NotFound),
+        (NtStatus::ObjectPathNotFound, io::ErrorKind::NotFound),
+        (NtStatus::AccessDenied, io::ErrorKind::PermissionDenied),
+        (NtStatus::ObjectNameCollision, io::ErrorKind::AlreadyExists),
+        (NtStatus::SharingViolation, io::ErrorKind::ResourceBusy),
+        (
+            NtStatus::DirectoryNotEmpty,
+            io::ErrorKind::DirectoryNotEmpty,
+        ),
+        (NtStatus::DiskFull, io::ErrorKind::StorageFull),
+        (NtStatus::Unknown(0xc0ff0001), io::ErrorKind::Other),
+    ] {
+        let error = io::Error::from(Error::NtStatus(status));
+        assert_eq!(error.kind(), kind, "{status:?}");
+        let inner = error.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(*inner, Error::NtStatus(s) if s == status));
+    }
+
+    // I/O errors come back out as they went in
+    let error = io::Error::from(Error::disconnected());
+    assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+    assert!(error.into_inner().is_none());
+}
+
+async fn receive_request<Request: DeserializeOwned>(
+    server: &mut (impl io::AsyncRead + Unpin),
+) -> (RequestHeader, Request) {
+    let len = server.read_u32().await.unwrap();
+    let mut bytes = vec![0; len as usize];
+    server.read_exact(&mut bytes).await.unwrap();
+    serde_smb::from_slice(&bytes).unwrap()
+}
+
+async fn send_response(
+    server: &mut (impl io::AsyncWrite + Unpin),
+    request: &RequestHeader,
+    nt_status: NtStatus,
+    async_id: Option<AsyncId>,
+    response: impl Serialize,
+) {
+    let mut header = ResponseHeader {
+        protocol_id: ProtocolId::new(),
+        header_length: 64,
+        credit_charge: request.credit_charge,
+        nt_status,
+        command: request.command,
+        credits_granted: Credits(1),
+        flags: HeaderFlags::new().with_response(true),
+        chain_offset: 0,
+        message_id: request.message_id,
+        process_id: request.process_id,
+        tree_id: request.tree_id,
+        session_id: request.session_id,
+        signature: Signature([0; 16]),
+    };
+    if let Some(async_id) = async_id {
+        header.set_async_id(async_id);
+    }
+    let bytes = serde_smb::to_vec(&(header, response)).unwrap();
+    server.write_u32(bytes.len() as u32).await.unwrap();
+    server.write_all(&bytes).await.unwrap();
+}
+
+fn flush_request() -> FlushRequest {
+    FlushRequest {
+        file_id: FileId {
+            persistent: 0,
+            volatile: 0,
+        },
+    }
+}
+
+#[tokio::test]
+async fn responses_are_routed_by_message_id() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+    client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .credits
+        .grant(Credits(1));
+
+    // Answer both requests, in the reverse order they arrived
+    let server = async {
+        let mut requests = vec![];
+        for _ in 0..2 {
+            let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+            requests.push(header);
+        }
+        for request in requests.iter().rev() {
+            send_response(
+                &mut server_side,
+                request,
+                NtStatus::Success,
+                None,
+                FlushResponse,
+            )
+            .await;
+        }
+    };
+
+    let flush = |tree_id| {
+        client.request::<_, FlushResponse>(
+            Credits(1),
+            None,
+            None,
+            None,
+            Some(TreeId(tree_id)),
+            flush_request(),
+        )
+    };
+    let (first, second, ()) = tokio::join!(flush(1), flush(2), server);
+    let (first, second) = (first.unwrap().0, second.unwrap().0);
+    assert_eq!((first.message_id, first.tree_id), (MessageId(0), TreeId(1)));
+    assert_eq!(
+        (second.message_id, second.tree_id),
+        (MessageId(1), TreeId(2))
+    );
+}
+
+#[tokio::test]
+async fn interim_responses_are_followed_by_async_response() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+
+    let server = async {
+        let (request, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        let async_id = Some(AsyncId(0x42));
+        send_response(
+            &mut server_side,
+            &request,
+            NtStatus::Pending,
+            async_id,
+            FlushResponse,
+        )
+        .await;
+        send_response(
+            &mut server_side,
+            &request,
+            NtStatus::Success,
+            async_id,
+            FlushResponse,
+        )
+        .await;
+    };
+
+    let flush =
+        client.request::<_, FlushResponse>(Credits(1), None, None, None, None, flush_request());
+    let (response, ()) = tokio::join!(flush, server);
+    let (header, _) = response.unwrap();
+    assert_eq!(header.async_id(), Some(AsyncId(0x42)));
+    assert!(client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .async_operations
+        .is_empty());
+}
+
+#[tokio::test]
+async fn compound_requests_share_a_frame() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+    client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .credits
+        .grant(Credits(1));
+
+    // Both requests arrive in one frame, and are answered in one
+    let server = async {
+        let len = server_side.read_u32().await.unwrap();
+        let mut bytes = vec![0; len as usize];
+        server_side.read_exact(&mut bytes).await.unwrap();
+
+        let first: RequestHeader = serde_smb::from_slice(&bytes).unwrap();
+        let offset = first.chain_offset as usize;
+        assert_eq!(offset % 8, 0);
+        assert!(!first.flags.chained());
+        let second: RequestHeader = serde_smb::from_slice(&bytes[offset..]).unwrap();
+        assert_eq!(second.chain_offset, 0);
+        assert!(second.flags.chained());
+
+        let mut response = vec![];
+        for (i, request) in [first, second].iter().enumerate() {
+            let mut header = ResponseHeader {
+                protocol_id: ProtocolId::new(),
+                header_length: 64,
+                credit_charge: request.credit_charge,
+                nt_status: NtStatus::Success,
+                command: request.command,
+                credits_granted: Credits(1),
+                flags: HeaderFlags::new().with_response(true),
+                chain_offset: 0,
+                message_id: request.message_id,
+                process_id: request.process_id,
+                tree_id: request.tree_id,
+                session_id: request.session_id,
+                signature: Signature([0; 16]),
+            };
+            if i == 0 {
+                header.chain_offset = 72;
+            }
+            let mut message = serde_smb::to_vec(&(header, FlushResponse)).unwrap();
+            message.resize(72, 0);
+            response.extend(message);
+        }
+        server_side.write_u32(response.len() as u32).await.unwrap();
+        server_side.write_all(&response).await.unwrap();
+    };
+
+    let requests = vec![
+        OutgoingRequest::new(Credits(1), None, false, flush_request()).unwrap(),
+        OutgoingRequest::new(Credits(1), None, true, flush_request()).unwrap(),
+    ];
+    let (responses, ()) = tokio::join!(client.exchange(requests, None, None, None), server);
+    let message_ids: Vec<_> = responses
+        .unwrap()
+        .into_iter()
+        .map(|response| response.unwrap().0.message_id)
+        .collect();
+    assert_eq!(message_ids, [MessageId(0), MessageId(1)]);
+}
+
+/// Checked at compile time, so the client can be used from spawned tasks.
+#[allow(dead_code)]
+fn client_futures_are_send(client: &Client<io::DuplexStream>, file_id: FileId) {
+    fn is_send(_: impl Send) {}
+    is_send(client.read_all(file_id, io::sink()));
+    is_send(client.write_all(file_id, io::empty()));
+    is_send(client.query_directory(file_id));
+    is_send(client.query_info_path::<FileAllInformation>("file"));
+    is_send(client.lock(file_id, 0, 1, LockMode::Shared));
+    is_send(client.watch("dir", CompletionFilter::FILE_NAME, false));
+    is_send(client.open_with_caching("file", CachingRequest::Oplock(OplockLevel::Batch)));
+    is_send(client.open_cached("file"));
+}
+
+#[allow(dead_code)]
+fn reconnecting_client_futures_are_send(
+    client: &ReconnectingClient<TestConnector>,
+    file_id: FileId,
+) {
+    fn is_send(_: impl Send) {}
+    is_send(client.read(file_id, 0, 1));
+    is_send(client.write(file_id, 0, vec![]));
+    is_send(client.query_info_path::<FileAllInformation>("file"));
+}
+
+#[tokio::test]
+async fn dropping_a_request_cancels_it() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+
+    let mut flush = Box::pin(client.request::<_, FlushResponse>(
+        Credits(1),
+        None,
+        None,
+        None,
+        None,
+        flush_request(),
+    ));
+    assert!(futures::poll!(&mut flush).is_pending());
+
+    let (request, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+    send_response(
+        &mut server_side,
+        &request,
+        NtStatus::Pending,
+        Some(AsyncId(0x42)),
+        FlushResponse,
+    )
+    .await;
+    tokio::task::yield_now().await;
+    drop(flush);
+
+    let (cancel, _): (_, CancelRequest) = receive_request(&mut server_side).await;
+    assert_eq!(cancel.command, Command::Cancel);
+    assert_eq!(cancel.message_id, request.message_id);
+    assert_eq!(cancel.async_id(), Some(AsyncId(0x42)));
+
+    let state = client.connection.state.lock().unwrap();
+    assert!(state.pending.is_empty());
+    assert!(state.async_operations.is_empty());
+}
+
+#[tokio::test]
+async fn dropping_a_compound_cancels_the_parts_still_pending() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+    client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .credits
+        .grant(Credits(1));
+
+    let requests = vec![
+        OutgoingRequest::new(Credits(1), None, false, flush_request()).unwrap(),
+        OutgoingRequest::new(Credits(1), None, true, flush_request()).unwrap(),
+    ];
+    let mut exchange = Box::pin(client.exchange(requests, None, None, None));
+    assert!(futures::poll!(&mut exchange).is_pending());
+
+    // Only the first part is answered before we stop waiting
+    let (first, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+    send_response(
+        &mut server_side,
+        &first,
+        NtStatus::Success,
+        None,
+        FlushResponse,
+    )
+    .await;
+    tokio::task::yield_now().await;
+    drop(exchange);
+
+    let (cancel, _): (_, CancelRequest) = receive_request(&mut server_side).await;
+    assert_eq!(cancel.command, Command::Cancel);
+    assert_eq!(cancel.message_id, MessageId(1));
+    assert!(client.connection.state.lock().unwrap().pending.is_empty());
+}
+
+#[tokio::test]
+async fn requests_that_fail_to_send_give_up_their_message_ids() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+
+    // Too short to hold a header
+    let request = OutgoingRequest {
+        credit_charge: Credits(1),
+        tree_id: None,
+        related: false,
+        replay: false,
+        bytes: vec![0; 4],
+    };
+    let result = client.exchange(vec![request], None, None, None).await;
+    assert!(matches!(result, Err(Error::Seralization(_))));
+    assert!(client.connection.state.lock().unwrap().pending.is_empty());
+
+    // Its credit and message id go to the next request
+    let server = async {
+        let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        assert_eq!(header.message_id, MessageId(0));
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let flush =
+        client.request::<_, FlushResponse>(Credits(1), None, None, None, None, flush_request());
+    let (result, ()) = tokio::join!(flush, server);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn cancelled_requests_hold_their_credits_until_answered() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+
+    let mut flush = Box::pin(client.request::<_, FlushResponse>(
+        Credits(1),
+        None,
+        None,
+        None,
+        None,
+        flush_request(),
+    ));
+    assert!(futures::poll!(&mut flush).is_pending());
+    let (cancelled, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+    drop(flush);
+    let (_, _): (_, CancelRequest) = receive_request(&mut server_side).await;
+
+    // The next request waits for the credit the cancelled one's response brings
+    let server = async {
+        send_response(
+            &mut server_side,
+            &cancelled,
+            NtStatus::Cancelled,
+            None,
+            FlushResponse,
+        )
+        .await;
+        let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        assert_eq!(header.message_id, MessageId(1));
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let flush =
+        client.request::<_, FlushResponse>(Credits(1), None, None, None, None, flush_request());
+    let (result, ()) = tokio::join!(flush, server);
+    result.unwrap();
+    assert!(client.connection.state.lock().unwrap().cancelled.is_empty());
+}
+
+#[tokio::test]
+async fn reads_are_as_large_as_the_credits_left_once_acquired() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+    client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .credits
+        .grant(Credits(2));
+
+    // The flush uses one of the three credits before the read gets to acquire its own
+    let server = async {
+        let (flush, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        let (read, request): (_, ReadRequest) = receive_request(&mut server_side).await;
+        assert_eq!(read.credit_charge, Credits(2));
+        assert_eq!(request.length, 2 * CREDIT_PAYLOAD_SIZE);
+        send_response(
+            &mut server_side,
+            &flush,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+        let response = ReadResponse {
+            data_remaining: 0,
+            flags: ReadResponseFlags::None,
+            data: vec![],
+        };
+        send_response(&mut server_side, &read, NtStatus::Success, None, response).await;
+    };
+    let flush =
+        client.request::<_, FlushResponse>(Credits(1), None, None, None, None, flush_request());
+    let read = client.sized_request::<_, ReadResponse>(
+        MAX_IO_SIZE,
+        None,
+        None,
+        None,
+        None,
+        false,
+        |length| ReadRequest {
+            padding: 0,
+            flags: ReadFlags::empty(),
+            length,
+            offset: 0,
+            file_id: flush_request().file_id,
+            minimum_bytes: 0,
+            channel: Channel::None,
+            remaining_bytes: 0,
+            channel_data: vec![0],
+        },
+    );
+    let (flush, read, ()) = tokio::join!(flush, read, server);
+    flush.unwrap();
+    read.unwrap();
+}
+
+#[tokio::test]
+async fn only_smb3_1_1_negotiates_contexts_and_a_pre_auth_hash() {
+    for dialect in [
+        Dialect::Smb2_0_2,
+        Dialect::Smb2_1,
+        Dialect::Smb3_0,
+        Dialect::Smb3_0_2,
+        Dialect::Smb3_1_1,
+    ] {
+        let (client_side, mut server_side) = io::duplex(4096);
+        let client = UnauthenticatedClient::new(client_side);
+        let server = async {
+            let (header, request): (_, NegotiateRequest) = receive_request(&mut server_side).await;
+            assert_eq!(request.dialects, [dialect]);
+            assert_eq!(
+                request.negotiate_contexts.is_empty(),
+                dialect != Dialect::Smb3_1_1
+            );
+            let response = NegotiateResponse {
+                security_mode: SecurityMode::SIGNING_ENABLED,
+                dialect,
+                server_guid: Uuid::new(&mut OsRng),
+                capabilities: Capabilities::empty(),
+                max_transaction_size: 65536,
+                max_read_size: 65536,
+                max_write_size: 65536,
+                current_time: Time { intervals: 0 },
+                boot_time: Time { intervals: 0 },
+                security_blob: vec![],
+                negotiate_contexts: vec![],
+            };
+            send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        };
+        let (dialects, client_guid) = ([dialect], Uuid::new(&mut OsRng));
+        let (result, ()) = tokio::join!(client.negotiate(&dialects, &client_guid), server);
+        result.unwrap();
+        assert_eq!(
+            client.pre_auth_hash.lock().unwrap().is_none(),
+            dialect != Dialect::Smb3_1_1
+        );
+    }
+}
+
+#[tokio::test]
+async fn the_pre_auth_hash_is_dropped_after_session_setup() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (client, _server_side, _) =
+        set_up_session_with_dialect(authenticator, SessionFlags::GUEST, Dialect::Smb3_1_1).await;
+    let channel = client.channel();
+    assert!(channel.signing.is_none());
+    assert!(channel
+        .unauth_client
+        .pre_auth_hash
+        .lock()
+        .unwrap()
+        .is_none());
+}
+
+/// Hands out numbered blobs and records the ones the server sent.
+struct ScriptedAuthenticator {
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+    session_key: Option<Vec<u8>>,
+}
+
+impl Authenticator for ScriptedAuthenticator {
+    fn step(&mut self, security_blob: &[u8]) -> Result<Vec<u8>> {
+        let mut received = self.received.lock().unwrap();
+        received.push(security_blob.to_vec());
+        Ok(format!("step {}", received.len()).into_bytes())
+    }
+
+    fn finish(&mut self, security_blob: &[u8]) -> Result<()> {
+        self.received.lock().unwrap().push(security_blob.to_vec());
+        Ok(())
+    }
+
+    fn session_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.session_key.clone())
+    }
+}
+
+/// Sets up a session with a server that takes two round trips and then gives the session these
+/// flags. Returns the server's end of the connection and the blobs the server received.
+async fn set_up_session(
+    authenticator: impl Authenticator,
+    session_flags: SessionFlags,
+) -> (
+    AuthenticatedClient<io::DuplexStream>,
+    io::DuplexStream,
+    Vec<Vec<u8>>,
+) {
+    set_up_session_with_dialect(authenticator, session_flags, Dialect::Smb2_1).await
+}
+
+async fn set_up_session_with_dialect(
+    authenticator: impl Authenticator,
+    session_flags: SessionFlags,
+    dialect: Dialect,
+) -> (
+    AuthenticatedClient<io::DuplexStream>,
+    io::DuplexStream,
+    Vec<Vec<u8>>,
+) {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let options = ClientOptions {
+        dialects: vec![dialect],
+        ..Default::default()
+    };
+    let (client, blobs) = tokio::join!(
+        AuthenticatedClient::new(client_side, authenticator, &options),
+        serve_session_setup(&mut server_side, session_flags, dialect)
+    );
+    (client.unwrap(), server_side, blobs)
+}
+
+/// The server's side of `set_up_session`.
+async fn serve_session_setup(
+    server_side: &mut io::DuplexStream,
+    session_flags: SessionFlags,
+    dialect: Dialect,
+) -> Vec<Vec<u8>> {
+    let (header, _): (_, NegotiateRequest) = receive_request(server_side).await;
+    let response = NegotiateResponse {
+        security_mode: SecurityMode::SIGNING_ENABLED,
+        dialect,
+        server_guid: Uuid::new(&mut OsRng),
+        capabilities: Capabilities::LEASING | Capabilities::PERSISTENT_HANDLES,
+        max_transaction_size: 65536,
+        max_read_size: 65536,
+        max_write_size: 65536,
+        current_time: Time { intervals: 0 },
+        boot_time: Time { intervals: 0 },
+        security_blob: b"hint".to_vec(),
+        negotiate_contexts: vec![],
+    };
+    send_response(server_side, &header, NtStatus::Success, None, response).await;
+
+    let mut blobs = vec![];
+    for (nt_status, flags, security_blob) in [
+        (
+            NtStatus::MoreProcessingRequired,
+            SessionFlags::empty(),
+            b"challenge",
+        ),
+        (NtStatus::Success, session_flags, b"confirmed"),
+    ] {
+        let (header, request): (_, SessionSetupRequest) = receive_request(server_side).await;
+        blobs.push(request.security_blob);
+        let response = SessionSetupResponse {
+            flags,
+            security_blob: security_blob.to_vec(),
+        };
+        send_response(server_side, &header, nt_status, None, response).await;
+    }
+    blobs
+}
+
+#[tokio::test]
+async fn session_setup_is_driven_by_the_authenticator() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let authenticator = ScriptedAuthenticator {
+        received: received.clone(),
+        session_key: None,
+    };
+    let (client, _server_side, blobs) = set_up_session(authenticator, SessionFlags::empty()).await;
+    assert!(client.channel().signing.is_none());
+    assert_eq!(blobs, [b"step 1", b"step 2"]);
+    assert_eq!(
+        *received.lock().unwrap(),
+        [&b"hint"[..], b"challenge", b"confirmed"]
+    );
+}
+
+#[tokio::test]
+async fn guest_and_null_sessions_are_not_signed() {
+    for session_flags in [SessionFlags::GUEST, SessionFlags::NULL] {
+        let authenticator = ScriptedAuthenticator {
+            received: Arc::default(),
+            session_key: Some(vec![0x42; 16]),
+        };
+        let (client, _, _) = set_up_session(authenticator, session_flags).await;
+        let channel = client.channel();
+        assert!(channel.signing.is_none());
+        assert!(channel.encryption.is_none());
+        assert_eq!(channel.session_flags, session_flags);
+    }
+}
+
+/// Signs a response with the session's key, then flips its last byte if it is to be tampered with.
+async fn send_signed_response(
+    server: &mut (impl io::AsyncWrite + Unpin),
+    request: &RequestHeader,
+    nt_status: NtStatus,
+    response: impl Serialize,
+    signing: &Signing,
+    tamper: bool,
+) {
+    let header = ResponseHeader {
+        protocol_id: ProtocolId::new(),
+        header_length: 64,
+        credit_charge: request.credit_charge,
+        nt_status,
+        command: request.command,
+        credits_granted: Credits(1),
+        flags: HeaderFlags::new().with_response(true).with_signing(true),
+        chain_offset: 0,
+        message_id: request.message_id,
+        process_id: request.process_id,
+        tree_id: request.tree_id,
+        session_id: request.session_id,
+        signature: Signature([0; 16]),
+    };
+    let mut bytes = serde_smb::to_vec(&(header, response)).unwrap();
+    let signature = signing.sign(&bytes);
+    bytes[48..64].copy_from_slice(&signature.0);
+    if tamper {
+        *bytes.last_mut().unwrap() ^= 1;
+    }
+    server.write_u32(bytes.len() as u32).await.unwrap();
+    server.write_all(&bytes).await.unwrap();
+}
+
+const SESSION_KEY: [u8; 16] = [0x42; 16];
+
+/// Sets up a 2.1 session with a key, whose final response the server signs.
+async fn set_up_signed_session(
+    security_mode: SecurityMode,
+    tamper: bool,
+) -> (
+    Result<AuthenticatedClient<io::DuplexStream>>,
+    io::DuplexStream,
+) {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: Some(SESSION_KEY.to_vec()),
+    };
+    let options = ClientOptions {
+        dialects: vec![Dialect::Smb2_1],
+        ..Default::default()
+    };
+    let signing = Signing::new(
+        Dialect::Smb2_1,
+        SigningAlgorithmId::HmacSha256,
+        &SESSION_KEY,
+        &[],
+    );
+
+    let server = async {
+        let (header, _): (_, NegotiateRequest) = receive_request(&mut server_side).await;
+        let response = NegotiateResponse {
+            security_mode,
+            dialect: Dialect::Smb2_1,
+            server_guid: Uuid::new(&mut OsRng),
+            capabilities: Capabilities::empty(),
+            max_transaction_size: 65536,
+            max_read_size: 65536,
+            max_write_size: 65536,
+            current_time: Time { intervals: 0 },
+            boot_time: Time { intervals: 0 },
+            security_blob: b"hint".to_vec(),
+            negotiate_contexts: vec![],
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        let (header, _): (_, SessionSetupRequest) = receive_request(&mut server_side).await;
+        let response = SessionSetupResponse {
+            flags: SessionFlags::empty(),
+            security_blob: b"challenge".to_vec(),
+        };
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::MoreProcessingRequired,
+            None,
+            response,
+        )
+        .await;
+
+        let (header, _): (_, SessionSetupRequest) = receive_request(&mut server_side).await;
+        let response = SessionSetupResponse {
+            flags: SessionFlags::empty(),
+            security_blob: b"confirmed".to_vec(),
+        };
+        send_signed_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            response,
+            &signing,
+            tamper,
+        )
+        .await;
+    };
+    let (client, ()) = tokio::join!(
+        AuthenticatedClient::new(client_side, authenticator, &options),
+        server
+    );
+    (client, server_side)
+}
+
+#[tokio::test]
+async fn responses_with_a_bad_signature_are_rejected() {
+    let (client, mut server_side) =
+        set_up_signed_session(SecurityMode::SIGNING_ENABLED, false).await;
+    let client = client.unwrap();
+    let signing = Signing::new(
+        Dialect::Smb2_1,
+        SigningAlgorithmId::HmacSha256,
+        &SESSION_KEY,
+        &[],
+    );
+
+    for tamper in [false, true] {
+        let server = async {
+            let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+            assert!(header.flags.signing());
+            send_signed_response(
+                &mut server_side,
+                &header,
+                NtStatus::Success,
+                FlushResponse,
+                &signing,
+                tamper,
+            )
+            .await;
+        };
+        let (result, ()) = tokio::join!(
+            client.request::<_, FlushResponse>(None, Credits(1), flush_request()),
+            server
+        );
+        if tamper {
+            assert!(matches!(result, Err(Error::InvalidSignature)));
+        } else {
+            result.unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn responses_to_encrypted_requests_must_be_encrypted() {
+    let (client_side, mut server_side) = io::duplex(4096);
+    let client = UnauthenticatedClient::new(client_side);
+    client
+        .connection
+        .state
+        .lock()
+        .unwrap()
+        .credits
+        .grant(Credits(1));
+    let session_id = SessionId(0x11);
+    let encryption = || Encryption::new(Dialect::Smb3_0, CipherId::Aes128Ccm, &SESSION_KEY, &[]);
+    let server_encryption = Encryption {
+        encryption_key: encryption().decryption_key,
+        decryption_key: encryption().encryption_key,
+        ..encryption()
+    };
+    client.add_decryption(session_id, Arc::new(encryption()));
+
+    for encrypt_response in [true, false] {
+        let server = async {
+            let len = server_side.read_u32().await.unwrap();
+            let mut bytes = vec![0; len as usize];
+            server_side.read_exact(&mut bytes).await.unwrap();
+            let bytes = server_encryption.decrypt(&bytes).unwrap();
+            let header: RequestHeader = serde_smb::from_slice(&bytes).unwrap();
+
+            // Unsigned plaintext, as a man in the middle would inject
+            let response = ResponseHeader {
+                protocol_id: ProtocolId::new(),
+                header_length: 64,
+                credit_charge: header.credit_charge,
+                nt_status: NtStatus::Success,
+                command: header.command,
+                credits_granted: Credits(1),
+                flags: HeaderFlags::new().with_response(true),
+                chain_offset: 0,
+                message_id: header.message_id,
+                process_id: header.process_id,
+                tree_id: header.tree_id,
+                session_id,
+                signature: Signature([0; 16]),
+            };
+            let mut message = serde_smb::to_vec(&(response, FlushResponse)).unwrap();
+            if encrypt_response {
+                message = server_encryption.encrypt(session_id, message).unwrap();
+            }
+            server_side.write_u32(message.len() as u32).await.unwrap();
+            server_side.write_all(&message).await.unwrap();
+        };
+        let encryption = encryption();
+        let request = client.request::<_, FlushResponse>(
+            Credits(1),
+            Some(session_id),
+            None,
+            Some(&encryption),
+            None,
+            flush_request(),
+        );
+        let (result, ()) = tokio::join!(request, server);
+        if encrypt_response {
+            result.unwrap();
+        } else {
+            assert!(matches!(result, Err(Error::DecryptionFailed)));
+        }
+    }
+}
+
+#[tokio::test]
+async fn unsigned_responses_are_rejected_when_signing_is_required() {
+    let (client, mut server_side) =
+        set_up_signed_session(SecurityMode::SIGNING_REQURED, false).await;
+    let client = client.unwrap();
+
+    let server = async {
+        let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(
+        client.request::<_, FlushResponse>(None, Credits(1), flush_request()),
+        server
+    );
+    assert!(matches!(result, Err(Error::InvalidSignature)));
+}
+
+#[tokio::test]
+async fn session_setup_fails_if_the_final_response_has_a_bad_signature() {
+    let (client, _server_side) = set_up_signed_session(SecurityMode::SIGNING_ENABLED, true).await;
+    assert!(matches!(client, Err(Error::InvalidSignature)));
+}
+
+#[tokio::test]
+async fn shutdown_closes_files_disconnects_trees_and_logs_off() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (client, mut server_side, _) = set_up_session(authenticator, SessionFlags::empty()).await;
+    let file_id = FileId {
+        persistent: 1,
+        volatile: 2,
+    };
+    client
+        .trees
+        .lock()
+        .unwrap()
+        .push((TreeId(7), "share".into()));
+    client.track_open(TreeId(7), file_id);
+
+    let server = async {
+        let (header, request): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert_eq!(
+            (header.command, header.tree_id),
+            (Command::Close, TreeId(7))
+        );
+        assert_eq!(request.file_id, file_id);
+        let response = CloseResponse {
+            flags: CloseFlags::empty(),
+            creation_time: Time { intervals: 0 },
+            last_access_time: Time { intervals: 0 },
+            last_write_time: Time { intervals: 0 },
+            change_time: Time { intervals: 0 },
+            allocation_time: 0,
+            end_of_file: 0,
+            file_attributes: FileAttributes::empty(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        let (header, _): (_, TreeDisconnectRequest) = receive_request(&mut server_side).await;
+        assert_eq!(
+            (header.command, header.tree_id),
+            (Command::TreeDisconnect, TreeId(7))
+        );
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            TreeDisconnectResponse,
+        )
+        .await;
+
+        let (header, _): (_, LogoffRequest) = receive_request(&mut server_side).await;
+        assert_eq!(header.command, Command::Logoff);
+        assert_eq!(header.session_id, client.channel().session_id);
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            LogoffResponse,
+        )
+        .await;
+
+        // Then the client closes its end
+        let mut rest = vec![];
+        server_side.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    };
+
+    let (result, ()) = tokio::join!(client.shutdown(), server);
+    result.unwrap();
+    assert!(client.open_files.lock().unwrap().is_empty());
+    assert!(matches!(
+        client.request::<_, FlushResponse>(None, Credits(1), flush_request()).await,
+        Err(Error::Io(error)) if error.kind() == io::ErrorKind::NotConnected
+    ));
+}
+
+#[tokio::test]
+async fn a_session_connects_to_many_trees() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session(authenticator, SessionFlags::empty()).await;
+    let session = Session::from_auth_client(auth_client, &ClientOptions::default());
+
+    let shares = [
+        ("files", TreeId(1), ShareType::Disk, AccessMask::GENERIC_ALL),
+        ("IPC$", TreeId(2), ShareType::Pipe, AccessMask::GENERIC_READ),
+    ];
+    let server = async {
+        for (share, tree_id, share_type, access_mask) in shares {
+            let (mut header, request): (_, TreeConnectRequest) =
+                receive_request(&mut server_side).await;
+            assert_eq!(request.path, share);
+            header.tree_id = tree_id;
+            let response = TreeConnectResponse {
+                share_type,
+                share_flags: ShareFlags::AUTO_CACHING,
+                share_capabilities: ShareCapabilities::CONTINUOUS_AVAILABILITY,
+                access_mask,
+            };
+            send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        }
+    };
+    let connect = async {
+        let files = session.tree_connect("files").await.unwrap();
+        let ipc = session.tree_connect("IPC$").await.unwrap();
+        (files, ipc)
+    };
+    let ((files, ipc), ()) = tokio::join!(connect, server);
+
+    for (client, (_, tree_id, share_type, access_mask)) in [files, ipc.clone()].iter().zip(shares) {
+        let tree = client.tree();
+        assert_eq!(tree.id(), tree_id);
+        assert_eq!(
+            *tree.info(),
+            ShareInfo {
+                share_type,
+                share_flags: ShareFlags::AUTO_CACHING,
+                share_capabilities: ShareCapabilities::CONTINUOUS_AVAILABILITY,
+                maximal_access: access_mask,
+            }
+        );
+        assert_eq!(tree.info().caching_mode(), CachingMode::Auto);
+        assert!(tree.info().is_continuously_available());
+        assert!(!tree.info().encrypts_data());
+    }
+    assert_eq!(
+        *session.auth_client.trees.lock().unwrap(),
+        [(TreeId(1), "files".into()), (TreeId(2), "IPC$".into())]
+    );
+
+    // Disconnecting one tree leaves the other, but only once the server has done it
+    let file_id = FileId {
+        persistent: 1,
+        volatile: 2,
+    };
+    session.auth_client.track_open(TreeId(2), file_id);
+    for nt_status in [NtStatus::AccessDenied, NtStatus::Success] {
+        let server = async {
+            let (header, _): (_, TreeDisconnectRequest) = receive_request(&mut server_side).await;
+            assert_eq!(header.tree_id, TreeId(2));
+            send_response(
+                &mut server_side,
+                &header,
+                nt_status,
+                None,
+                TreeDisconnectResponse,
+            )
+            .await;
+        };
+        let (result, ()) = tokio::join!(ipc.clone().tree_disconnect(), server);
+        let auth_client = &session.auth_client;
+        let open_files = auth_client.open_files.lock().unwrap().len();
+        if nt_status == NtStatus::Success {
+            result.unwrap();
+            assert_eq!(
+                *auth_client.trees.lock().unwrap(),
+                [(TreeId(1), "files".into())]
+            );
+            assert_eq!(open_files, 0);
+        } else {
+            assert!(result.is_err());
+            assert_eq!(auth_client.trees.lock().unwrap().len(), 2);
+            assert_eq!(open_files, 1);
+        }
+    }
+}
+
+/// A client on a disk share, through a session set up by `set_up_session`.
+fn client_on_tree(auth_client: AuthenticatedClient<io::DuplexStream>) -> Client<io::DuplexStream> {
+    let session = Session::from_auth_client(auth_client, &ClientOptions::default());
+    Client {
+        auth_client: session.auth_client,
+        tree: Tree {
+            id: TreeId(1),
+            info: ShareInfo {
+                share_type: ShareType::Disk,
+                share_flags: ShareFlags::empty(),
+                share_capabilities: ShareCapabilities::empty(),
+                maximal_access: AccessMask::GENERIC_ALL,
+            },
+        },
+        max_read_size: session.max_read_size,
+        max_write_size: session.max_write_size,
+        max_outstanding_io: session.max_outstanding_io,
+        replay: false,
+    }
+}
+
+#[tokio::test]
+async fn locks_wait_for_the_server_and_unlock_when_dropped() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session(authenticator, SessionFlags::empty()).await;
+    let client = client_on_tree(auth_client);
+    let file_id = FileId {
+        persistent: 1,
+        volatile: 2,
+    };
+
+    // The lock is held elsewhere for a while, so the server goes async on it
+    let server = async {
+        let (header, request): (_, LockRequest) = receive_request(&mut server_side).await;
+        assert_eq!(request.file_id, file_id);
+        assert_eq!(
+            request.locks,
+            [LockElement {
+                offset: 10,
+                length: 20,
+                flags: LockFlags::EXCLUSIVE_LOCK,
+            }]
+        );
+        let async_id = Some(AsyncId(0x42));
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Pending,
+            async_id,
+            LockResponse,
+        )
+        .await;
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            async_id,
+            LockResponse,
+        )
+        .await;
+    };
+    let (guard, ()) = tokio::join!(client.lock(file_id, 10, 20, LockMode::Exclusive), server);
+    let guard = guard.unwrap();
+    assert_eq!((guard.offset(), guard.length()), (10, 20));
+
+    drop(guard);
+    let (header, request): (_, LockRequest) = receive_request(&mut server_side).await;
+    assert_eq!(
+        request.locks,
+        [LockElement {
+            offset: 10,
+            length: 20,
+            flags: LockFlags::UNLOCK,
+        }]
+    );
+    send_response(
+        &mut server_side,
+        &header,
+        NtStatus::Success,
+        None,
+        LockResponse,
+    )
+    .await;
+
+    // Locks that can't wait fail with the server's status
+    let server = async {
+        let (header, request): (_, LockRequest) = receive_request(&mut server_side).await;
+        assert_eq!(
+            request.locks[0].flags,
+            LockFlags::SHARED_LOCK | LockFlags::FAIL_IMMEDIATELY
+        );
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::LockNotGranted,
+            None,
+            LockResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(client.try_lock(file_id, 0, 1, LockMode::Shared), server);
+    assert!(matches!(
+        result,
+        Err(Error::NtStatus(NtStatus::LockNotGranted))
+    ));
+}
+
+fn create_response(file_id: FileId) -> CreateResponse {
+    CreateResponse {
+        oplock_level: OplockLevel::None,
+        reparse_point: false,
+        create_action: FileCreateAction::Opened,
+        create_time: Time { intervals: 0 },
+        last_access_time: Time { intervals: 0 },
+        last_write_time: Time { intervals: 0 },
+        change_time: Time { intervals: 0 },
+        allocation_size: 0,
+        end_of_file: 0,
+        file_attributes: FileAttributes::DIRECTORY,
+        file_id,
+        create_contexts: vec![],
+    }
+}
+
+#[tokio::test]
+async fn watching_a_directory_streams_its_changes() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session(authenticator, SessionFlags::empty()).await;
+    let client = client_on_tree(auth_client);
+    let file_id = FileId {
+        persistent: 3,
+        volatile: 4,
+    };
+    let change = |action, file_name: &str| FileNotifyInformation {
+        action,
+        file_name: file_name.into(),
+    };
+    let filter = CompletionFilter::FILE_NAME | CompletionFilter::LAST_WRITE;
+
+    let server = async {
+        let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+        assert_eq!(request.name, "dir");
+        assert!(request
+            .create_options
+            .contains(FileCreateOptions::DIRECTORY_FILE));
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            create_response(file_id),
+        )
+        .await;
+
+        // Nothing changes for a while, then two things do at once
+        let (header, request): (_, ChangeNotifyRequest) = receive_request(&mut server_side).await;
+        assert_eq!(request.file_id, file_id);
+        assert_eq!(request.flags, ChangeNotifyFlags::WATCH_TREE);
+        assert_eq!(request.completion_filter, filter);
+        let async_id = Some(AsyncId(0x42));
+        let response = ChangeNotifyResponse { entries: vec![] };
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Pending,
+            async_id,
+            response,
+        )
+        .await;
+        let response = ChangeNotifyResponse {
+            entries: vec![
+                change(FileAction::Added, "a"),
+                change(FileAction::Modified, "sub\\b"),
+            ],
+        };
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            async_id,
+            response,
+        )
+        .await;
+
+        // Then too many to keep track of, and one more after that
+        for (nt_status, entries) in [
+            (NtStatus::NotifyEnumDir, vec![]),
+            (NtStatus::Success, vec![change(FileAction::Removed, "a")]),
+        ] {
+            let (header, _): (_, ChangeNotifyRequest) = receive_request(&mut server_side).await;
+            let response = ChangeNotifyResponse { entries };
+            send_response(&mut server_side, &header, nt_status, None, response).await;
+        }
+    };
+    let watch = async {
+        let mut changes = client.watch("dir", filter, true).await.unwrap();
+        let mut received = vec![];
+        for _ in 0..4 {
+            received.push(changes.next().await.unwrap());
+        }
+        (changes, received)
+    };
+    let ((mut changes, received), ()) = tokio::join!(watch, server);
+    assert_eq!(
+        received[0].as_ref().unwrap(),
+        &change(FileAction::Added, "a")
+    );
+    assert_eq!(
+        received[1].as_ref().unwrap(),
+        &change(FileAction::Modified, "sub\\b")
+    );
+    assert!(matches!(
+        received[2],
+        Err(Error::NtStatus(NtStatus::NotifyEnumDir))
+    ));
+    assert_eq!(
+        received[3].as_ref().unwrap(),
+        &change(FileAction::Removed, "a")
+    );
+
+    // Dropping the stream while it waits cancels the request and closes the directory
+    assert!(futures::poll!(changes.next()).is_pending());
+    let (notify, _): (_, ChangeNotifyRequest) = receive_request(&mut server_side).await;
+    drop(changes);
+    let (cancel, _): (_, CancelRequest) = receive_request(&mut server_side).await;
+    assert_eq!(cancel.message_id, notify.message_id);
+    let (_, close): (_, CloseRequest) = receive_request(&mut server_side).await;
+    assert_eq!(close.file_id, file_id);
+}
+
+/// Sends a break notification, which isn't a response to anything.
+async fn send_break(server: &mut (impl io::AsyncWrite + Unpin), notification: impl Serialize) {
+    let header = ResponseHeader {
+        protocol_id: ProtocolId::new(),
+        header_length: 64,
+        credit_charge: Credits(0),
+        nt_status: NtStatus::Success,
+        command: Command::OplockBreak,
+        credits_granted: Credits(0),
+        flags: HeaderFlags::new().with_response(true),
+        chain_offset: 0,
+        message_id: MessageId(u64::MAX),
+        process_id: ProcessId(0),
+        tree_id: TreeId(0),
+        session_id: SessionId(0),
+        signature: Signature([0; 16]),
+    };
+    let bytes = serde_smb::to_vec(&(header, notification)).unwrap();
+    server.write_u32(bytes.len() as u32).await.unwrap();
+    server.write_all(&bytes).await.unwrap();
+}
+
+/// The create contexts of a response granting a lease.
+fn lease_context(lease: &ResponseLease) -> Vec<u8> {
+    response_contexts(&[(b"RqLs", serde_smb::to_vec(lease).unwrap())])
+}
+
+/// Create contexts with these tags and data, as a response carries them.
+fn response_contexts(contexts: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for (i, (tag, data)) in contexts.iter().enumerate() {
+        let len = (24 + data.len()).next_multiple_of(8);
+        let next = if i + 1 < contexts.len() { len } else { 0 };
+        bytes.extend((next as u32).to_le_bytes());
+        bytes.extend([16, 0, 4, 0, 0, 0, 24, 0]);
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(*tag);
+        bytes.extend([0; 4]);
+        bytes.extend(data);
+        if next != 0 {
+            bytes.resize(bytes.len().next_multiple_of(8), 0);
+        }
+    }
+    bytes
+}
+
+#[tokio::test]
+async fn lease_breaks_are_acknowledged() {
+    // SMB 2.1 has the shorter version 1 leases
+    for dialect in [Dialect::Smb2_1, Dialect::Smb3_0] {
+        let authenticator = ScriptedAuthenticator {
+            received: Arc::default(),
+            session_key: None,
+        };
+        let (auth_client, mut server_side, _) =
+            set_up_session_with_dialect(authenticator, SessionFlags::empty(), dialect).await;
+        let client = client_on_tree(auth_client);
+        let file_id = FileId {
+            persistent: 5,
+            volatile: 6,
+        };
+        let read_write_handle =
+            LeaseState::READ_CACHING | LeaseState::WRITE_CACHING | LeaseState::HANDLE_CACHING;
+
+        let server = async {
+            let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+            assert_eq!(request.requested_oplock_level, OplockLevel::Lease);
+            let (lease_key, lease_state, contexts) = match &request.create_contexts[0].body {
+                CreateContext::RequestLeaseV1(requested) if dialect == Dialect::Smb2_1 => {
+                    let lease = ResponseLeaseV1 {
+                        lease_key: requested.lease_key,
+                        lease_state: read_write_handle,
+                        flags: LeaseFlags::empty(),
+                    };
+                    let data = serde_smb::to_vec(&lease).unwrap();
+                    (
+                        requested.lease_key,
+                        requested.lease_state,
+                        response_contexts(&[(b"RqLs", data)]),
+                    )
+                }
+                CreateContext::RequestLease(requested) if dialect == Dialect::Smb3_0 => {
+                    let contexts = lease_context(&ResponseLease {
+                        lease_key: requested.lease_key,
+                        lease_state: read_write_handle,
+                        flags: LeaseFlags::empty(),
+                        parent_lease_key: LeaseKey::default(),
+                        epoch: 1,
+                    });
+                    (requested.lease_key, requested.lease_state, contexts)
+                }
+                _ => panic!("no lease requested: {:?}", request.create_contexts),
+            };
+            assert_eq!(lease_state, read_write_handle);
+            let mut response = create_response(file_id);
+            response.oplock_level = OplockLevel::Lease;
+            response.create_contexts = contexts;
+            send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+            lease_key
+        };
+        let (file, lease_key) = tokio::join!(
+            client.open_with_caching("file", CachingRequest::Lease(read_write_handle)),
+            server
+        );
+        let file = file.unwrap();
+        assert_eq!(file.lease_key(), Some(lease_key));
+        assert_eq!(file.caching_state(), read_write_handle);
+
+        // Someone else opens the file for reading
+        send_break(
+            &mut server_side,
+            LeaseBreakNotification {
+                new_epoch: 2,
+                flags: LeaseBreakFlags::ACK_REQUIRED,
+                lease_key,
+                current_lease_state: read_write_handle,
+                new_lease_state: LeaseState::READ_CACHING,
+            },
+        )
+        .await;
+        let (header, ack): (_, LeaseBreakAcknowledgment) = receive_request(&mut server_side).await;
+        assert_eq!(header.command, Command::OplockBreak);
+        assert_eq!(ack.lease_key, lease_key);
+        assert_eq!(ack.lease_state, LeaseState::READ_CACHING);
+        assert_eq!(file.caching_state(), LeaseState::READ_CACHING);
+        send_response(&mut server_side, &header, NtStatus::Success, None, ack).await;
+
+        drop(file);
+        let (_, close): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert_eq!(close.file_id, file_id);
+    }
+}
+
+#[tokio::test]
+async fn malformed_leases_fail_the_open() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session(authenticator, SessionFlags::empty()).await;
+    let client = client_on_tree(auth_client);
+    let file_id = FileId {
+        persistent: 5,
+        volatile: 6,
+    };
+
+    let server = async {
+        let (header, _): (_, CreateRequest) = receive_request(&mut server_side).await;
+        let mut response = create_response(file_id);
+        response.oplock_level = OplockLevel::Lease;
+        response.create_contexts = response_contexts(&[(b"RqLs", vec![0; 20])]);
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        // The handle is no use without knowing what it may cache
+        let (header, close): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert_eq!(close.file_id, file_id);
+        let response = CloseResponse {
+            flags: CloseFlags::empty(),
+            creation_time: Time { intervals: 0 },
+            last_access_time: Time { intervals: 0 },
+            last_write_time: Time { intervals: 0 },
+            change_time: Time { intervals: 0 },
+            allocation_time: 0,
+            end_of_file: 0,
+            file_attributes: FileAttributes::empty(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+    };
+    let (file, ()) = tokio::join!(
+        client.open_with_caching("file", CachingRequest::Lease(LeaseState::READ_CACHING)),
+        server
+    );
+    assert!(matches!(file, Err(Error::Seralization(_))));
+}
+
+#[tokio::test]
+async fn oplock_breaks_are_acknowledged() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session(authenticator, SessionFlags::empty()).await;
+    let client = client_on_tree(auth_client);
+    let file_id = FileId {
+        persistent: 7,
+        volatile: 8,
+    };
+
+    // The break comes right behind the create response
+    let server = async {
+        let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+        assert_eq!(request.requested_oplock_level, OplockLevel::Batch);
+        let mut response = create_response(file_id);
+        response.oplock_level = OplockLevel::Batch;
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        send_break(
+            &mut server_side,
+            OplockBreak {
+                oplock_level: OplockLevel::II,
+                file_id,
+            },
+        )
+        .await;
+    };
+    let (file, ()) = tokio::join!(
+        client.open_with_caching("file", CachingRequest::Oplock(OplockLevel::Batch)),
+        server
+    );
+    let file = file.unwrap();
+    assert_eq!(file.lease_key(), None);
+
+    let (header, ack): (_, OplockBreak) = receive_request(&mut server_side).await;
+    assert_eq!(ack.oplock_level, OplockLevel::II);
+    assert_eq!(ack.file_id, file_id);
+    assert_eq!(file.caching_state(), LeaseState::READ_CACHING);
+    send_response(&mut server_side, &header, NtStatus::Success, None, ack).await;
+
+    // Level II oplocks are broken without an acknowledgment
+    let mut changes = file.caching_changes();
+    send_break(
+        &mut server_side,
+        OplockBreak {
+            oplock_level: OplockLevel::None,
+            file_id,
+        },
+    )
+    .await;
+    changes
+        .wait_for(|&state| state == LeaseState::LEASE_NONE)
+        .await
+        .unwrap();
+
+    let server = async {
+        let (header, close): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert_eq!(close.file_id, file_id);
+        let response = CloseResponse {
+            flags: CloseFlags::empty(),
+            creation_time: Time { intervals: 0 },
+            last_access_time: Time { intervals: 0 },
+            last_write_time: Time { intervals: 0 },
+            change_time: Time { intervals: 0 },
+            allocation_time: 0,
+            end_of_file: 0,
+            file_attributes: FileAttributes::empty(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+    };
+    let (result, ()) = tokio::join!(file.close(), server);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn cached_files_are_written_back_when_the_lease_breaks() {
+    let authenticator = ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session_with_dialect(authenticator, SessionFlags::empty(), Dialect::Smb3_0).await;
+    let client = client_on_tree(auth_client);
+    let file_id = FileId {
+        persistent: 9,
+        volatile: 10,
+    };
+    let contents: Vec<u8> = (0..100).collect();
+    let read_write_handle =
+        LeaseState::READ_CACHING | LeaseState::WRITE_CACHING | LeaseState::HANDLE_CACHING;
+    let (break_now, wait_for_break) = oneshot::channel();
+    let (acknowledged, wait_for_acknowledgment) = oneshot::channel();
+
+    let server = async {
+        let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+        let CreateContext::RequestLease(requested) = &request.create_contexts[0].body else {
+            panic!("no lease requested: {:?}", request.create_contexts);
+        };
+        let lease_key = requested.lease_key;
+        let mut response = create_response(file_id);
+        response.oplock_level = OplockLevel::Lease;
+        response.create_contexts = lease_context(&ResponseLease {
+            lease_key,
+            lease_state: read_write_handle,
+            flags: LeaseFlags::empty(),
+            parent_lease_key: LeaseKey::default(),
+            epoch: 1,
+        });
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        // The file is read once, however often the client reads it
+        let (header, request): (_, ReadRequest) = receive_request(&mut server_side).await;
+        assert_eq!(request.offset, 0);
+        let response = ReadResponse {
+            data_remaining: 0,
+            flags: ReadResponseFlags::None,
+            data: contents.clone(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        // Someone else opens the file, so the writes held back have to be sent before the break
+        // is acknowledged
+        wait_for_break.await.unwrap();
+        send_break(
+            &mut server_side,
+            LeaseBreakNotification {
+                new_epoch: 2,
+                flags: LeaseBreakFlags::ACK_REQUIRED,
+                lease_key,
+                current_lease_state: read_write_handle,
+                new_lease_state: LeaseState::READ_CACHING | LeaseState::HANDLE_CACHING,
+            },
+        )
+        .await;
+        for (offset, data) in [(5, &b"abcde"[..]), (99, b"yz")] {
+            let (header, request): (_, WriteRequest) = receive_request(&mut server_side).await;
+            assert_eq!((request.offset, &request.data[..]), (offset, data));
+            let response = WriteResponse {
+                count: data.len() as u32,
+            };
+            send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        }
+        let (header, ack): (_, LeaseBreakAcknowledgment) = receive_request(&mut server_side).await;
+        assert_eq!(
+            ack.lease_state,
+            LeaseState::READ_CACHING | LeaseState::HANDLE_CACHING
+        );
+        send_response(&mut server_side, &header, NtStatus::Success, None, ack).await;
+        acknowledged.send(()).unwrap();
+
+        // The page that was written to is read again, and writes go straight through
+        let (header, _): (_, ReadRequest) = receive_request(&mut server_side).await;
+        let response = ReadResponse {
+            data_remaining: 0,
+            flags: ReadResponseFlags::None,
+            data: b"written".to_vec(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        let (header, request): (_, WriteRequest) = receive_request(&mut server_side).await;
+        assert_eq!((request.offset, &request.data[..]), (0, &b"x"[..]));
+        let response = WriteResponse { count: 1 };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        let (_, close): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert_eq!(close.file_id, file_id);
+    };
+    let cached = async {
+        let file = client.open_cached("header.h").await.unwrap();
+        assert_eq!(file.read(0, 10).await.unwrap(), contents[..10]);
+        assert_eq!(file.read(90, 20).await.unwrap(), contents[90..]);
+
+        file.write(5, b"abc".to_vec()).await.unwrap();
+        file.write(8, b"de".to_vec()).await.unwrap();
+        file.write(99, b"yz".to_vec()).await.unwrap();
+        let mut expected = contents.clone();
+        expected[5..10].copy_from_slice(b"abcde");
+        expected[99] = b'y';
+        expected.push(b'z');
+        assert_eq!(file.read(0, 200).await.unwrap(), expected);
+
+        break_now.send(()).unwrap();
+        wait_for_acknowledgment.await.unwrap();
+        assert_eq!(
+            file.caching_state(),
+            LeaseState::READ_CACHING | LeaseState::HANDLE_CACHING
+        );
+        assert_eq!(file.read(0, 200).await.unwrap(), b"written");
+        file.write(0, b"x".to_vec()).await.unwrap();
+        drop(file);
+    };
+    tokio::join!(server, cached);
+}
+
+#[tokio::test]
+async fn durable_handles_are_reopened_after_reconnecting() {
+    let authenticator = || ScriptedAuthenticator {
+        received: Arc::default(),
+        session_key: None,
+    };
+    let (auth_client, mut server_side, _) =
+        set_up_session_with_dialect(authenticator(), SessionFlags::empty(), Dialect::Smb3_0).await;
+    let session = Session::from_auth_client(auth_client, &ClientOptions::default());
+    let file_id = FileId {
+        persistent: 3,
+        volatile: 4,
+    };
+    let read_handle = LeaseState::READ_CACHING | LeaseState::HANDLE_CACHING;
+
+    let server = async {
+        let (mut header, _): (_, TreeConnectRequest) = receive_request(&mut server_side).await;
+        header.tree_id = TreeId(1);
+        let response = TreeConnectResponse {
+            share_type: ShareType::Disk,
+            share_flags: ShareFlags::empty(),
+            share_capabilities: ShareCapabilities::CONTINUOUS_AVAILABILITY,
+            access_mask: AccessMask::GENERIC_ALL,
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+        let [durable, lease] = &request.create_contexts[..] else {
+            panic!("unexpected contexts: {:?}", request.create_contexts);
+        };
+        let CreateContext::DurableHandleRequestV2(durable) = &durable.body else {
+            panic!("no durable handle requested: {durable:?}");
+        };
+        assert_eq!(durable.flags, DurableHandleFlags::PERSISTENT);
+        let CreateContext::RequestLease(lease) = &lease.body else {
+            panic!("no lease requested: {lease:?}");
+        };
+        let mut response = create_response(file_id);
+        response.oplock_level = OplockLevel::Lease;
+        response.create_contexts = response_contexts(&[
+            (
+                b"DH2Q",
+                serde_smb::to_vec(&DurableHandleResponseV2 {
+                    timeout: 60000,
+                    flags: DurableHandleFlags::PERSISTENT,
+                })
+                .unwrap(),
+            ),
+            (
+                b"RqLs",
+                serde_smb::to_vec(&ResponseLease {
+                    lease_key: lease.lease_key,
+                    lease_state: lease.lease_state,
+                    flags: LeaseFlags::empty(),
+                    parent_lease_key: LeaseKey::default(),
+                    epoch: 1,
+                })
+                .unwrap(),
+            ),
+        ]);
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+        (durable.create_guid.clone(), lease.lease_key)
+    };
+    let open = async {
+        let client = session.tree_connect("share").await?;
+        let file = client
+            .open_durable("file", CachingRequest::Lease(read_handle))
+            .await?;
+        Ok::<_, Error>((client, file))
+    };
+    let (opened, (create_guid, lease_key)) = tokio::join!(open, server);
+    let (client, file) = opened.unwrap();
+    assert!(file.is_persistent());
+    assert_eq!(file.caching_state(), read_handle);
+
+    // The connection drops, and on the new one the share gets another tree id
+    drop(server_side);
+    let (client_side, mut server_side) = io::duplex(4096);
+    let server = async {
+        serve_session_setup(&mut server_side, SessionFlags::empty(), Dialect::Smb3_0).await;
+
+        let (mut header, request): (_, TreeConnectRequest) =
+            receive_request(&mut server_side).await;
+        assert_eq!(request.path, "share");
+        header.tree_id = TreeId(9);
+        let response = TreeConnectResponse {
+            share_type: ShareType::Disk,
+            share_flags: ShareFlags::empty(),
+            share_capabilities: ShareCapabilities::CONTINUOUS_AVAILABILITY,
+            access_mask: AccessMask::GENERIC_ALL,
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+
+        let (header, request): (_, CreateRequest) = receive_request(&mut server_side).await;
+        assert!(header.flags.replay());
+        assert_eq!(header.tree_id, TreeId(9));
+        let reconnect = request
+            .create_contexts
+            .iter()
+            .find_map(|context| match &context.body {
+                CreateContext::DurableHandleReconnectV2(reconnect) => Some(reconnect),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(reconnect.file_id, file_id);
+        assert_eq!(reconnect.create_guid, create_guid);
+        // The lease was broken while we were gone
+        let mut response = create_response(file_id);
+        response.oplock_level = OplockLevel::Lease;
+        response.create_contexts = lease_context(&ResponseLease {
+            lease_key,
+            lease_state: LeaseState::READ_CACHING,
+            flags: LeaseFlags::empty(),
+            parent_lease_key: LeaseKey::default(),
+            epoch: 2,
+        });
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+    };
+    let (result, ()) = tokio::join!(session.reconnect(client_side, authenticator()), server);
+    result.unwrap();
+    file.caching_changes()
+        .wait_for(|&state| state == LeaseState::READ_CACHING)
+        .await
+        .unwrap();
+
+    // The client goes on using the handle, on the new tree id
+    let server = async {
+        let (header, request): (_, FlushRequest) = receive_request(&mut server_side).await;
+        assert_eq!(header.tree_id, TreeId(9));
+        assert_eq!(request.file_id, file_id);
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(client.flush(file.file_id()), server);
+    result.unwrap();
+}
+
+/// Hands out the client's ends of connections to a test server, in order.
+struct TestConnector {
+    transports: Mutex<VecDeque<io::DuplexStream>>,
+}
+
+impl Connector for TestConnector {
+    type Transport = io::DuplexStream;
+
+    fn connect(&self) -> BoxFuture<'_, Result<io::DuplexStream>> {
+        let transport = self.transports.lock().unwrap().pop_front();
+        async move { transport.ok_or_else(Error::disconnected) }.boxed()
+    }
+
+    fn authenticator(&self) -> BoxFuture<'_, Result<Box<dyn Authenticator>>> {
+        let authenticator = ScriptedAuthenticator {
+            received: Arc::default(),
+            session_key: None,
+        };
+        async move { Ok(Box::new(authenticator) as Box<dyn Authenticator>) }.boxed()
+    }
+}
+
+/// The server's side of connecting to a share with the given tree id.
+async fn serve_tree_connect(server_side: &mut io::DuplexStream, tree_id: TreeId) {
+    let (mut header, request): (_, TreeConnectRequest) = receive_request(server_side).await;
+    assert_eq!(request.path, "share");
+    header.tree_id = tree_id;
+    let response = TreeConnectResponse {
+        share_type: ShareType::Disk,
+        share_flags: ShareFlags::empty(),
+        share_capabilities: ShareCapabilities::empty(),
+        access_mask: AccessMask::GENERIC_ALL,
+    };
+    send_response(server_side, &header, NtStatus::Success, None, response).await;
+}
+
+#[tokio::test]
+async fn operations_are_retried_after_reconnecting() {
+    let (first_client_side, mut first_server_side) = io::duplex(4096);
+    let (second_client_side, mut second_server_side) = io::duplex(4096);
+    let connector = TestConnector {
+        transports: Mutex::new(VecDeque::from([first_client_side, second_client_side])),
+    };
+    let retry_options = RetryOptions {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(10),
+    };
+    let file_id = FileId {
+        persistent: 1,
+        volatile: 2,
+    };
+
+    let server = async {
+        serve_session_setup(
+            &mut first_server_side,
+            SessionFlags::empty(),
+            Dialect::Smb2_1,
+        )
+        .await;
+        serve_tree_connect(&mut first_server_side, TreeId(1)).await;
+    };
+    let (client, ()) = tokio::join!(
+        ReconnectingClient::connect(connector, "share", ClientOptions::default(), retry_options),
+        server
+    );
+    let client = client.unwrap();
+
+    // The connection drops while the flush is in flight
+    let server = async {
+        let (_, _): (_, FlushRequest) = receive_request(&mut first_server_side).await;
+        drop(first_server_side);
+
+        serve_session_setup(
+            &mut second_server_side,
+            SessionFlags::empty(),
+            Dialect::Smb2_1,
+        )
+        .await;
+        serve_tree_connect(&mut second_server_side, TreeId(2)).await;
+        let (header, request): (_, FlushRequest) = receive_request(&mut second_server_side).await;
+        assert_eq!(header.tree_id, TreeId(2));
+        assert_eq!(request.file_id, file_id);
+        // Only requests on durable handles are replays
+        assert!(!header.flags.replay());
+        send_response(
+            &mut second_server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(client.flush(file_id), server);
+    result.unwrap();
+    assert!(client.client().session().is_connected());
+
+    // Errors from the server aren't retried
+    let server = async {
+        let (header, _): (_, FlushRequest) = receive_request(&mut second_server_side).await;
+        send_response(
+            &mut second_server_side,
+            &header,
+            NtStatus::AccessDenied,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(client.flush(file_id), server);
+    assert!(matches!(
+        result,
+        Err(Error::NtStatus(NtStatus::AccessDenied))
+    ));
+}
+
+#[tokio::test]
+async fn retried_requests_on_durable_handles_are_replays() {
+    let (client_sides, mut server_sides): (VecDeque<_>, VecDeque<_>) =
+        (0..4).map(|_| io::duplex(4096)).unzip();
+    let connector = TestConnector {
+        transports: Mutex::new(client_sides),
+    };
+    let retry_options = RetryOptions {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(10),
+    };
+    let file_id = FileId {
+        persistent: 3,
+        volatile: 4,
+    };
+
+    let mut server_side = server_sides.pop_front().unwrap();
+    let server = async {
+        serve_session_setup(&mut server_side, SessionFlags::empty(), Dialect::Smb3_0).await;
+        serve_tree_connect(&mut server_side, TreeId(1)).await;
+    };
+    let (client, ()) = tokio::join!(
+        ReconnectingClient::connect(connector, "share", ClientOptions::default(), retry_options),
+        server
+    );
+    let client = client.unwrap();
+    let mut request = open_request("file");
+    request.create_contexts.push(
+        CreateContext::DurableHandleRequestV2(DurableHandleRequestV2 {
+            timeout: 0,
+            flags: DurableHandleFlags::PERSISTENT,
+            create_guid: Uuid::new(&mut OsRng),
+        })
+        .into(),
+    );
+    client
+        .client()
+        .auth_client
+        .track_durable_open(TreeId(1), file_id, request);
+
+    // Each operation is cut off by the connection dropping, after the server may have done it,
+    // and repeated as a replay on the next one
+    let server = async {
+        let (header, _): (_, WriteRequest) = receive_request(&mut server_side).await;
+        assert!(!header.flags.replay());
+        let next = server_sides.pop_front().unwrap();
+        serve_durable_reconnect(&mut server_side, next, file_id).await;
+        let (header, request): (_, WriteRequest) = receive_request(&mut server_side).await;
+        assert!(header.flags.replay());
+        assert_eq!(request.file_id, file_id);
+        let response = WriteResponse { count: 5 };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+    };
+    let (result, ()) = tokio::join!(client.write(file_id, 0, b"hello".to_vec()), server);
+    assert_eq!(result.unwrap(), 5);
+
+    let server = async {
+        let (header, _): (_, SetInfoRequest<FileEndOfFileInformation>) =
+            receive_request(&mut server_side).await;
+        assert!(!header.flags.replay());
+        let next = server_sides.pop_front().unwrap();
+        serve_durable_reconnect(&mut server_side, next, file_id).await;
+        let (header, request): (_, SetInfoRequest<FileEndOfFileInformation>) =
+            receive_request(&mut server_side).await;
+        assert!(header.flags.replay());
+        assert_eq!(request.info.end_of_file, 5);
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            SetInfoResponse,
+        )
+        .await;
+    };
+    let resize = client.retry(|client| async move { client.resize(file_id, 5).await });
+    let (result, ()) = tokio::join!(resize, server);
+    result.unwrap();
+
+    // Requests that aren't repeats aren't replays
+    let server = async {
+        let (header, _): (_, FlushRequest) = receive_request(&mut server_side).await;
+        assert!(!header.flags.replay());
+        send_response(
+            &mut server_side,
+            &header,
+            NtStatus::Success,
+            None,
+            FlushResponse,
+        )
+        .await;
+    };
+    let (result, ()) = tokio::join!(client.flush(file_id), server);
+    result.unwrap();
+
+    let server = async {
+        let (header, _): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert!(!header.flags.replay());
+        let next = server_sides.pop_front().unwrap();
+        serve_durable_reconnect(&mut server_side, next, file_id).await;
+        let (header, request): (_, CloseRequest) = receive_request(&mut server_side).await;
+        assert!(header.flags.replay());
+        assert_eq!(request.file_id, file_id);
+        let response = CloseResponse {
+            flags: CloseFlags::empty(),
+            creation_time: Time { intervals: 0 },
+            last_access_time: Time { intervals: 0 },
+            last_write_time: Time { intervals: 0 },
+            change_time: Time { intervals: 0 },
+            allocation_time: 0,
+            end_of_file: 0,
+            file_attributes: FileAttributes::empty(),
+        };
+        send_response(&mut server_side, &header, NtStatus::Success, None, response).await;
+    };
+    let close = client.retry(|client| async move { client.close(file_id).await });
+    let (result, ()) = tokio::join!(close, server);
+    result.unwrap();
+    assert!(client
+        .client()
+        .auth_client
+        .open_files
+        .lock()
+        .unwrap()
+        .is_empty());
+}
+
+/// Drops the connection, and serves setting the session up again on the next one and reopening
+/// a durable handle.
+async fn serve_durable_reconnect(
+    server_side: &mut io::DuplexStream,
+    next: io::DuplexStream,
+    file_id: FileId,
+) {
+    drop(std::mem::replace(server_side, next));
+    serve_session_setup(server_side, SessionFlags::empty(), Dialect::Smb3_0).await;
+    serve_tree_connect(server_side, TreeId(2)).await;
+    let (header, _): (_, CreateRequest) = receive_request(server_side).await;
+    assert!(header.flags.replay());
+    let response = create_response(file_id);
+    send_response(server_side, &header, NtStatus::Success, None, response).await;
+}
@@ -0,0 +1,115 @@
+//! Watching directories for changes, which the server tells us about as they happen.
+
+use crate::{path_str, spawn_cleanup, Client, Error, Result, Transport, CREDIT_PAYLOAD_SIZE};
+use futures::stream::{self, BoxStream, StreamExt as _};
+use smb3::{
+    AccessMask, ChangeNotifyFlags, ChangeNotifyRequest, ChangeNotifyResponse, CompletionFilter,
+    CreateRequest, CreateResponse, Credits, FileAttributes, FileCreateDisposition,
+    FileCreateOptions, FileId, FileNotifyInformation, FileShareAccess, ImpersonationLevel,
+    NtStatus, OplockLevel,
+};
+use std::collections::VecDeque;
+use std::path::Path;
+
+/// The directory being watched, which is closed once nobody is watching it any more.
+struct WatchedDirectory<TransportT: Transport> {
+    client: Client<TransportT>,
+    file_id: FileId,
+    request: ChangeNotifyRequest,
+}
+
+impl<TransportT: Transport> WatchedDirectory<TransportT> {
+    /// Waits for the server to report the next batch of changes.
+    async fn changes(&self) -> Result<Vec<FileNotifyInformation>> {
+        let (_, response): (_, ChangeNotifyResponse) = self
+            .client
+            .file_request(self.file_id, self.request.clone())
+            .await?;
+        Ok(response.entries)
+    }
+}
+
+impl<TransportT: Transport> Drop for WatchedDirectory<TransportT> {
+    fn drop(&mut self) {
+        let client = self.client.clone();
+        let file_id = self.file_id;
+        spawn_cleanup(async move { client.close(file_id).await.map(drop) });
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Watches a directory for the changes in the filter, and with `recursive` everything under
+    /// it too. The server reports changes until the stream is dropped, which closes the directory.
+    ///
+    /// If more changes happen than the server can keep track of, the stream yields
+    /// `NtStatus::NotifyEnumDir` and carries on, and the directory needs listing again to catch
+    /// up. Any other error ends it.
+    pub async fn watch(
+        &self,
+        path: impl AsRef<Path>,
+        filter: CompletionFilter,
+        recursive: bool,
+    ) -> Result<BoxStream<'static, Result<FileNotifyInformation>>> {
+        let (_, response): (_, CreateResponse) = self
+            .auth_client
+            .request(
+                Some(self.tree.id),
+                Credits(1),
+                CreateRequest {
+                    requested_oplock_level: OplockLevel::None,
+                    impersonation_level: ImpersonationLevel::Impersonation,
+                    desired_access: AccessMask::FILE_LIST_DIRECTORY
+                        | AccessMask::FILE_READ_ATTRIBUTES,
+                    file_attributes: FileAttributes::empty(),
+                    share_access: FileShareAccess::READ
+                        | FileShareAccess::WRITE
+                        | FileShareAccess::DELETE,
+                    create_disposition: FileCreateDisposition::Open,
+                    create_options: FileCreateOptions::DIRECTORY_FILE,
+                    name: path_str(path),
+                    create_contexts: vec![],
+                },
+            )
+            .await?;
+        self.auth_client.track_open(self.tree.id, response.file_id);
+
+        let flags = if recursive {
+            ChangeNotifyFlags::WATCH_TREE
+        } else {
+            ChangeNotifyFlags::empty()
+        };
+        let directory = WatchedDirectory {
+            client: self.clone(),
+            file_id: response.file_id,
+            request: ChangeNotifyRequest {
+                flags,
+                output_buffer_length: CREDIT_PAYLOAD_SIZE,
+                file_id: response.file_id,
+                completion_filter: filter,
+            },
+        };
+
+        // Each response covers the changes since the last, so asking again right away doesn't
+        // miss any
+        let changes = stream::unfold(
+            (Some(directory), VecDeque::new()),
+            |(directory, mut changes)| async move {
+                loop {
+                    if let Some(change) = changes.pop_front() {
+                        return Some((Ok(change), (directory, changes)));
+                    }
+                    match directory.as_ref()?.changes().await {
+                        Ok(more) => changes.extend(more),
+                        // The directory was closed, so there won't be any more
+                        Err(Error::NtStatus(NtStatus::NotifyCleanup)) => return None,
+                        Err(error @ Error::NtStatus(NtStatus::NotifyEnumDir)) => {
+                            return Some((Err(error), (directory, changes)));
+                        }
+                        Err(error) => return Some((Err(error), (None, changes))),
+                    }
+                }
+            },
+        );
+        Ok(changes.boxed())
+    }
+}
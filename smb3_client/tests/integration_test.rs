@@ -9,7 +9,7 @@ use smb3::{
     FileNameInformation, FilePositionInformation, FileStandardInformation, HasFileInformationClass,
     NtStatus, Time,
 };
-use smb3_client::{Client, Error, PORT};
+use smb3_client::{Client, Error, NtlmAuthenticator, PORT};
 use std::collections::BTreeSet;
 use tokio::net::TcpStream;
 
@@ -38,7 +38,10 @@ impl<'machine> Fixture<'machine> {
             .find(|p| p.guest == PORT)
             .unwrap();
         let transport = TcpStream::connect(("127.0.0.1", port.host)).await.unwrap();
-        let client = Client::new(transport, "root", "a", "files").await.unwrap();
+        let authenticator = NtlmAuthenticator::new("root", "a").unwrap();
+        let client = Client::new(transport, authenticator, "files")
+            .await
+            .unwrap();
 
         Self { machine, client }
     }
@@ -271,6 +274,7 @@ impl<'machine> Fixture<'machine> {
 async fn run_fixture(m: &mut vm_runner::Machine) {
     let mut fix = Fixture::new(m).await;
     fix.run().await;
+    fix.client.shutdown().await.unwrap();
 }
 
 #[test]